@@ -34,6 +34,9 @@ fn rust_begin_panic(pi: &PanicInfo) -> ! {
 
     printk!("\n===========================\n");
 
+    // Whatever led up to the panic is what we want to replay.
+    crate::sched::replay::dump();
+
     loop {
         hlt(); // Don't just spin... wait a bit
     }
@@ -18,51 +18,236 @@
 //! However, passing around a lot of capabilities still means passing around a lot of 128-bit
 //! handles. To mitigate this, handles can be grouped into a `CapabilityGroup`, which is a capability
 //! that contains other capabilities and gives access to all of them. To keep things simple,
-//! capability groups may _not_ have other groups in them.
+//! capability groups may _not_ have other groups in them: `CapabilityGroup::create` refuses to make
+//! one. Whoever holds a group's handle can use the handles of its members (see
+//! `ResourceHandle::members` and `ResourceHandle::member`), so a single handle can be passed to a
+//! continuation or user task to give it a whole bundle of resources. A member used through a group
+//! only grants the rights that both its own handle and the group's handle grant.
+//!
+//! # Continuations
+//!
+//! Each continuation holds a `CapabilityGroup`, and it can only access resources in that group.
+//! While a continuation runs, its group is the "current" group, and `ResourceHandle::with` checks
+//! against it. Any capability registered by a continuation is added to its group. When a
+//! continuation produces children, each child inherits the parent's group or the subset of it
+//! chosen with `Continuation::with_caps`.
+//!
+//! Kernel code that acts on its own behalf or on behalf of other continuations (e.g. the
+//! scheduler) has access to everything, but only inside of `as_kernel`, which takes a
+//! `KernelAuthority` token. Outside of both a continuation and `as_kernel`, nothing is accessible.
 //!
 //! # User space
 //!
 //! Capabilities _must never_ leave kernel mode because they are not fully thread-safe, and we
-//! cannot control what users do with them. Instead, we only ever return `ResourceHandle`s and
-//! resource metadata to user space.
+//! cannot control what users do with them. Instead, we only ever return resource metadata and
+//! indices into a task's `HandleTable` to user space. Tasks can list the handles they hold, get
+//! their metadata, derive handles with fewer rights, and give handles up with the `SYS_CAP_*`
+//! system calls.
 //!
-//! A `ResourceHandle` is guaranteed to be valid until it is destroy by the user.
+//! Each task has its own `HandleTable`, which is filled in explicitly by whoever starts the task,
+//! and user space names a handle by its index in the table, like a file descriptor. A task can't
+//! name a handle that is not in its own table, so guessing gets it nothing. Each handle is resolved
+//! to its capability when it is added to the table, so a system call doesn't need to look it up in
+//! the registry: using a handle is just indexing the table.
+//!
+//! A handle in the table is guaranteed to be valid until it is destroy by the user or revoked.
 //!
 //! On the other hand, the metadata may become out of date with the actual kernel resource, so the
 //! user should be prepared that. Each resource may also make its own guarantees about its
 //! metadata, too, in addition to what is guaranteed for all resources.
+//!
+//! # Revocation
+//!
+//! The registry keeps a derivation tree of handles. `derive` creates a new handle on the same
+//! resource as a child of the given one, and a handle received in a message is likewise derived
+//! from the handle that was sent (see `receive`). `revoke` invalidates a handle along with
+//! everything derived from it, so a holder can share a resource and take it back later by deriving
+//! a handle to share. After that, using any of the revoked handles fails with `MissingCapability`.
+//!
+//! A received handle is removed from the tree once the last copy of it is dropped, so receiving
+//! handles over and over doesn't fill up the registry.
+//!
+//! `destroy` gets rid of a single handle, e.g. one that its holder no longer needs. When the last
+//! handle on a resource is destroyed or revoked, the resource is freed.
+//!
+//! # Rights
+//!
+//! Each handle carries a set of `Rights`, which limit what its holder can do with the resource. A
+//! registered capability starts out with all rights, and `derive` can drop some of them, e.g. to
+//! hand out a read-only handle on a memory region. A derived handle never has more rights than the
+//! handle it was derived from.
+
+use alloc::{
+    boxed::Box,
+    collections::{BTreeMap, BTreeSet},
+    sync::{Arc, Weak},
+    vec,
+    vec::Vec,
+};
 
-use alloc::{boxed::Box, collections::BTreeMap, vec::Vec};
+use core::{
+    cmp, mem,
+    ops::{BitAnd, BitOr},
+    slice,
+    sync::atomic::{AtomicBool, Ordering},
+};
 
 use rand::{rngs::StdRng, Rng, SeedableRng};
 
 use spin::Mutex;
 
-use crate::memory::VirtualMemoryRegion;
+use crate::{
+    entropy,
+    error::KernelError,
+    interrupts::irq::Irq,
+    io::ports::IoPortRange,
+    ipc::Channel,
+    memory::{PhysicalFrames, VirtualMemoryRegion},
+    smp::PerCpu,
+};
 
-/// A registry of cabilities.
-static CAPABILITY_REGISTRY: Mutex<Option<BTreeMap<u128, Box<Capability>>>> = Mutex::new(None);
+/// A registry of cabilities, by the key of their `ResourceHandle`.
+static CAPABILITY_REGISTRY: Mutex<Option<BTreeMap<u128, Node>>> = Mutex::new(None);
 
 /// RNG for capability numbers.
 static CAPABILITY_RNG: Mutex<Option<Box<StdRng>>> = Mutex::new(None);
 
+/// The authority of the code running on each core: the capabilities of the continuation that is
+/// running, or the kernel's inside of `as_kernel`. `None` means neither, which can access nothing.
+static CURRENT: PerCpu<Option<Authority>> = PerCpu::new();
+
 /// Init the capability system.
 pub fn init() {
     *CAPABILITY_REGISTRY.lock() = Some(BTreeMap::new());
-    *CAPABILITY_RNG.lock() = Some(box StdRng::seed_from_u64(0));
+
+    let mut seed = [0; 32];
+    entropy::fill(&mut seed);
+    *CAPABILITY_RNG.lock() = Some(box StdRng::from_seed(seed));
+}
+
+/// An entry in the capability registry. Every handle has its own node in the derivation tree, and
+/// all of the nodes derived from the same registered capability share it, so the capability is
+/// dropped along with the last of them.
+struct Node {
+    cap: Arc<Capability>,
+
+    /// What the holder of this handle may do with the capability.
+    rights: Rights,
+
+    /// The key of the handle this one was derived from, if any.
+    parent: Option<u128>,
+
+    /// The keys of the handles derived from this one.
+    children: BTreeSet<u128>,
+
+    /// Shared with the `TableEntry`s of this handle, and cleared when it is destroyed or revoked.
+    live: Arc<AtomicBool>,
 }
 
-/// A capability on a single resource. Having this capability gives access to the resource.
+/// A capability on a single resource. Having this capability gives access to the resource. The
+/// resource is freed when the capability is dropped, i.e. when the last handle on it is destroyed
+/// or revoked.
 /// Capabilities should be registered in the `CAPABILITY_REGISTRY` before use so that the kernel
 /// can check them when needed.
 #[derive(Debug)]
 pub enum Capability {
     /// A group of capabilities that are given together.
-    #[allow(dead_code)]
     CapabilityGroup(CapabilityGroup),
 
     /// A capability on a region of the virtual address space.
     VirtualMemoryRegion(VirtualMemoryRegion),
+
+    /// A capability on a message channel.
+    Channel(Channel),
+
+    /// A capability on a range of I/O ports.
+    IoPortRange(IoPortRange),
+
+    /// A capability on an IRQ line.
+    Irq(Irq),
+
+    /// A capability on physical memory, either RAM (e.g. for DMA) or device memory.
+    PhysicalFrames(PhysicalFrames),
+}
+
+impl Capability {
+    /// What type of capability this is.
+    pub fn ty(&self) -> CapabilityType {
+        match self {
+            Capability::CapabilityGroup(_) => CapabilityType::CapabilityGroup,
+            Capability::VirtualMemoryRegion(_) => CapabilityType::VirtualMemoryRegion,
+            Capability::Channel(_) => CapabilityType::Channel,
+            Capability::IoPortRange(_) => CapabilityType::IoPortRange,
+            Capability::Irq(_) => CapabilityType::Irq,
+            Capability::PhysicalFrames(_) => CapabilityType::PhysicalFrames,
+        }
+    }
+}
+
+/// The types of capabilities, i.e. the variants of `Capability` without their contents.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum CapabilityType {
+    CapabilityGroup,
+    VirtualMemoryRegion,
+    Channel,
+    IoPortRange,
+    Irq,
+    PhysicalFrames,
+}
+
+/// The operations a handle allows on its resource.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct Rights(u8);
+
+impl Rights {
+    /// Read from the resource (e.g. map a memory region, or receive on a channel).
+    pub const READ: Rights = Rights(1 << 0);
+
+    /// Write to the resource (e.g. map a memory region writable, or send on a channel).
+    pub const WRITE: Rights = Rights(1 << 1);
+
+    /// Execute the resource (e.g. map a memory region executable).
+    pub const EXECUTE: Rights = Rights(1 << 2);
+
+    /// Pass the handle on to someone else (e.g. in a message).
+    pub const GRANT: Rights = Rights(1 << 3);
+
+    /// Map the resource into the address space.
+    pub const MAP: Rights = Rights(1 << 4);
+
+    /// All of the above.
+    pub const ALL: Rights = Rights(0b1_1111);
+
+    /// The rights whose bits are set in `bits`. Unknown bits are ignored.
+    pub fn from_bits(bits: u8) -> Rights {
+        Rights(bits & Rights::ALL.0)
+    }
+
+    /// The bits of these rights, e.g. for passing them to user space.
+    pub fn bits(self) -> u8 {
+        self.0
+    }
+
+    /// Does `self` include all of the rights in `other`?
+    pub fn contains(self, other: Rights) -> bool {
+        self.0 & other.0 == other.0
+    }
+}
+
+impl BitOr for Rights {
+    type Output = Rights;
+
+    fn bitor(self, other: Rights) -> Rights {
+        Rights(self.0 | other.0)
+    }
+}
+
+impl BitAnd for Rights {
+    type Output = Rights;
+
+    fn bitand(self, other: Rights) -> Rights {
+        Rights(self.0 & other.0)
+    }
 }
 
 /// Used to unwrap a capability when you know statically what type it is.
@@ -77,11 +262,36 @@ macro_rules! cap_unwrap {
     };
 }
 
-/// A handle to a resource in the capability registry.
-#[derive(Debug)]
+/// A handle to a resource in the capability registry. Clones of a handle are the same handle: they
+/// are revoked together. Use `derive` to get a handle that can be revoked separately.
+#[derive(Clone, Debug)]
 pub struct ResourceHandle {
     /// An index into the capability registry.
     key: u128,
+
+    /// For a handle that was received in a message, keeps its node in the registry while any copy
+    /// of the handle is left (see `receive`).
+    received: Option<Arc<Received>>,
+}
+
+impl PartialEq for ResourceHandle {
+    fn eq(&self, other: &Self) -> bool {
+        self.key == other.key
+    }
+}
+
+impl Eq for ResourceHandle {}
+
+impl PartialOrd for ResourceHandle {
+    fn partial_cmp(&self, other: &Self) -> Option<cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for ResourceHandle {
+    fn cmp(&self, other: &Self) -> cmp::Ordering {
+        self.key.cmp(&other.key)
+    }
 }
 
 impl ResourceHandle {
@@ -89,28 +299,96 @@ impl ResourceHandle {
     /// returns to the caller.
     ///
     /// NOTE: This method holds the registry lock, so nothing expensive should be done in `f`.
-    pub fn with<F, R>(&self, f: F) -> R
+    ///
+    /// Returns `MissingCapability` if the current continuation does not hold this capability or it
+    /// has been revoked.
+    pub fn with<F, R>(&self, f: F) -> Result<R, KernelError>
     where
         F: FnOnce(&Capability) -> R,
     {
+        if !is_held(self) {
+            return Err(KernelError::MissingCapability);
+        }
+
         let reg = CAPABILITY_REGISTRY.lock();
-        let cap = reg.as_ref().unwrap().get(&self.key).unwrap();
+        let node = reg
+            .as_ref()
+            .unwrap()
+            .get(&self.key)
+            .ok_or(KernelError::MissingCapability)?;
 
-        f(cap)
+        Ok(f(&node.cap))
 
         // unlock
     }
-}
 
-impl Clone for ResourceHandle {
-    fn clone(&self) -> Self {
-        ResourceHandle {
-            key: self.key.clone(),
+    /// A reference to the capability this handle is on, which keeps it from being dropped even
+    /// after every handle on it is gone, e.g. while its memory is mapped somewhere.
+    ///
+    /// Returns `MissingCapability` if the current continuation does not hold this capability or it
+    /// has been revoked.
+    pub fn pin(&self) -> Result<Arc<Capability>, KernelError> {
+        if !is_held(self) {
+            return Err(KernelError::MissingCapability);
+        }
+
+        let reg = CAPABILITY_REGISTRY.lock();
+        let node = reg
+            .as_ref()
+            .unwrap()
+            .get(&self.key)
+            .ok_or(KernelError::MissingCapability)?;
+
+        Ok(node.cap.clone())
+
+        // unlock
+    }
+
+    /// The rights this handle grants to the current continuation. If it is only held through a
+    /// group, those are the rights that both this handle and the group's handle grant.
+    ///
+    /// Returns `MissingCapability` if the current continuation does not hold this capability or it
+    /// has been revoked.
+    pub fn rights(&self) -> Result<Rights, KernelError> {
+        held_rights(self).ok_or(KernelError::MissingCapability)
+    }
+
+    /// Returns `InsufficientRights` unless this handle grants all of `rights`.
+    pub fn check_rights(&self, rights: Rights) -> Result<(), KernelError> {
+        if self.rights()?.contains(rights) {
+            Ok(())
+        } else {
+            Err(KernelError::InsufficientRights)
         }
     }
-}
 
-impl Copy for ResourceHandle {}
+    /// The handles in the group this handle is on, in the order that they were given to
+    /// `CapabilityGroup::create` in. The current continuation can use all of them, since it holds
+    /// the group.
+    ///
+    /// Returns `MissingCapability` if the current continuation does not hold this capability or it
+    /// has been revoked, and `WrongCapabilityType` if it is not a group.
+    pub fn members(&self) -> Result<Vec<ResourceHandle>, KernelError> {
+        self.with(|cap| match cap {
+            Capability::CapabilityGroup(group) => Ok(group.iter().cloned().collect()),
+            _ => Err(KernelError::WrongCapabilityType),
+        })?
+    }
+
+    /// The handle of the member at `index` of the group this handle is on. Members are in the
+    /// order that they were given to `CapabilityGroup::create` in.
+    ///
+    /// Returns `MissingCapability` if the current continuation does not hold this capability, it
+    /// has been revoked, or the group has no more than `index` members, and `WrongCapabilityType`
+    /// if it is not a group.
+    #[allow(dead_code)]
+    pub fn member(&self, index: usize) -> Result<ResourceHandle, KernelError> {
+        self.members()?
+            .into_iter()
+            .nth(index)
+            .ok_or(KernelError::MissingCapability)
+    }
+}
 
 /// A capability that has not been registered yet.  An unregistered capability can be modified
 /// until it is registered.
@@ -129,26 +407,30 @@ impl UnregisteredResourceHandle {
     /// be updated.
     pub fn register(self) -> ResourceHandle {
         let mut locked = CAPABILITY_REGISTRY.lock();
+        let reg = locked.as_mut().unwrap();
 
-        // Generate a new random key. We are generating 128-bit random value, so the odds of a
-        // collision by chance or by malicious users are extremely low.
-        //
-        // NOTE: I am not actually using a random sequence because I am seeding the RNG.
-        let mut rand = { CAPABILITY_RNG.lock().as_mut().unwrap().gen() }; // unlock
+        let key = fresh_key(reg);
+        reg.insert(
+            key,
+            Node {
+                cap: Arc::new(self.resource),
+                rights: Rights::ALL,
+                parent: None,
+                children: BTreeSet::new(),
+                live: Arc::new(AtomicBool::new(true)),
+            },
+        );
 
-        while locked.as_mut().unwrap().contains_key(&rand) {
-            // extremely unlikely...
-            rand = rand;
-        }
-
-        locked
-            .as_mut()
-            .unwrap()
-            .insert(rand, Box::new(self.resource));
+        drop(locked); // unlock
 
-        ResourceHandle { key: rand }
+        // The registering continuation holds the new capability.
+        let handle = ResourceHandle {
+            key,
+            received: None,
+        };
+        hold(&handle);
 
-        // unlock
+        handle
     }
 
     /// Return an immutable reference to the resource.
@@ -167,16 +449,629 @@ impl UnregisteredResourceHandle {
 // Implementations of different capabilities.
 ////////////////////////////////////////////////////////////////////////////////
 
-/// Capability on a group of capabilities.
-#[derive(Debug)]
+/// Capability on a group of capabilities. This is also used for the set of handles held by a
+/// continuation, which may include groups.
+///
+/// The handles are kept in the order they were added in, so that the members of a group can be
+/// told apart by their index (see `ResourceHandle::member`). Groups are small, so looking through
+/// all of them is cheap enough.
+#[derive(Clone, Debug, Default)]
 pub struct CapabilityGroup {
-    caps: Vec<Capability>,
+    caps: Vec<ResourceHandle>,
 }
 
 impl CapabilityGroup {
-    #[allow(dead_code)]
-    pub fn new(caps: Vec<Capability>) -> Self {
-        // TODO: make sure there are no groups within...
-        CapabilityGroup { caps }
+    /// A set of handles. This does not check for groups, so use `create` to make a group
+    /// capability.
+    pub fn new(caps: Vec<ResourceHandle>) -> Self {
+        let mut group = CapabilityGroup::default();
+        for handle in caps {
+            group.insert(handle);
+        }
+        group
+    }
+
+    /// Create a group capability with the handles in `caps`, which the current continuation must
+    /// hold.
+    ///
+    /// Returns `MissingCapability` if the current continuation does not hold one of `caps` or it
+    /// has been revoked, and `NestedCapabilityGroup` if one of them is a group.
+    pub fn create(caps: Vec<ResourceHandle>) -> Result<UnregisteredResourceHandle, KernelError> {
+        for cap in caps.iter() {
+            if cap.with(Capability::ty)? == CapabilityType::CapabilityGroup {
+                return Err(KernelError::NestedCapabilityGroup);
+            }
+        }
+
+        Ok(UnregisteredResourceHandle::new(
+            Capability::CapabilityGroup(CapabilityGroup::new(caps)),
+        ))
+    }
+
+    /// Is `handle` in this group?
+    pub fn contains(&self, handle: &ResourceHandle) -> bool {
+        self.caps.contains(handle)
+    }
+
+    /// Add `handle` to this group, unless it is in it already.
+    pub fn insert(&mut self, handle: ResourceHandle) {
+        if !self.contains(&handle) {
+            self.caps.push(handle);
+        }
+    }
+
+    /// Remove `handle` from this group.
+    pub fn remove(&mut self, handle: &ResourceHandle) {
+        self.caps.retain(|held| held != handle);
+    }
+
+    /// The number of handles in this group.
+    pub fn len(&self) -> usize {
+        self.caps.len()
+    }
+
+    /// Returns the capabilities in both `self` and `other`.
+    pub fn intersection(&self, other: &CapabilityGroup) -> CapabilityGroup {
+        CapabilityGroup {
+            caps: self
+                .caps
+                .iter()
+                .filter(|handle| other.contains(handle))
+                .cloned()
+                .collect(),
+        }
+    }
+
+    /// Iterate over the handles in this group.
+    pub fn iter(&self) -> slice::Iter<ResourceHandle> {
+        self.caps.iter()
+    }
+
+    /// The rights that holding the handles in this set gives on `handle`, or `None` if it gives no
+    /// access to `handle` or `handle` has been revoked. A handle that is only in this set through
+    /// groups grants the rights that both it and one of the groups' handles grant. Groups can't be
+    /// nested, so there is no need to look any deeper.
+    pub fn rights_on(&self, handle: &ResourceHandle) -> Option<Rights> {
+        let reg = CAPABILITY_REGISTRY.lock();
+        let reg = reg.as_ref().unwrap();
+
+        let rights = reg.get(&handle.key)?.rights;
+        if self.contains(handle) {
+            return Some(rights);
+        }
+
+        self.iter()
+            .filter_map(|held| reg.get(&held.key))
+            .filter_map(|node| match &*node.cap {
+                Capability::CapabilityGroup(group) if group.contains(handle) => {
+                    Some(node.rights & rights)
+                }
+                _ => None,
+            })
+            .fold(None, |all, rights| {
+                Some(all.map_or(rights, |all| all | rights))
+            })
+
+        // unlock
+    }
+}
+
+/// The handles a user task can name, by small indices like file descriptors. User space only ever
+/// sees indices into the calling task's own table, so a task can't name a handle it was not given.
+/// The table is filled in explicitly by whoever starts the task, and handles the task derives are
+/// added to it.
+///
+/// Each handle is resolved when it is added, which is only possible if the current continuation
+/// holds it, so using it later doesn't need the registry (see `TableEntry`).
+#[derive(Debug, Default)]
+pub struct HandleTable {
+    /// The handles, by index. The slots of removed handles are empty until they are reused.
+    slots: Vec<Option<TableEntry>>,
+
+    /// The indices of the empty slots.
+    free: BTreeSet<usize>,
+}
+
+impl HandleTable {
+    /// A table with `handles` at indices 0, 1, 2, ... in order.
+    ///
+    /// Returns `MissingCapability` if the current continuation does not hold one of `handles` or
+    /// it has been revoked.
+    pub fn new(handles: Vec<ResourceHandle>) -> Result<Self, KernelError> {
+        Ok(HandleTable {
+            slots: handles
+                .into_iter()
+                .map(|handle| TableEntry::new(handle).map(Some))
+                .collect::<Result<_, _>>()?,
+            free: BTreeSet::new(),
+        })
+    }
+
+    /// The entry at `index`, if there is one.
+    pub fn get(&self, index: usize) -> Option<&TableEntry> {
+        self.slots.get(index)?.as_ref()
+    }
+
+    /// Add `handle` to the table in the lowest empty slot, and return its index.
+    ///
+    /// Returns `MissingCapability` if the current continuation does not hold `handle` or it has
+    /// been revoked.
+    pub fn insert(&mut self, handle: ResourceHandle) -> Result<usize, KernelError> {
+        let entry = TableEntry::new(handle)?;
+
+        Ok(match self.free.iter().next().copied() {
+            Some(index) => {
+                self.free.remove(&index);
+                self.slots[index] = Some(entry);
+                index
+            }
+            None => {
+                self.slots.push(Some(entry));
+                self.slots.len() - 1
+            }
+        })
+    }
+
+    /// Take the entry at `index` out of the table, if there is one. The index may be reused.
+    pub fn remove(&mut self, index: usize) -> Option<TableEntry> {
+        let entry = self.slots.get_mut(index)?.take()?;
+        self.free.insert(index);
+        Some(entry)
+    }
+
+    /// Iterate over the indices and entries in the table, in order of index.
+    pub fn iter(&self) -> impl Iterator<Item = (usize, &TableEntry)> {
+        self.slots
+            .iter()
+            .enumerate()
+            .filter_map(|(index, slot)| slot.as_ref().map(|entry| (index, entry)))
+    }
+}
+
+/// A handle in a `HandleTable`, along with the capability it is on and the rights it grants, so
+/// that a system call can use it without locking the registry or checking what the task holds.
+///
+/// The entry doesn't keep the capability alive, so revoking or destroying the handle still frees
+/// the resource. Once that happens, using the entry fails with `MissingCapability`.
+#[derive(Clone, Debug)]
+pub struct TableEntry {
+    handle: ResourceHandle,
+    cap: Weak<Capability>,
+    rights: Rights,
+
+    /// Shared with the handle's node in the registry.
+    live: Arc<AtomicBool>,
+
+    /// The entries of the members, if the handle is on a group.
+    members: Arc<[TableEntry]>,
+}
+
+impl TableEntry {
+    /// Resolve `handle` for use in a `HandleTable`.
+    ///
+    /// Returns `MissingCapability` if the current continuation does not hold `handle` or it has
+    /// been revoked.
+    pub fn new(handle: ResourceHandle) -> Result<Self, KernelError> {
+        let rights = held_rights(&handle).ok_or(KernelError::MissingCapability)?;
+
+        let reg = CAPABILITY_REGISTRY.lock();
+        Self::resolve(reg.as_ref().unwrap(), handle, rights).ok_or(KernelError::MissingCapability)
+
+        // unlock
+    }
+
+    /// The entry for `handle`, which grants `rights`, and its members, if it is in `reg`. A member
+    /// only grants the rights that both its own handle and the group's grant. Groups can't be
+    /// nested, so the members have no members of their own.
+    fn resolve(reg: &BTreeMap<u128, Node>, handle: ResourceHandle, rights: Rights) -> Option<Self> {
+        let node = reg.get(&handle.key)?;
+        let members = match &*node.cap {
+            Capability::CapabilityGroup(group) => group
+                .iter()
+                .filter_map(|member| {
+                    let member_rights = reg.get(&member.key)?.rights & rights;
+                    Self::resolve(reg, member.clone(), member_rights)
+                })
+                .collect(),
+            _ => Vec::new(),
+        };
+
+        Some(TableEntry {
+            handle,
+            cap: Arc::downgrade(&node.cap),
+            rights,
+            live: node.live.clone(),
+            members: members.into(),
+        })
+    }
+
+    /// The handle this entry is for.
+    pub fn handle(&self) -> &ResourceHandle {
+        &self.handle
+    }
+
+    /// Like `ResourceHandle::with`, but without the registry lock.
+    ///
+    /// Returns `MissingCapability` if the handle has been destroyed or revoked.
+    pub fn with<F, R>(&self, f: F) -> Result<R, KernelError>
+    where
+        F: FnOnce(&Capability) -> R,
+    {
+        if !self.live.load(Ordering::Acquire) {
+            return Err(KernelError::MissingCapability);
+        }
+
+        let cap = self.cap.upgrade().ok_or(KernelError::MissingCapability)?;
+
+        Ok(f(&cap))
+    }
+
+    /// The rights the handle grants.
+    ///
+    /// Returns `MissingCapability` if the handle has been destroyed or revoked.
+    pub fn rights(&self) -> Result<Rights, KernelError> {
+        if self.live.load(Ordering::Acquire) {
+            Ok(self.rights)
+        } else {
+            Err(KernelError::MissingCapability)
+        }
+    }
+
+    /// Returns `InsufficientRights` unless the handle grants all of `rights`.
+    pub fn check_rights(&self, rights: Rights) -> Result<(), KernelError> {
+        if self.rights()?.contains(rights) {
+            Ok(())
+        } else {
+            Err(KernelError::InsufficientRights)
+        }
+    }
+
+    /// The entry of the first member of the group the handle is on that is a capability of type
+    /// `ty`, in the order of `ResourceHandle::members`.
+    ///
+    /// Returns `MissingCapability` if the handle has been destroyed or revoked, or the group has
+    /// no (unrevoked) member of type `ty`, and `WrongCapabilityType` if it is not a group.
+    pub fn member(&self, ty: CapabilityType) -> Result<&TableEntry, KernelError> {
+        if self.with(Capability::ty)? != CapabilityType::CapabilityGroup {
+            return Err(KernelError::WrongCapabilityType);
+        }
+
+        self.members
+            .iter()
+            .find(|member| {
+                member
+                    .with(Capability::ty)
+                    .map_or(false, |found| found == ty)
+            })
+            .ok_or(KernelError::MissingCapability)
+    }
+}
+
+/// Returns a new handle on the same resource as `handle`, derived from it, so that revoking
+/// `handle` also revokes the new handle. The new handle has the rights in `rights` that `handle`
+/// grants the current continuation (see `ResourceHandle::rights`), and no others. The current
+/// continuation holds the new handle.
+///
+/// Returns `MissingCapability` if the current continuation does not hold `handle` or it has been
+/// revoked.
+pub fn derive(handle: &ResourceHandle, rights: Rights) -> Result<ResourceHandle, KernelError> {
+    let held = held_rights(handle).ok_or(KernelError::MissingCapability)?;
+
+    let key = add_child(
+        CAPABILITY_REGISTRY.lock().as_mut().unwrap(),
+        handle.key,
+        held & rights,
+    )
+    .ok_or(KernelError::MissingCapability)?;
+
+    let derived = ResourceHandle {
+        key,
+        received: None,
+    };
+    hold(&derived);
+
+    Ok(derived)
+}
+
+/// Returns the handle that a continuation gets when it receives `sent` in a message: a new handle
+/// derived from `sent`, so that the sender can revoke it. The new handle is removed from the
+/// registry once the last copy of it is dropped, e.g. once the receiver and any continuations it
+/// passed the handle on to are done with it. If `sent` has been revoked in the meantime, the
+/// receiver gets `sent` itself, which is no use to it. The new handle has the rights of `sent`.
+///
+/// This acts on behalf of the sender, which held `sent`, so it doesn't check the capabilities of
+/// the current continuation, and the caller decides who holds the new handle.
+pub fn receive(sent: ResourceHandle) -> ResourceHandle {
+    let mut locked = CAPABILITY_REGISTRY.lock();
+    let key = match add_child(locked.as_mut().unwrap(), sent.key, Rights::ALL) {
+        Some(key) => key,
+        None => return sent,
+    };
+    drop(locked); // unlock
+
+    // Dropping the last copy of `sent` removes it from the registry, which needs the lock.
+    drop(sent);
+
+    ResourceHandle {
+        key,
+        received: Some(Arc::new(Received { key })),
+    }
+}
+
+/// Revoke `handle` and every handle derived from it, directly or indirectly. Later attempts to use
+/// any of them fail. The resource itself goes away once no handles on it are left.
+///
+/// Returns `MissingCapability` if the current continuation does not hold `handle` or it has already
+/// been revoked.
+pub fn revoke(handle: &ResourceHandle) -> Result<(), KernelError> {
+    if !is_held(handle) {
+        return Err(KernelError::MissingCapability);
+    }
+
+    let mut locked = CAPABILITY_REGISTRY.lock();
+    let reg = locked.as_mut().unwrap();
+
+    let node = reg
+        .remove(&handle.key)
+        .ok_or(KernelError::MissingCapability)?;
+
+    if let Some(parent) = node.parent.and_then(|parent| reg.get_mut(&parent)) {
+        parent.children.remove(&handle.key);
+    }
+
+    // Remove the whole subtree.
+    let mut subtree = vec![node];
+    let mut revoked = Vec::new();
+    while let Some(node) = subtree.pop() {
+        subtree.extend(node.children.iter().filter_map(|child| reg.remove(child)));
+        node.live.store(false, Ordering::Release);
+        revoked.push(node);
+    }
+
+    drop(locked); // unlock
+
+    // The revoked capabilities may hold the last copies of received handles (e.g. in a channel's
+    // queue), and dropping those needs the lock. Freeing any resources that are now gone can also
+    // be expensive.
+    drop(revoked);
+
+    Ok(())
+}
+
+/// Add a node derived from `parent` to `reg` with the rights in `rights` that `parent` has, and
+/// return its key. Returns `None` if `parent` is not in `reg`, e.g. because it has been revoked.
+fn add_child(reg: &mut BTreeMap<u128, Node>, parent: u128, rights: Rights) -> Option<u128> {
+    let node = reg.get(&parent)?;
+    let cap = node.cap.clone();
+    let rights = node.rights & rights;
+
+    let key = fresh_key(reg);
+    reg.insert(
+        key,
+        Node {
+            cap,
+            rights,
+            parent: Some(parent),
+            children: BTreeSet::new(),
+            live: Arc::new(AtomicBool::new(true)),
+        },
+    );
+    reg.get_mut(&parent).unwrap().children.insert(key);
+
+    Some(key)
+}
+
+/// Keeps the node of a received handle (see `receive`) in the registry. It is shared by all copies
+/// of the handle, and the node is removed when the last of them is dropped.
+#[derive(Debug)]
+struct Received {
+    /// The key of the received handle.
+    key: u128,
+}
+
+impl Drop for Received {
+    fn drop(&mut self) {
+        let mut locked = CAPABILITY_REGISTRY.lock();
+        let reg = locked.as_mut().unwrap();
+
+        // It may have been revoked already.
+        let node = detach(reg, self.key);
+
+        drop(locked); // unlock
+
+        // The capability may hold the last copies of other received handles, or be the last handle
+        // on its resource.
+        drop(node);
+    }
+}
+
+/// Destroy `handle`, leaving any handles derived from it as if they had been derived from its
+/// parent. The current continuation no longer holds `handle`, and later attempts to use it fail.
+/// Once no handles on a resource are left, the resource is freed (e.g. a memory region is unmapped
+/// and its memory is returned).
+///
+/// Returns `MissingCapability` if the current continuation does not hold `handle` or it has already
+/// been destroyed or revoked.
+pub fn destroy(handle: &ResourceHandle) -> Result<(), KernelError> {
+    if !is_held(handle) {
+        return Err(KernelError::MissingCapability);
+    }
+
+    let mut locked = CAPABILITY_REGISTRY.lock();
+    let reg = locked.as_mut().unwrap();
+
+    let node = detach(reg, handle.key).ok_or(KernelError::MissingCapability)?;
+
+    drop(locked); // unlock
+
+    if let Some(Authority::Continuation(current)) = CURRENT.get().lock().as_mut() {
+        current.remove(handle);
+    }
+
+    // Free the resource if this was the last handle on it, outside of the lock.
+    drop(node);
+
+    Ok(())
+}
+
+/// Remove the node `key` from `reg` and return it, handing any handles derived from it over to its
+/// parent, so that revoking the parent still revokes them. Its `TableEntry`s stop working. Returns
+/// `None` if `key` is not in `reg`.
+fn detach(reg: &mut BTreeMap<u128, Node>, key: u128) -> Option<Node> {
+    let node = reg.remove(&key)?;
+    node.live.store(false, Ordering::Release);
+
+    for child in node.children.iter() {
+        reg.get_mut(child).unwrap().parent = node.parent;
+    }
+    if let Some(parent) = node.parent.and_then(|parent| reg.get_mut(&parent)) {
+        parent.children.remove(&key);
+        parent.children.extend(node.children.iter().copied());
+    }
+
+    Some(node)
+}
+
+/// Runs `f` on every capability the current continuation can access, including the members of
+/// groups it holds, along with the rights it has through the handle (or group) it can access it
+/// through, and collects the results that are not `None`. The kernel itself is not considered to
+/// hold anything.
+///
+/// NOTE: This holds the registry lock, so nothing expensive should be done in `f`.
+pub fn accessible<F, T>(mut f: F) -> Vec<T>
+where
+    F: FnMut(&Capability, Rights) -> Option<T>,
+{
+    let current = CURRENT.get().lock();
+    let current = match current.as_ref() {
+        Some(Authority::Continuation(current)) => current,
+        _ => return Vec::new(),
+    };
+
+    let reg = CAPABILITY_REGISTRY.lock();
+    let reg = reg.as_ref().unwrap();
+
+    let mut found = Vec::new();
+    let mut visit = |handle: &ResourceHandle, rights: Rights| {
+        if let Some(node) = reg.get(&handle.key) {
+            found.extend(f(&node.cap, node.rights & rights));
+        }
+    };
+
+    for handle in current.iter() {
+        visit(handle, Rights::ALL);
+
+        if let Some(node) = reg.get(&handle.key) {
+            if let Capability::CapabilityGroup(group) = &*node.cap {
+                group.iter().for_each(|member| visit(member, node.rights));
+            }
+        }
+    }
+
+    found
+
+    // unlock
+}
+
+/// Returns a key that is not in use in `reg`. We are generating 128-bit random values, so the odds
+/// of a collision are extremely low.
+fn fresh_key(reg: &BTreeMap<u128, Node>) -> u128 {
+    let mut rng = CAPABILITY_RNG.lock();
+    let rng = rng.as_mut().unwrap();
+
+    loop {
+        let key = rng.gen();
+        if !reg.contains_key(&key) {
+            return key;
+        }
+    }
+}
+
+/// Whose authority code runs with (see `CURRENT`).
+enum Authority {
+    /// The running continuation's, which can access the capabilities in its group.
+    Continuation(CapabilityGroup),
+
+    /// The kernel's, which can access everything.
+    Kernel,
+}
+
+/// Proof that the code holding it acts for the kernel itself, which it needs to run code with the
+/// kernel's authority with `as_kernel`.
+pub struct KernelAuthority(());
+
+impl KernelAuthority {
+    /// Claim the kernel's authority.
+    ///
+    /// # Safety
+    ///
+    /// The caller must only use the authority on behalf of the kernel itself, or of continuations
+    /// whose capabilities have been checked already (e.g. by the scheduler, which waits for events
+    /// on their behalf). Otherwise, continuations could use capabilities that they don't hold.
+    pub unsafe fn new() -> Self {
+        KernelAuthority(())
+    }
+}
+
+/// The current continuation (if any) now holds `handle`.
+fn hold(handle: &ResourceHandle) {
+    if let Some(Authority::Continuation(current)) = CURRENT.get().lock().as_mut() {
+        current.insert(handle.clone());
+    }
+}
+
+/// Make `caps` the capabilities of the currently running continuation.
+pub fn enter(caps: CapabilityGroup) {
+    *CURRENT.get().lock() = Some(Authority::Continuation(caps));
+}
+
+/// The current continuation is done running. Returns its capabilities, including any it
+/// registered while running.
+pub fn leave() -> CapabilityGroup {
+    match CURRENT.get().lock().take() {
+        Some(Authority::Continuation(caps)) => caps,
+        _ => CapabilityGroup::default(),
+    }
+}
+
+/// The capabilities of the currently running continuation, if any. Code running in a continuation
+/// uses this to give its capabilities to a continuation that carries on from it but is not one of
+/// its children.
+pub fn current() -> CapabilityGroup {
+    match CURRENT.get().lock().as_ref() {
+        Some(Authority::Continuation(caps)) => caps.clone(),
+        _ => CapabilityGroup::default(),
+    }
+}
+
+/// Run `f` with the authority of the kernel rather than that of the current continuation (if any),
+/// e.g. to act on behalf of other continuations.
+pub fn as_kernel<R>(_: &KernelAuthority, f: impl FnOnce() -> R) -> R {
+    let current = mem::replace(&mut *CURRENT.get().lock(), Some(Authority::Kernel));
+    let result = f();
+    *CURRENT.get().lock() = current;
+    result
+}
+
+/// Is `handle` accessible in the current context? It is if the current continuation holds it or a
+/// group that it is in.
+fn is_held(handle: &ResourceHandle) -> bool {
+    held_rights(handle).is_some()
+}
+
+/// The rights that the current context has on `handle` (see `CapabilityGroup::rights_on`), or
+/// `None` if it can't access it or it has been revoked.
+fn held_rights(handle: &ResourceHandle) -> Option<Rights> {
+    match CURRENT.get().lock().as_ref() {
+        Some(Authority::Continuation(current)) => current.rights_on(handle),
+        Some(Authority::Kernel) => CAPABILITY_REGISTRY
+            .lock()
+            .as_ref()
+            .unwrap()
+            .get(&handle.key)
+            .map(|node| node.rights),
+
+        // Outside of a continuation, only code with a `KernelAuthority` can access anything.
+        None => None,
     }
 }
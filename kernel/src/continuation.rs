@@ -1,11 +1,69 @@
 //! A module for defining continuations and events
 
-use alloc::{boxed::Box, vec, vec::Vec};
+use alloc::{boxed::Box, sync::Arc, vec, vec::Vec};
 
-use crate::{sched, time::SysTime};
+use core::{
+    fmt,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use crate::{
+    cap::{self, CapabilityGroup, ResourceHandle, Rights},
+    error::KernelError,
+    ipc::Message,
+    sched::{self, policy, user::TaskHandle},
+    smp::PerCpu,
+    time::SysTime,
+};
+
+/// The next continuation id to be handed out.
+static NEXT_CONT_ID: AtomicUsize = AtomicUsize::new(0);
+
+/// The error handlers of the continuation running on each core, if any. See `current_handlers`.
+static CURRENT_HANDLERS: PerCpu<Option<Arc<HandlerChain>>> = PerCpu::new();
+
+/// The result value of a continuation that finished with `ContResult::Return`.
+pub type ContValue = Box<dyn core::any::Any + Send>;
+
+/// Creates the continuation that handles an error. See `Continuation::on_error`.
+pub type ErrorHandler = Arc<dyn Fn() -> Continuation + Send + Sync>;
+
+/// A chain of error handlers, innermost first. If a handler itself fails, the error goes to the
+/// next handler in the chain.
+pub struct HandlerChain {
+    handler: ErrorHandler,
+    outer: Option<Arc<HandlerChain>>,
+}
+
+/// Uniquely identifies a continuation. Continuations can wait for a set of other continuations to
+/// finish with `EventKind::Join`.
+#[derive(Copy, Clone, Debug, Eq, Ord, PartialEq, PartialOrd)]
+pub struct ContId(usize);
+
+impl ContId {
+    /// The id of every idle continuation. Idle continuations don't use up fresh ids, so that the
+    /// ids of other continuations don't depend on how often cores go idle.
+    pub const IDLE: ContId = ContId(usize::MAX);
+
+    /// Returns a fresh id.
+    pub fn new() -> Self {
+        ContId(NEXT_CONT_ID.fetch_add(1, Ordering::Relaxed))
+    }
+
+    /// Returns the id that is displayed as `id`, e.g. to read it back from a log.
+    pub fn from_raw(id: usize) -> Self {
+        ContId(id)
+    }
+}
+
+impl fmt::Display for ContId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
 
 /// Different kinds of events a continuation can wait for.
-#[derive(Copy, Clone, Eq, Ord, PartialEq, PartialOrd)]
+#[derive(Clone, Debug, Eq, Ord, PartialEq, PartialOrd)]
 pub enum EventKind {
     /// Wait for "now" to occur. i.e. don't wait for anything.
     Now,
@@ -15,10 +73,56 @@ pub enum EventKind {
 
     /// Wait for the system "clock" to have a given reading.
     Until(SysTime),
+
+    /// Wait for the given user task to exit.
+    TaskExit(TaskHandle),
+
+    /// Wait for a message to arrive on the given channel.
+    ChannelRecv(ResourceHandle),
+
+    /// Wait for there to be room on the given channel, then send the given message.
+    ChannelSend(ResourceHandle, Message),
+
+    /// Wait for an interrupt on the IRQ line of the given capability (see `interrupts::irq`).
+    Irq(ResourceHandle),
+
+    /// Wait for all of the given continuations to finish (i.e. return `ContResult::Done`,
+    /// `ContResult::Return`, or `ContResult::Error`).
+    ///
+    /// A continuation's completion is only remembered if some pending `Join` is waiting for it,
+    /// so the `Join` must be enqueued no later than the continuations it waits for (e.g. in the
+    /// same `ContResult::Success`). Each continuation can only be joined once.
+    Join(Vec<ContId>),
+
+    /// Wait for whichever of the given events happens first. The other events are cancelled;
+    /// in particular, they do not consume anything (e.g. keyboard input).
+    Any(Vec<EventKind>),
+}
+
+impl EventKind {
+    /// The name of this kind of event, without any arguments.
+    pub fn name(&self) -> &'static str {
+        match self {
+            EventKind::Now => "Now",
+            EventKind::Keyboard => "Keyboard",
+            EventKind::Until(_) => "Until",
+            EventKind::TaskExit(_) => "TaskExit",
+            EventKind::ChannelRecv(_) => "ChannelRecv",
+            EventKind::ChannelSend(..) => "ChannelSend",
+            EventKind::Irq(_) => "Irq",
+            EventKind::Join(_) => "Join",
+            EventKind::Any(_) => "Any",
+        }
+    }
+
+    /// Wait for `self` or for `secs` seconds to pass, whichever comes first. If the timeout
+    /// fires, the continuation gets `Event::Any { branch: 1, .. }`.
+    pub fn with_timeout(self, secs: usize) -> EventKind {
+        EventKind::Any(vec![self, EventKind::Until(SysTime::now().after(secs))])
+    }
 }
 
 /// The events corresponding to `EventKind`.
-#[derive(Copy, Clone)]
 pub enum Event {
     /// Wow! It's now!
     Now,
@@ -28,6 +132,30 @@ pub enum Event {
 
     /// A timer has expired
     Timer,
+
+    /// A user task has exited with the given exit code
+    TaskExited { code: isize },
+
+    /// The given message was received on a channel
+    Message(Message),
+
+    /// A message was sent on a channel
+    MessageSent,
+
+    /// An interrupt arrived on the given IRQ line
+    Irq(u8),
+
+    /// All joined continuations have finished. The results are given in the same order as the
+    /// `ContId`s in the `EventKind::Join`. Continuations that returned `ContResult::Done` or
+    /// `ContResult::Error` have no result.
+    Joined(Vec<Option<ContValue>>),
+
+    /// Some continuation failed with the given error, and this continuation is its handler
+    Error(KernelError),
+
+    /// One of the events of an `EventKind::Any` happened. `branch` is the index of the
+    /// `EventKind` that fired, and `event` is its event.
+    Any { branch: usize, event: Box<Event> },
 }
 
 /// The possible results of running a continuation.
@@ -36,29 +164,285 @@ pub enum ContResult {
     /// The continuation suceeded and the next continuation and its precondition are given.
     Success(Vec<(EventKind, Continuation)>),
 
-    /// The Continuation failed and here is the continuation to handle the error.
-    Error(Continuation),
+    /// The Continuation failed with the given error. The error is delivered as `Event::Error` to
+    /// the given handler or, if none is given, to the nearest handler attached with
+    /// `Continuation::on_error` to this continuation or one of its ancestors.
+    Error(KernelError, Option<Continuation>),
 
     /// The continuation suceeded and there is nothing left to be done.
     Done,
+
+    /// The continuation suceeded with the given result, and there is nothing left to be done. The
+    /// result is passed to whoever joins this continuation.
+    Return(ContValue),
+}
+
+impl ContResult {
+    /// Run all of the `children` now, then run `then` once all of them have finished. `then` gets
+    /// an `Event::Joined` with the children's results.
+    #[allow(dead_code)]
+    pub fn fork_join(children: Vec<Continuation>, then: Continuation) -> ContResult {
+        let ids = children.iter().map(Continuation::id).collect();
+
+        let mut conts = vec![(EventKind::Join(ids), then)];
+        conts.extend(children.into_iter().map(|child| (EventKind::Now, child)));
+
+        ContResult::Success(conts)
+    }
 }
 
 /// Represents a single Task in the system
 pub struct Continuation {
+    id: ContId,
+
+    /// The continuation that produced this one, if any.
+    parent: Option<ContId>,
+
+    /// A human-readable label for debugging.
+    label: &'static str,
+
+    /// When this continuation was created.
+    created: SysTime,
+
     routine: Option<Box<dyn FnMut(Event) -> ContResult + Send>>,
+
+    /// The error handler attached to this continuation with `on_error`, if any.
+    handler: Option<ErrorHandler>,
+
+    /// The error handlers inherited from this continuation's ancestors.
+    outer: Option<Arc<HandlerChain>>,
+
+    /// If this continuation is an error handler, the error it should handle.
+    error: Option<KernelError>,
+
+    /// The capabilities this continuation holds. `None` means that it inherits all of its parent's
+    /// capabilities.
+    caps: Option<CapabilityGroup>,
+
+    /// The name of the `EventKind` this continuation is waiting for and when it started waiting,
+    /// while it is in the scheduler.
+    waiting: Option<(&'static str, SysTime)>,
+
+    /// The priority of this continuation, used by `sched::policy::Priority`.
+    priority: usize,
+
+    /// The time by which this continuation should run, used by `sched::policy::Edf`.
+    deadline: Option<SysTime>,
+
+    /// The number of lottery tickets this continuation holds, used by `sched::policy::Lottery`.
+    tickets: usize,
+
+    /// The size of the stack this continuation needs, in pages.
+    stack_pages: usize,
 }
 
 impl Continuation {
     /// Create a new `Task` struct whose entry point is the `main_fn` function
     pub fn new<F>(routine: F) -> Continuation
+    where
+        F: 'static + Send + FnMut(Event) -> ContResult,
+    {
+        Self::with_routine(ContId::new(), routine)
+    }
+
+    /// Create an idle continuation, which has the id `ContId::IDLE`.
+    pub fn idle<F>(routine: F) -> Continuation
+    where
+        F: 'static + Send + FnMut(Event) -> ContResult,
+    {
+        Self::with_routine(ContId::IDLE, routine)
+    }
+
+    /// Create a continuation with the given id whose entry point is `routine`.
+    fn with_routine<F>(id: ContId, routine: F) -> Continuation
     where
         F: 'static + Send + FnMut(Event) -> ContResult,
     {
         Continuation {
+            id,
+            parent: None,
+            label: "",
+            created: SysTime::now(),
             routine: Some(Box::new(routine)),
+            handler: None,
+            outer: None,
+            error: None,
+            caps: None,
+            waiting: None,
+            priority: policy::DEFAULT_PRIORITY,
+            deadline: None,
+            tickets: policy::DEFAULT_TICKETS,
+            stack_pages: sched::STACK_PAGES,
         }
     }
 
+    /// Only pass the given capabilities to this continuation. By default, a continuation inherits
+    /// all of its parent's capabilities. A continuation can never get capabilities that its
+    /// parent does not hold.
+    pub fn with_caps(mut self, caps: Vec<ResourceHandle>) -> Continuation {
+        self.caps = Some(CapabilityGroup::new(caps));
+        self
+    }
+
+    /// Give this continuation its share of its parent's capabilities, `parent`.
+    fn inherit_caps(&mut self, parent: &CapabilityGroup) {
+        self.caps = Some(match self.caps.take() {
+            Some(caps) => caps.intersection(parent),
+            None => parent.clone(),
+        });
+    }
+
+    /// Does this continuation hold all of the capabilities needed to wait for `kind`, with the
+    /// rights needed? They may be held directly or through groups.
+    fn can_wait_for(&self, kind: &EventKind) -> bool {
+        let holds = |handle: &ResourceHandle, rights| {
+            self.caps
+                .as_ref()
+                .and_then(|caps| caps.rights_on(handle))
+                .map_or(false, |held: Rights| held.contains(rights))
+        };
+
+        match kind {
+            EventKind::ChannelRecv(chan) => holds(chan, Rights::READ),
+            EventKind::ChannelSend(chan, Message::Handle(handle)) => {
+                holds(chan, Rights::WRITE) && holds(handle, Rights::GRANT)
+            }
+            EventKind::ChannelSend(chan, _) => holds(chan, Rights::WRITE),
+            EventKind::Irq(irq) => holds(irq, Rights::READ),
+            EventKind::Any(kinds) => kinds.iter().all(|kind| self.can_wait_for(kind)),
+            _ => true,
+        }
+    }
+
+    /// Attach a default error handler to this continuation and all of the continuations it
+    /// (transitively) produces. When any of them fails without giving an explicit handler,
+    /// `handler` is called to create a continuation, which runs with `Event::Error`.
+    ///
+    /// If a handler is already attached to an ancestor, the new handler takes precedence, and an
+    /// error in the new handler (or its descendants) goes to the ancestor's handler.
+    pub fn on_error<F>(mut self, handler: F) -> Continuation
+    where
+        F: 'static + Send + Sync + Fn() -> Continuation,
+    {
+        self.handler = Some(Arc::new(handler));
+        self
+    }
+
+    /// Give this continuation the error handlers `handlers` (see `current_handlers`), as if it had
+    /// been produced by the continuation they belong to.
+    pub fn with_handlers(mut self, handlers: Option<Arc<HandlerChain>>) -> Continuation {
+        self.outer = handlers;
+        self
+    }
+
+    /// The chain of error handlers in effect for this continuation.
+    fn handlers(&self) -> Option<Arc<HandlerChain>> {
+        match &self.handler {
+            Some(handler) => Some(Arc::new(HandlerChain {
+                handler: handler.clone(),
+                outer: self.outer.clone(),
+            })),
+            None => self.outer.clone(),
+        }
+    }
+
+    /// Give this continuation a human-readable label, which shows up in debugging output.
+    pub fn with_label(mut self, label: &'static str) -> Continuation {
+        self.label = label;
+        self
+    }
+
+    /// Give this continuation a priority. Higher priorities run first under the priority
+    /// scheduling policy.
+    pub fn with_priority(mut self, priority: usize) -> Continuation {
+        self.priority = priority;
+        self
+    }
+
+    /// Give this continuation a deadline. Earlier deadlines run first under the earliest deadline
+    /// first scheduling policy.
+    pub fn with_deadline(mut self, deadline: SysTime) -> Continuation {
+        self.deadline = Some(deadline);
+        self
+    }
+
+    /// Give this continuation a number of lottery tickets. Continuations with more tickets tend to
+    /// run sooner under the lottery scheduling policy.
+    pub fn with_tickets(mut self, tickets: usize) -> Continuation {
+        self.tickets = tickets;
+        self
+    }
+
+    /// Run this continuation on a stack of at least `npages` pages, rather than the default
+    /// `sched::STACK_PAGES`.
+    pub fn with_stack_pages(mut self, npages: usize) -> Continuation {
+        self.stack_pages = npages;
+        self
+    }
+
+    /// Give this continuation the id of an earlier continuation that it carries on from, so that
+    /// joining the earlier continuation waits for this one.
+    pub fn with_id(mut self, id: ContId) -> Continuation {
+        self.id = id;
+        self
+    }
+
+    /// The unique id of this continuation.
+    pub fn id(&self) -> ContId {
+        self.id
+    }
+
+    /// The id of the continuation that produced this one, if any.
+    pub fn parent(&self) -> Option<ContId> {
+        self.parent
+    }
+
+    /// The human-readable label of this continuation.
+    pub fn label(&self) -> &'static str {
+        self.label
+    }
+
+    /// When this continuation was created.
+    pub fn created(&self) -> SysTime {
+        self.created
+    }
+
+    /// The priority of this continuation.
+    pub fn priority(&self) -> usize {
+        self.priority
+    }
+
+    /// The deadline of this continuation, if any.
+    pub fn deadline(&self) -> Option<SysTime> {
+        self.deadline
+    }
+
+    /// The number of lottery tickets this continuation holds.
+    pub fn tickets(&self) -> usize {
+        self.tickets
+    }
+
+    /// Run at least as soon as `parent` would have under any policy. Children that carry on the
+    /// parent's work right away keep its boost (e.g. for keyboard input) this way.
+    fn inherit_boost(&mut self, parent: &Continuation) {
+        self.priority = self.priority.max(parent.priority);
+        self.tickets = self.tickets.max(parent.tickets);
+        self.deadline = match (self.deadline, parent.deadline) {
+            (Some(ours), Some(theirs)) => Some(ours.min(theirs)),
+            (ours, theirs) => ours.or(theirs),
+        };
+    }
+
+    /// The size of the stack this continuation needs, in pages.
+    pub fn stack_pages(&self) -> usize {
+        self.stack_pages
+    }
+
+    /// Record that this continuation is starting to wait for `kind` in the scheduler.
+    pub fn set_waiting(&mut self, kind: &EventKind) {
+        self.waiting = Some((kind.name(), SysTime::now()));
+    }
+
     /// Execute this continuation. Enqueue any resulting continuation in the scheduler. Then, cede
     /// control to the scheduler.
     ///
@@ -69,16 +453,95 @@ impl Continuation {
     ///
     /// Usually, this will be called just from the scheduler.
     pub fn run(mut self, event: Event) -> ! {
-        // run this continuation, and enqueue the result
-        match (self.routine.take().unwrap())(event) {
-            // schedule the continuation
-            ContResult::Success(cont) => sched::enqueue(cont),
+        sched::replay::record(self.id, self.label, &event);
+        sched::stats::started(self.id, self.label, self.waiting.take());
+
+        // error handlers get the error instead of whatever they were waiting for
+        let event = match self.error.take() {
+            Some(error) => Event::Error(error),
+            None => event,
+        };
 
-            // schedule the error continuation with the error event
-            ContResult::Error(cont) => sched::enqueue(vec![(EventKind::Now, cont)]),
+        // we hold our capabilities and any that were sent to us while we run
+        let mut caps = self.caps.take().unwrap_or_default();
+        let event = receive_handles(event, &mut caps);
+        cap::enter(caps);
+        *CURRENT_HANDLERS.get().lock() = self.handlers();
 
-            // if they are done, the continuation is the idle continuation
-            ContResult::Done => sched::idle(),
+        // run this continuation
+        let result = (self.routine.take().unwrap())(event);
+
+        // the children get (some of) our capabilities, including any we created
+        CURRENT_HANDLERS.get().lock().take();
+        let caps = cap::leave();
+        let result = match result {
+            ContResult::Success(mut cont) => {
+                for (_, cont) in cont.iter_mut() {
+                    cont.inherit_caps(&caps);
+                }
+
+                if cont.iter().all(|(kind, cont)| cont.can_wait_for(kind)) {
+                    ContResult::Success(cont)
+                } else {
+                    ContResult::Error(KernelError::MissingCapability, None)
+                }
+            }
+            result => result,
+        };
+
+        // enqueue the result
+        match result {
+            // schedule the continuation; they inherit our error handlers
+            ContResult::Success(mut cont) => {
+                let handlers = self.handlers();
+                for (kind, cont) in cont.iter_mut() {
+                    cont.parent = Some(self.id);
+                    if cont.outer.is_none() {
+                        cont.outer = handlers.clone();
+                    }
+                    if let EventKind::Now = kind {
+                        cont.inherit_boost(&self);
+                    }
+                }
+                sched::enqueue(cont)
+            }
+
+            // schedule the error continuation with the error event; anyone joining us must not wait
+            // forever
+            ContResult::Error(error, handler) => {
+                sched::finished(self.id, None);
+
+                let handlers = self.handlers();
+                let mut handler = match (handler, handlers) {
+                    // an explicit handler; errors in the handler go to our handlers
+                    (Some(mut handler), handlers) => {
+                        if handler.outer.is_none() {
+                            handler.outer = handlers;
+                        }
+                        handler
+                    }
+
+                    // the nearest handler; errors in the handler go to the next one out
+                    (None, Some(chain)) => {
+                        let mut handler = (chain.handler)();
+                        handler.outer = chain.outer.clone();
+                        handler
+                    }
+
+                    (None, None) => panic!("Unhandled kernel error: {:?}", error),
+                };
+
+                handler.inherit_caps(&caps);
+                handler.parent = Some(self.id);
+                handler.error = Some(error);
+                sched::enqueue(vec![(EventKind::Now, handler)])
+            }
+
+            // if they are done, the scheduler runs something else or this core's idle continuation
+            ContResult::Done => sched::finished(self.id, None),
+
+            // same, but anyone joining us gets the result
+            ContResult::Return(value) => sched::finished(self.id, Some(value)),
         }
 
         // TODO: do any necessary cleanup here
@@ -91,3 +554,27 @@ impl Continuation {
         sched::sched()
     }
 }
+
+/// The error handlers in effect for the continuation running on this core, if any. Code running
+/// in a continuation uses this to give its handlers to a continuation that carries on from it but
+/// is not one of its children (see `Continuation::with_handlers`).
+pub fn current_handlers() -> Option<Arc<HandlerChain>> {
+    CURRENT_HANDLERS.get().lock().clone()
+}
+
+/// Any handles received in a message become held by the receiver. The receiver gets a handle
+/// derived from the one that was sent, so the sender can revoke it (see `cap::receive`).
+fn receive_handles(event: Event, caps: &mut CapabilityGroup) -> Event {
+    match event {
+        Event::Message(Message::Handle(handle)) => {
+            let handle = cap::receive(handle);
+            caps.insert(handle.clone());
+            Event::Message(Message::Handle(handle))
+        }
+        Event::Any { branch, event } => Event::Any {
+            branch,
+            event: Box::new(receive_handles(*event, caps)),
+        },
+        event => event,
+    }
+}
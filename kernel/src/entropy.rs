@@ -0,0 +1,102 @@
+//! Random numbers that user code can't predict, e.g. for capability keys.
+//!
+//! We use the CPU's random number generator if it has one: RDSEED, which is meant for seeding
+//! other generators, or else RDRAND. Without either, we fall back to mixing up the jitter of the
+//! TSC, which is much weaker but better than nothing.
+//!
+//! These are slow, so they should only be used to seed a faster generator.
+
+use core::{
+    arch::x86_64::{__cpuid, __cpuid_count, _rdrand64_step, _rdseed64_step, _rdtsc},
+    sync::atomic::spin_loop_hint,
+};
+
+/// The number of times to retry RDSEED and RDRAND, which fail when the CPU runs out of entropy.
+const RETRIES: usize = 16;
+
+/// The number of TSC measurements mixed into each random number when falling back to TSC jitter.
+const JITTER_ROUNDS: usize = 64;
+
+/// Returns a random number.
+pub fn random_u64() -> u64 {
+    unsafe {
+        if has_rdseed() {
+            if let Some(x) = rdseed() {
+                return x;
+            }
+        }
+
+        if has_rdrand() {
+            if let Some(x) = rdrand() {
+                return x;
+            }
+        }
+    }
+
+    tsc_jitter()
+}
+
+/// Fill `buf` with random bytes.
+pub fn fill(buf: &mut [u8]) {
+    for chunk in buf.chunks_mut(8) {
+        let x = random_u64().to_le_bytes();
+        chunk.copy_from_slice(&x[..chunk.len()]);
+    }
+}
+
+/// Does the CPU support RDRAND?
+fn has_rdrand() -> bool {
+    unsafe { __cpuid(1).ecx & (1 << 30) != 0 }
+}
+
+/// Does the CPU support RDSEED?
+fn has_rdseed() -> bool {
+    unsafe { __cpuid(0).eax >= 7 && __cpuid_count(7, 0).ebx & (1 << 18) != 0 }
+}
+
+/// Get a random number with RDSEED. The CPU must support it.
+#[target_feature(enable = "rdseed")]
+unsafe fn rdseed() -> Option<u64> {
+    let mut x = 0;
+    (0..RETRIES)
+        .find(|_| _rdseed64_step(&mut x) == 1)
+        .map(|_| x)
+}
+
+/// Get a random number with RDRAND. The CPU must support it.
+#[target_feature(enable = "rdrand")]
+unsafe fn rdrand() -> Option<u64> {
+    let mut x = 0;
+    (0..RETRIES)
+        .find(|_| _rdrand64_step(&mut x) == 1)
+        .map(|_| x)
+}
+
+/// Get a random number from the jitter of the TSC: how long a bit of busy work takes varies with
+/// caches, interrupts, SMIs, and so on. Each measurement is mixed into the result, so its low bits
+/// (which are the least predictable) affect all bits of the result.
+fn tsc_jitter() -> u64 {
+    let mut state = unsafe { _rdtsc() };
+
+    for _ in 0..JITTER_ROUNDS {
+        let start = unsafe { _rdtsc() };
+
+        // Busy work whose length depends on what we have so far.
+        for _ in 0..(state & 0xFF) {
+            spin_loop_hint();
+        }
+
+        let elapsed = unsafe { _rdtsc() }.wrapping_sub(start);
+        state = mix(state ^ elapsed);
+    }
+
+    state
+}
+
+/// The splitmix64 finalizer, which scrambles all bits of `x` into all bits of the result.
+fn mix(mut x: u64) -> u64 {
+    x = x.wrapping_add(0x9E37_79B9_7F4A_7C15);
+    x = (x ^ (x >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+    x = (x ^ (x >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+    x ^ (x >> 31)
+}
@@ -0,0 +1,47 @@
+//! Errors that kernel operations can fail with.
+//!
+//! A continuation that fails returns `ContResult::Error` with one of these, and it is delivered to
+//! the appropriate error handler as `Event::Error`.
+
+/// A kernel error.
+#[derive(Clone, Debug)]
+pub enum KernelError {
+    /// The virtual address space is exhausted.
+    OutOfVirtualMemory,
+
+    /// Physical memory is exhausted.
+    OutOfPhysicalMemory,
+
+    /// An allocation would go over the quota it is charged to.
+    QuotaExceeded,
+
+    /// A page is already mapped (or is part of a huge page).
+    AlreadyMapped,
+
+    /// A memory region is too small for what is being mapped into it.
+    RegionTooSmall,
+
+    /// A physical address range that should be device memory is RAM or is otherwise in use.
+    NotDeviceMemory,
+
+    /// An alignment is not a power of two.
+    BadAlignment,
+
+    /// A binary could not be loaded. The reason is given.
+    BadElf(&'static str),
+
+    /// The capability needed for an operation does not exist or is not held.
+    MissingCapability,
+
+    /// The capability needed for an operation is held, but it does not grant the rights needed.
+    InsufficientRights,
+
+    /// The capability is not of the type needed for an operation (e.g. it is not a group).
+    WrongCapabilityType,
+
+    /// A capability group would contain another group, which is not allowed.
+    NestedCapabilityGroup,
+
+    /// The IRQ line does not exist, is used by the kernel itself, or has already been claimed.
+    IrqUnavailable,
+}
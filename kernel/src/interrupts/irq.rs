@@ -0,0 +1,109 @@
+//! IRQ lines that are handled by the holder of a capability rather than by the kernel, so that
+//! device drivers can run in user space.
+//!
+//! The holder of an `Irq` capability waits for interrupts on its line with `EventKind::Irq`. When
+//! an interrupt arrives, the line is masked at the PIC and marked pending, and the next
+//! continuation waiting on the line gets `Event::Irq`. The line stays masked until the holder calls
+//! `Irq::ack`, once it has dealt with the device (e.g. read the key that was pressed), so that a
+//! device can't flood the kernel with interrupts that nobody handles.
+//!
+//! While a line is claimed, the kernel's own handler for it (e.g. `io::kbd`) doesn't run. When the
+//! capability is dropped, the line goes back to the kernel.
+
+use core::sync::atomic::{AtomicU16, Ordering};
+
+use crate::{
+    cap::{Capability, UnregisteredResourceHandle},
+    error::KernelError,
+};
+
+use super::pic;
+
+/// The lines that can't be claimed: the timer and the cascade from the second PIC.
+const RESERVED: u16 = (1 << 0) | (1 << 2);
+
+/// The lines that are claimed by a capability, one bit per line.
+static CLAIMED: AtomicU16 = AtomicU16::new(0);
+
+/// The claimed lines that have had an interrupt that has not been delivered yet.
+static PENDING: AtomicU16 = AtomicU16::new(0);
+
+/// Capability on an IRQ line.
+#[derive(Debug)]
+pub struct Irq {
+    irq: u8,
+}
+
+impl Irq {
+    /// Claim the IRQ line `irq`, so that its interrupts go to the holder of the returned capability.
+    ///
+    /// Returns `IrqUnavailable` if the line does not exist, the kernel needs it, or it has already
+    /// been claimed.
+    pub fn claim(irq: u8) -> Result<UnregisteredResourceHandle, KernelError> {
+        let bit = 1u16
+            .checked_shl(irq as u32)
+            .filter(|bit| bit & RESERVED == 0)
+            .ok_or(KernelError::IrqUnavailable)?;
+
+        if CLAIMED.fetch_or(bit, Ordering::AcqRel) & bit != 0 {
+            return Err(KernelError::IrqUnavailable);
+        }
+
+        Ok(UnregisteredResourceHandle::new(Capability::Irq(Irq {
+            irq,
+        })))
+    }
+
+    /// The IRQ line.
+    pub fn number(&self) -> u8 {
+        self.irq
+    }
+
+    /// The last interrupt has been dealt with, so the line can deliver the next one.
+    pub fn ack(&self) {
+        pic::unmask(self.irq);
+    }
+}
+
+impl Drop for Irq {
+    fn drop(&mut self) {
+        let bit = 1 << self.irq;
+
+        PENDING.fetch_and(!bit, Ordering::AcqRel);
+        CLAIMED.fetch_and(!bit, Ordering::AcqRel);
+
+        // The line may have been left masked, waiting for an `ack`.
+        pic::unmask(self.irq);
+    }
+}
+
+/// Called by the interrupt handler for `irq`. If the line is claimed, mask it and mark it pending
+/// for `sched::wait` to deliver, and return true. Otherwise, the kernel handles the interrupt.
+pub fn handle(irq: u8) -> bool {
+    let bit = 1 << irq;
+
+    if CLAIMED.load(Ordering::Acquire) & bit == 0 {
+        return false;
+    }
+
+    pic::mask(irq);
+    PENDING.fetch_or(bit, Ordering::AcqRel);
+
+    true
+}
+
+/// Are there any interrupts to deliver?
+pub fn has_pending() -> bool {
+    PENDING.load(Ordering::Acquire) != 0
+}
+
+/// Is there an interrupt to deliver on `irq`?
+pub fn is_pending(irq: u8) -> bool {
+    PENDING.load(Ordering::Acquire) & (1 << irq) != 0
+}
+
+/// Take the interrupt pending on `irq` for delivery. Returns false if there isn't one.
+pub fn take(irq: u8) -> bool {
+    let bit = 1 << irq;
+    PENDING.fetch_and(!bit, Ordering::AcqRel) & bit != 0
+}
@@ -32,7 +32,9 @@
 //! Any bits that are not specified above are "don't care" and should just be set to 0. See the SDM
 //! chapters mentioned above for the meanings of these bits.
 
-use alloc::boxed::Box;
+use alloc::{boxed::Box, vec::Vec};
+
+use core::mem;
 
 use spin::Mutex;
 
@@ -41,39 +43,73 @@ use x86_64::{
     structures::{
         gdt::{Descriptor, DescriptorFlags, GlobalDescriptorTable, SegmentSelector},
         idt::{InterruptDescriptorTable, InterruptStackFrame},
+        paging::PageTableFlags,
         tss::TaskStateSegment,
     },
     PrivilegeLevel, VirtAddr,
 };
 
-pub use self::pit::HZ as PIT_HZ;
+use crate::cap::{self, KernelAuthority};
+use crate::memory::{self, VirtualMemoryRegion};
+use crate::smp::PerCpu;
+
+pub use self::pic::timer_irq;
+pub use self::pit::{arm as arm_timer, calibrate_tsc, MAX_ONE_SHOT_US};
 
+pub mod irq;
 mod pic;
 mod pit;
 
 /// Number of bytes of the IST stack frame.
 const IST_FRAME_SIZE: usize = 4096;
 
+/// Number of pages of the IST stack frames for faults. These are bigger than the others, and they
+/// are surrounded by guard pages, since the handlers report (e.g.) stack overflows and panic on
+/// them.
+const FAULT_IST_PAGES: usize = 4;
+
+/// Number of bytes of the I/O permission bitmap: one bit per port, plus a byte of ones at the end,
+/// since the CPU always reads two bytes of the bitmap at a time.
+const IO_BITMAP_SIZE: usize = 0x1_0000 / 8 + 1;
+
 /// The index in the TSS of the first Interrupt stack frame, used for fault handlers in emergency
 /// (e.g. double faults).
 pub const EMERGENCY_IST_FRAME_INDEX: u16 = 0;
 
-/// The index in the TSS of the Interrupt stack frame where the interrupts, page faults, etc are
-/// handled. Note that system calls are not handled on this stack frame. They use the main kernel
-/// stacks in the scheduler.
+/// The index in the TSS of the Interrupt stack frame where the interrupts, IPIs, etc are handled.
+/// Note that system calls are not handled on this stack frame. They use the main kernel stacks in
+/// the scheduler.
 pub const IRQ_IST_FRAME_INDEX: u16 = EMERGENCY_IST_FRAME_INDEX + 1;
 
+/// The index in the TSS of the Interrupt stack frame where page faults are handled. A page fault
+/// can happen in an interrupt handler (or on a continuation stack that just overflowed), so it
+/// needs a stack of its own.
+pub const PAGE_FAULT_IST_FRAME_INDEX: u16 = IRQ_IST_FRAME_INDEX + 1;
+
 // See notes at top of file regarding descriptor tables and segments.
 
-/// Global Descriptor Table.
-static GDT: Mutex<Option<GlobalDescriptorTable>> = Mutex::new(None);
+/// Global Descriptor Table. Each core has its own, so that it can have its own TSS.
+static GDT: PerCpu<Option<GlobalDescriptorTable>> = PerCpu::new();
+
+/// The Task State Segment. Each core has its own, so that it can have its own interrupt stacks and
+/// I/O permission bitmap.
+pub static TSS: PerCpu<Option<Box<Tss>>> = PerCpu::new();
 
-/// The Task State Segment.
-pub static TSS: Mutex<Option<TaskStateSegment>> = Mutex::new(None);
+/// The I/O port ranges (first and last port) that user code may use on each core, i.e. that are
+/// cleared in its I/O permission bitmap.
+static OPEN_IO_PORTS: PerCpu<Vec<(u16, u16)>> = PerCpu::new();
 
-/// Interrupt Descriptor Table.
+/// Interrupt Descriptor Table. This is shared by all cores.
 pub static IDT: Mutex<Option<InterruptDescriptorTable>> = Mutex::new(None);
 
+/// A TSS followed by its I/O permission bitmap. User code may only use the I/O ports whose bits are
+/// clear in the bitmap (see `set_io_ports`).
+#[repr(C)]
+pub struct Tss {
+    tss: TaskStateSegment,
+    io_bitmap: [u8; IO_BITMAP_SIZE],
+}
+
 #[derive(Debug)]
 pub struct Selectors {
     pub kernel_cs: SegmentSelector,
@@ -91,26 +127,70 @@ pub static SELECTORS: Mutex<Selectors> = Mutex::new(Selectors {
     tss: SegmentSelector::new(0, PrivilegeLevel::Ring0),
 });
 
-/// Initialize interrupts (and exceptions).
+/// Initialize interrupts (and exceptions). This is called once, by the bootstrap core.
 pub fn init() {
+    let mut idt = InterruptDescriptorTable::new();
+
+    // Initialize the IDT
+
+    // Reset the IDT (this sets a few critical bits, too)
+    //
+    // We need to be careful not to overflow the stack, though...
+    idt.reset();
+
+    unsafe {
+        pic::init_irqs(&mut idt);
+
+        crate::memory::init_pf_handler(&mut idt);
+
+        crate::smp::init_ipis(&mut idt);
+
+        // Handle errors in weird states
+        idt.general_protection_fault
+            .set_handler_fn(handle_gpf)
+            .set_stack_index(EMERGENCY_IST_FRAME_INDEX);
+
+        idt.double_fault
+            .set_handler_fn(handle_double_fault)
+            .set_stack_index(EMERGENCY_IST_FRAME_INDEX);
+
+        idt.non_maskable_interrupt
+            .set_handler_fn(handle_nmi)
+            .set_stack_index(EMERGENCY_IST_FRAME_INDEX);
+
+        idt.invalid_opcode
+            .set_handler_fn(handle_invalid_opcode)
+            .set_stack_index(EMERGENCY_IST_FRAME_INDEX);
+    }
+
+    *IDT.lock() = Some(idt);
+
+    // Set up this core's tables and load the IDT.
+    init_cpu();
+
+    // Initialize the Programmable Interrupt Controler
+    pic::init();
+
+    // Initialize the Programmable Interrupt Timer
+    pit::init();
+}
+
+/// Set up the calling core's GDT, TSS, and interrupt stacks, and load the IDT. This is called by
+/// every core, after `init` has created the IDT.
+pub fn init_cpu() {
     let mut tss = TaskStateSegment::new();
     let mut gdt = GlobalDescriptorTable::new();
-    let mut idt = InterruptDescriptorTable::new();
 
     // Create TSS (but don't load yet).
     tss.interrupt_stack_table[EMERGENCY_IST_FRAME_INDEX as usize] = {
-        // We create a struct to force the alignment to 16.
-        #[repr(align(16))]
-        struct Stack {
-            _data: [u8; IST_FRAME_SIZE],
-        }
+        let stack_end = guarded_stack(FAULT_IST_PAGES);
+        printk!("double fault stack @ {:?}\n", stack_end);
+        stack_end
+    };
 
-        let stack = box Stack {
-            _data: [0; IST_FRAME_SIZE],
-        };
-        let stack_start = VirtAddr::from_ptr(Box::into_raw(stack));
-        let stack_end = stack_start + IST_FRAME_SIZE;
-        printk!("double fault stack @ {:?}, {:?}\n", stack_start, stack_end);
+    tss.interrupt_stack_table[PAGE_FAULT_IST_FRAME_INDEX as usize] = {
+        let stack_end = guarded_stack(FAULT_IST_PAGES);
+        printk!("page fault stack @ {:?}\n", stack_end);
         stack_end
     };
 
@@ -130,14 +210,20 @@ pub fn init() {
         stack_end
     };
 
-    *TSS.lock() = Some(tss);
+    // All ports are closed to user code until `set_io_ports` opens some.
+    tss.iomap_base = mem::size_of::<TaskStateSegment>() as u16;
+
+    *TSS.get().lock() = Some(box Tss {
+        tss,
+        io_bitmap: [0xFF; IO_BITMAP_SIZE],
+    });
 
     let tss_ref = unsafe {
         // We know that the TSS will last forever...
-        &*(TSS.lock().as_ref().unwrap() as *const TaskStateSegment)
+        &*(&**TSS.get().lock().as_ref().unwrap() as *const Tss)
     };
 
-    // Initalize GDT
+    // Initalize GDT. The layout is the same on every core, so the selectors are too.
     let mut selectors = SELECTORS.lock();
 
     // NOTE: In the descriptors below, the names of the flags are aweful. I have added some
@@ -190,14 +276,20 @@ pub fn init() {
             .bits(),
     ));
 
-    selectors.tss = gdt.add_entry(Descriptor::tss_segment(tss_ref));
+    // `tss_segment` only covers the TSS itself, so extend the limit over the I/O permission bitmap.
+    selectors.tss = gdt.add_entry(match Descriptor::tss_segment(&tss_ref.tss) {
+        Descriptor::SystemSegment(low, high) => {
+            Descriptor::SystemSegment((low & !0xFFFF) | (mem::size_of::<Tss>() - 1) as u64, high)
+        }
+        _ => unreachable!(),
+    });
 
-    *GDT.lock() = Some(gdt);
+    *GDT.get().lock() = Some(gdt);
 
     // Load the GDT and TSS
     let gdt_ref = unsafe {
         // We know that the TSS will last forever...
-        &*(GDT.lock().as_ref().unwrap() as *const GlobalDescriptorTable)
+        &*(GDT.get().lock().as_ref().unwrap() as *const GlobalDescriptorTable)
     };
     gdt_ref.load();
     unsafe {
@@ -205,49 +297,61 @@ pub fn init() {
         load_tss(selectors.tss);
     }
 
-    // Initialize the IDT
-
-    // Reset the IDT (this sets a few critical bits, too)
-    //
-    // We need to be careful not to overflow the stack, though...
-    idt.reset();
-
-    unsafe {
-        pic::init_irqs(&mut idt);
-
-        crate::memory::init_pf_handler(&mut idt);
-
-        // Handle errors in weird states
-        idt.general_protection_fault
-            .set_handler_fn(handle_gpf)
-            .set_stack_index(EMERGENCY_IST_FRAME_INDEX);
+    let idt_ref = unsafe {
+        // We know that the IDT will last forever...
+        &*(IDT.lock().as_ref().unwrap() as *const InterruptDescriptorTable)
+    };
+    idt_ref.load();
+}
 
-        idt.double_fault
-            .set_handler_fn(handle_double_fault)
-            .set_stack_index(EMERGENCY_IST_FRAME_INDEX);
+/// Allocate a stack of `npages` pages with a guard page on either side, and return its end (the
+/// first stack pointer). The stack is mapped right away, since we can't take a page fault on it.
+fn guarded_stack(npages: usize) -> VirtAddr {
+    // The stack belongs to the kernel. No continuation is running yet.
+    let kernel = unsafe { KernelAuthority::new() };
+    cap::as_kernel(&kernel, || {
+        let region = VirtualMemoryRegion::alloc_with_guard(npages)?.register();
+        memory::map_region(
+            &region,
+            PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE,
+        )?;
+        memory::populate_region(&region)?;
+
+        region.with(|cap| {
+            let region = cap_unwrap!(VirtualMemoryRegion(cap));
+            VirtAddr::from_ptr(region.start()) + region.len()
+        })
+    })
+    .expect("unable to allocate an interrupt stack")
+}
 
-        idt.non_maskable_interrupt
-            .set_handler_fn(handle_nmi)
-            .set_stack_index(EMERGENCY_IST_FRAME_INDEX);
+/// Let user code on this core use exactly the I/O ports in `ranges` (first and last port of each).
+/// This is called whenever we switch to a user task, with the ports it holds capabilities on (see
+/// `io::ports`).
+pub fn set_io_ports(ranges: Vec<(u16, u16)>) {
+    let mut open = OPEN_IO_PORTS.get().lock();
 
-        idt.invalid_opcode
-            .set_handler_fn(handle_invalid_opcode)
-            .set_stack_index(EMERGENCY_IST_FRAME_INDEX);
+    // Usually, this is the same task or one with the same ports.
+    if *open == ranges {
+        return;
     }
 
-    *IDT.lock() = Some(idt);
+    let mut tss = TSS.get().lock();
+    let bitmap = &mut tss.as_mut().unwrap().io_bitmap;
 
-    let idt_ref = unsafe {
-        // We know that the TSS will last forever...
-        &*(IDT.lock().as_ref().unwrap() as *const InterruptDescriptorTable)
-    };
-    idt_ref.load();
+    for &(first, last) in open.iter() {
+        for port in first..=last {
+            bitmap[port as usize / 8] |= 1 << (port % 8);
+        }
+    }
 
-    // Initialize the Programmable Interrupt Controler
-    pic::init();
+    for &(first, last) in ranges.iter() {
+        for port in first..=last {
+            bitmap[port as usize / 8] &= !(1 << (port % 8));
+        }
+    }
 
-    // Initialize the Programmable Interrupt Timer
-    pit::init();
+    *open = ranges;
 }
 
 /// Handle invalid opcode
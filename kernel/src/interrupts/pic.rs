@@ -1,5 +1,7 @@
 //! A module for programmable interrupt controller
 
+use spin::Mutex;
+
 use x86_64::{
     instructions::{interrupts, port::Port},
     structures::idt::{InterruptDescriptorTable, InterruptStackFrame},
@@ -7,7 +9,7 @@ use x86_64::{
 
 use crate::time;
 
-use super::IRQ_IST_FRAME_INDEX;
+use super::{irq, IRQ_IST_FRAME_INDEX};
 
 //use super::idt64;
 
@@ -23,15 +25,19 @@ const C2: Port<u8> = Port::new(0xA0);
 /// Data port for PIC2
 const D2: Port<u8> = Port::new(0xA1);
 
+/// Held while changing which lines are masked, since that is a read-modify-write of the PICs.
+static MASK_LOCK: Mutex<()> = Mutex::new(());
+
 /// The first entries of the IDT are reserved for traps and exceptions. So the first
 /// _interrupt_ is at vector 0x30.
 const FIRST_IDT: u8 = 0x30;
 
 /// Initialize some interrupt handlers
 pub unsafe fn init_irqs(idt: &mut InterruptDescriptorTable) {
-    // Set up basic interrupts
+    // Set up basic interrupts. The timer interrupt saves all registers so that it can preempt
+    // user tasks.
     idt[FIRST_IDT as usize]
-        .set_handler_fn(irq_0)
+        .set_handler_fn(crate::sched::user::preempt::timer_handler())
         .set_stack_index(IRQ_IST_FRAME_INDEX);
     idt[FIRST_IDT as usize + 0x1]
         .set_handler_fn(irq_1)
@@ -111,6 +117,35 @@ pub fn init() {
     };
 }
 
+/// Stop the PIC from delivering interrupts on `irq` until `unmask` is called.
+pub fn mask(irq: u8) {
+    set_masked(irq, true);
+}
+
+/// Let the PIC deliver interrupts on `irq` again.
+pub fn unmask(irq: u8) {
+    set_masked(irq, false);
+}
+
+/// Mask or unmask `irq`.
+fn set_masked(irq: u8, masked: bool) {
+    // Interrupt handlers mask lines too, so keep them out while we hold the lock.
+    interrupts::without_interrupts(|| {
+        let _guard = MASK_LOCK.lock();
+
+        let (mut data, bit) = if irq < 8 { (D1, irq) } else { (D2, irq - 8) };
+
+        unsafe {
+            let mask = data.read();
+            if masked {
+                data.write(mask | (1 << bit));
+            } else {
+                data.write(mask & !(1 << bit));
+            }
+        }
+    });
+}
+
 /// End of interrupt: send the next irq, but interrupts still disabled
 fn pic_eoi(irq: u8) {
     unsafe {
@@ -129,18 +164,36 @@ fn pic_eoi(irq: u8) {
 ///
 /// Note that this should _not_ be confused with _exceptions_. For more info on x86 exceptions, see
 /// https://wiki.osdev.org/Exceptions
-fn pic_irq(irq: usize, _: &mut InterruptStackFrame) {
+fn pic_irq(irq: usize) {
+    // A claimed line is handled by the holder of its capability.
+    if irq::handle(irq as u8) {
+        // Someone may be waiting for it.
+        crate::smp::wake_idle();
+
+        pic_eoi(irq as u8);
+        return;
+    }
+
     // execute handler
     match irq {
         // PIT interrupts
         0 => {
-            // tick the clock
-            time::tick();
+            // the timer is one-shot, so it has to be armed again by whoever needs it
+            time::fired();
+
+            // Only this core gets timer interrupts, so let the others know if they have something
+            // to do.
+            if crate::sched::timer_expired() {
+                crate::smp::wake_idle();
+            }
         }
 
         // Keyboard interrupts
         1 => {
             unsafe { crate::io::kbd::handler() };
+
+            // Someone may be waiting for input.
+            crate::smp::wake_idle();
         }
 
         // Processor and FPU interrupts
@@ -164,71 +217,73 @@ fn pic_irq(irq: usize, _: &mut InterruptStackFrame) {
 // The interrupt handlers
 //
 // These are called by the hardware. They simply call `pic_irq`, which does the
-// hard work for them.
+// hard work for them. The timer interrupt is an exception; see `timer_irq`.
 ////////////////////////////////////////////////////////////////////////////////
 
-extern "x86-interrupt" fn irq_0(esf: &mut InterruptStackFrame) {
-    pic_irq(0, esf);
+/// Handle the timer interrupt. This is called by the preemption code in `sched::user`, which
+/// saves the registers that the other handlers leave to the `x86-interrupt` calling convention.
+pub fn timer_irq() {
+    pic_irq(0);
 }
 
-extern "x86-interrupt" fn irq_1(esf: &mut InterruptStackFrame) {
-    pic_irq(1, esf);
+extern "x86-interrupt" fn irq_1(_: &mut InterruptStackFrame) {
+    pic_irq(1);
 }
 
-extern "x86-interrupt" fn irq_2(esf: &mut InterruptStackFrame) {
-    pic_irq(2, esf);
+extern "x86-interrupt" fn irq_2(_: &mut InterruptStackFrame) {
+    pic_irq(2);
 }
 
-extern "x86-interrupt" fn irq_3(esf: &mut InterruptStackFrame) {
-    pic_irq(3, esf);
+extern "x86-interrupt" fn irq_3(_: &mut InterruptStackFrame) {
+    pic_irq(3);
 }
 
-extern "x86-interrupt" fn irq_4(esf: &mut InterruptStackFrame) {
-    pic_irq(4, esf);
+extern "x86-interrupt" fn irq_4(_: &mut InterruptStackFrame) {
+    pic_irq(4);
 }
 
-extern "x86-interrupt" fn irq_5(esf: &mut InterruptStackFrame) {
-    pic_irq(5, esf);
+extern "x86-interrupt" fn irq_5(_: &mut InterruptStackFrame) {
+    pic_irq(5);
 }
 
-extern "x86-interrupt" fn irq_6(esf: &mut InterruptStackFrame) {
-    pic_irq(6, esf);
+extern "x86-interrupt" fn irq_6(_: &mut InterruptStackFrame) {
+    pic_irq(6);
 }
 
-extern "x86-interrupt" fn irq_7(esf: &mut InterruptStackFrame) {
-    pic_irq(7, esf);
+extern "x86-interrupt" fn irq_7(_: &mut InterruptStackFrame) {
+    pic_irq(7);
 }
 
-extern "x86-interrupt" fn irq_8(esf: &mut InterruptStackFrame) {
-    pic_irq(8, esf);
+extern "x86-interrupt" fn irq_8(_: &mut InterruptStackFrame) {
+    pic_irq(8);
 }
 
-extern "x86-interrupt" fn irq_9(esf: &mut InterruptStackFrame) {
-    pic_irq(9, esf);
+extern "x86-interrupt" fn irq_9(_: &mut InterruptStackFrame) {
+    pic_irq(9);
 }
 
-extern "x86-interrupt" fn irq_a(esf: &mut InterruptStackFrame) {
-    pic_irq(0xa, esf);
+extern "x86-interrupt" fn irq_a(_: &mut InterruptStackFrame) {
+    pic_irq(0xa);
 }
 
-extern "x86-interrupt" fn irq_b(esf: &mut InterruptStackFrame) {
-    pic_irq(0xb, esf);
+extern "x86-interrupt" fn irq_b(_: &mut InterruptStackFrame) {
+    pic_irq(0xb);
 }
 
-extern "x86-interrupt" fn irq_c(esf: &mut InterruptStackFrame) {
-    pic_irq(0xc, esf);
+extern "x86-interrupt" fn irq_c(_: &mut InterruptStackFrame) {
+    pic_irq(0xc);
 }
 
-extern "x86-interrupt" fn irq_d(esf: &mut InterruptStackFrame) {
-    pic_irq(0xd, esf);
+extern "x86-interrupt" fn irq_d(_: &mut InterruptStackFrame) {
+    pic_irq(0xd);
 }
 
-extern "x86-interrupt" fn irq_e(esf: &mut InterruptStackFrame) {
-    pic_irq(0xe, esf);
+extern "x86-interrupt" fn irq_e(_: &mut InterruptStackFrame) {
+    pic_irq(0xe);
 }
 
-extern "x86-interrupt" fn irq_f(esf: &mut InterruptStackFrame) {
-    pic_irq(0xf, esf);
+extern "x86-interrupt" fn irq_f(_: &mut InterruptStackFrame) {
+    pic_irq(0xf);
 }
 
 /// Handle a breakpoint exception
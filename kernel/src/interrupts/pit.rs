@@ -1,4 +1,9 @@
 //! A module for the programmable interrupt timer
+//!
+//! At boot, the PIT runs as a rate generator so that we can use it to calibrate the TSC. After
+//! that, it is used as a one-shot timer, armed for the next time something needs to happen.
+
+use core::arch::x86_64::_rdtsc;
 
 use x86_64::{
     instructions::{interrupts, port::Port},
@@ -8,8 +13,14 @@ use x86_64::{
 /// Max frequency of the PIT
 const MAX_HZ: usize = 1_193_182;
 
-/// The frequency of the PIT
-pub const HZ: usize = 1000;
+/// The frequency of the PIT while it is a rate generator.
+const HZ: usize = 1000;
+
+/// The number of milliseconds over which the TSC is calibrated.
+const CALIBRATION_MS: usize = 50;
+
+/// The longest a one-shot can be armed for, in microseconds, given the 16-bit count.
+pub const MAX_ONE_SHOT_US: usize = 0xffff * 1_000_000 / MAX_HZ;
 
 /// The command port of the PIT
 const PIT_CMD: Port<u8> = Port::new(0x43);
@@ -17,7 +28,7 @@ const PIT_CMD: Port<u8> = Port::new(0x43);
 /// The data port of the PIT
 const PIT_DATA: Port<u8> = Port::new(0x40);
 
-/// Initialize the PIT to the given frequency
+/// Initialize the PIT as a rate generator at `HZ`.
 pub fn init() {
     let divide = MAX_HZ / HZ;
 
@@ -53,3 +64,77 @@ pub fn init() {
         rflags::write(saved_flags);
     }
 }
+
+/// Measure the frequency of the TSC against the PIT, which must still be a rate generator. This
+/// works even with interrupts disabled.
+pub fn calibrate_tsc() -> usize {
+    // Start at a period boundary so that we measure whole periods.
+    spin_ms(1);
+
+    let start = unsafe { _rdtsc() };
+    spin_ms(CALIBRATION_MS);
+    let end = unsafe { _rdtsc() };
+
+    (end - start) as usize * 1000 / CALIBRATION_MS
+}
+
+/// Arm the PIT to interrupt once after `us` microseconds (at most `MAX_ONE_SHOT_US`), rounded up to
+/// a whole PIT tick. This replaces whatever the PIT was doing before, including running as a rate
+/// generator.
+///
+/// The caller must make sure that nobody else programs the PIT concurrently.
+pub fn arm(us: usize) {
+    let count = ((us * MAX_HZ + 999_999) / 1_000_000).max(1).min(0xffff);
+
+    unsafe {
+        // save flags
+        let saved_flags = rflags::read();
+
+        // disable interrupts
+        interrupts::disable();
+
+        // command
+        // 00 (channel 0)
+        // 110 (lobyte/hibyte)
+        // 000 (interrupt on terminal count)
+        let cmd = 0b_0011_0000_u8;
+
+        // write commmand
+        PIT_CMD.write(cmd);
+
+        // Set the count, one byte at a time. Counting starts after the second byte.
+        PIT_DATA.write((count & 0xFF) as u8);
+        PIT_DATA.write(((count & 0xFF00) >> 8) as u8);
+
+        // restore flags
+        rflags::write(saved_flags);
+    }
+}
+
+/// Busy-wait for about `ms` milliseconds by watching the PIT count down. This only works while
+/// the PIT is a rate generator.
+fn spin_ms(ms: usize) {
+    let mut last = read_count();
+    let mut elapsed = 0;
+
+    // The count goes down to 1 and then starts over from the divide every 1/HZ seconds.
+    while elapsed < ms * HZ / 1000 {
+        let count = read_count();
+        if count > last {
+            elapsed += 1;
+        }
+        last = count;
+    }
+}
+
+/// Read the current count of channel 0.
+fn read_count() -> u16 {
+    unsafe {
+        // Latch the count of channel 0 so that we can read both bytes consistently.
+        PIT_CMD.write(0);
+
+        let lo = PIT_DATA.read() as u16;
+        let hi = PIT_DATA.read() as u16;
+        (hi << 8) | lo
+    }
+}
@@ -0,0 +1,76 @@
+//! QEMU's firmware configuration device, which we use to read boot-time configuration.
+//!
+//! The bootloader doesn't give us a kernel command line, so options are passed as fw_cfg files
+//! instead, e.g. `-fw_cfg name=opt/sched-policy,string=lottery`. The device is documented in
+//! `docs/specs/fw_cfg.txt` in the QEMU source. On anything other than QEMU, there are no files.
+
+use x86_64::instructions::port::Port;
+
+/// Selects the item to read.
+const FW_CFG_SELECTOR: Port<u16> = Port::new(0x510);
+
+/// Reads the selected item, one byte at a time.
+const FW_CFG_DATA: Port<u8> = Port::new(0x511);
+
+/// The item containing "QEMU" if the device is there.
+const FW_CFG_SIGNATURE: u16 = 0x0000;
+
+/// The item containing the list of files.
+const FW_CFG_FILE_DIR: u16 = 0x0019;
+
+/// The length of a file name in the list of files, including the NUL padding.
+const FILE_NAME_LEN: usize = 56;
+
+/// Read the fw_cfg file `name` into `buf`. Returns the number of bytes read, which is less than the
+/// length of the file if `buf` is too short, or None if there is no such file.
+pub fn read_file(name: &str, buf: &mut [u8]) -> Option<usize> {
+    select(FW_CFG_SIGNATURE);
+    let mut signature = [0; 4];
+    read(&mut signature);
+    if &signature != b"QEMU" {
+        return None;
+    }
+
+    // The list of files is a big-endian count followed by an entry for each file.
+    select(FW_CFG_FILE_DIR);
+    let mut count = [0; 4];
+    read(&mut count);
+
+    for _ in 0..u32::from_be_bytes(count) {
+        let mut size = [0; 4];
+        let mut key = [0; 2];
+        let mut reserved = [0; 2];
+        let mut file_name = [0; FILE_NAME_LEN];
+        read(&mut size);
+        read(&mut key);
+        read(&mut reserved);
+        read(&mut file_name);
+
+        let len = file_name
+            .iter()
+            .position(|&c| c == 0)
+            .unwrap_or(FILE_NAME_LEN);
+        if &file_name[..len] == name.as_bytes() {
+            let len = (u32::from_be_bytes(size) as usize).min(buf.len());
+            select(u16::from_be_bytes(key));
+            read(&mut buf[..len]);
+            return Some(len);
+        }
+    }
+
+    None
+}
+
+/// Select the item `key` and start reading it from the beginning.
+fn select(key: u16) {
+    unsafe {
+        FW_CFG_SELECTOR.write(key);
+    }
+}
+
+/// Read the next `buf.len()` bytes of the selected item.
+fn read(buf: &mut [u8]) {
+    for byte in buf.iter_mut() {
+        *byte = unsafe { FW_CFG_DATA.read() };
+    }
+}
@@ -4,6 +4,8 @@
 
 use alloc::collections::linked_list::LinkedList;
 
+use core::sync::atomic::{AtomicUsize, Ordering};
+
 use spin::Mutex;
 
 use x86_64::instructions::{interrupts::without_interrupts, port::Port};
@@ -20,6 +22,10 @@ const KBD_DATA: Port<u8> = Port::new(0x60);
 /// Buffered keyboard input.
 static KBD_BUFFER: Mutex<Option<LinkedList<u8>>> = Mutex::new(None);
 
+/// The number of buffered characters. This lets the scheduler check for input without taking the
+/// buffer lock.
+static PENDING: AtomicUsize = AtomicUsize::new(0);
+
 /// Is this character capital? Safe because we really don't care too much...
 static mut SHIFT: bool = false;
 
@@ -29,6 +35,7 @@ static mut SHIFT: bool = false;
 pub unsafe fn handler() {
     if let Some(key) = read() {
         KBD_BUFFER.lock().as_mut().unwrap().push_back(key);
+        PENDING.fetch_add(1, Ordering::Release);
     }
 }
 
@@ -106,5 +113,16 @@ pub fn init() {
 pub fn kbd_next() -> Option<u8> {
     // Without interrupts to avoid deadlocks because the keyboard handler grabs a lock. Yeah, I
     // know. So sue me.
-    without_interrupts(|| KBD_BUFFER.lock().as_mut().unwrap().pop_front())
+    without_interrupts(|| {
+        let c = KBD_BUFFER.lock().as_mut().unwrap().pop_front();
+        if c.is_some() {
+            PENDING.fetch_sub(1, Ordering::Release);
+        }
+        c
+    })
+}
+
+/// Is there any buffered input?
+pub fn has_input() -> bool {
+    PENDING.load(Ordering::Acquire) > 0
 }
@@ -1,6 +1,8 @@
 //! All things I/O related.
 
+pub mod fw_cfg;
 pub mod kbd;
+pub mod ports;
 
 pub fn init() {
     kbd::init();
@@ -0,0 +1,54 @@
+//! I/O port ranges as capabilities, so that device drivers can run in user space.
+//!
+//! A user task may use the ports of every `IoPortRange` it can access with both read and write
+//! rights, since the CPU can only allow or deny access to a port as a whole. The ports are opened
+//! in the core's I/O permission bitmap whenever we switch to the task (see `load`), and all others
+//! are closed.
+//!
+//! NOTE: A task that is running when one of its port ranges is revoked can keep using the ports
+//! until it next makes a system call or is preempted.
+
+use crate::cap::{self, Capability, Rights, UnregisteredResourceHandle};
+
+/// Capability on a range of I/O ports.
+#[derive(Debug)]
+pub struct IoPortRange {
+    /// The first port in the range.
+    first: u16,
+
+    /// The last port in the range.
+    last: u16,
+}
+
+impl IoPortRange {
+    /// Create a capability on the ports from `first` to `last`, inclusive. Nothing stops the kernel
+    /// from handing out the same ports more than once (or ports it uses itself), so be careful.
+    pub fn new(first: u16, last: u16) -> UnregisteredResourceHandle {
+        assert!(first <= last, "empty port range");
+
+        UnregisteredResourceHandle::new(Capability::IoPortRange(IoPortRange { first, last }))
+    }
+
+    /// The first port in the range.
+    pub fn first(&self) -> u16 {
+        self.first
+    }
+
+    /// The number of ports in the range.
+    pub fn len(&self) -> u32 {
+        (self.last - self.first) as u32 + 1
+    }
+}
+
+/// Let user code on this core use the ports that the current continuation can access, and no
+/// others. This is called whenever we switch to a user task.
+pub fn load() {
+    let ranges = cap::accessible(|cap, rights| match cap {
+        Capability::IoPortRange(range) if rights.contains(Rights::READ | Rights::WRITE) => {
+            Some((range.first, range.last))
+        }
+        _ => None,
+    });
+
+    crate::interrupts::set_io_ports(ranges);
+}
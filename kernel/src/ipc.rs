@@ -0,0 +1,82 @@
+//! Inter-continuation and inter-task communication.
+//!
+//! A `Channel` is a bounded queue of small messages. Channels are capabilities: to send or receive
+//! on a channel, you need its `ResourceHandle`. Continuations can wait for messages using
+//! `EventKind::ChannelRecv` and for space in the queue using `EventKind::ChannelSend`.
+//!
+//! Code that calls `Channel::send` or `Channel::recv` directly (rather than through events) should
+//! call `sched::channel_activity` afterwards, so that continuations waiting on the channel are
+//! woken up.
+
+use alloc::collections::VecDeque;
+
+use spin::Mutex;
+
+use crate::cap::{Capability, ResourceHandle, UnregisteredResourceHandle};
+
+/// A small message that can be passed over a `Channel`.
+#[derive(Clone, Debug, Eq, Ord, PartialEq, PartialOrd)]
+pub enum Message {
+    /// A plain machine word.
+    Word(u64),
+
+    /// A handle to some other resource. This is how capabilities are passed around. The receiver
+    /// gets a handle derived from the one that was sent (see `cap::derive`).
+    Handle(ResourceHandle),
+}
+
+/// Capability on a bounded message queue.
+#[derive(Debug)]
+pub struct Channel {
+    /// The maximum number of messages that can be buffered.
+    capacity: usize,
+
+    /// The buffered messages, oldest first.
+    ///
+    /// Capabilities are immutable once registered, so the queue needs its own lock.
+    queue: Mutex<VecDeque<Message>>,
+}
+
+impl Channel {
+    /// Create a new channel that can buffer up to `capacity` messages.
+    ///
+    /// Return a capability for the channel.
+    pub fn new(capacity: usize) -> UnregisteredResourceHandle {
+        UnregisteredResourceHandle::new(Capability::Channel(Channel {
+            capacity,
+            queue: Mutex::new(VecDeque::with_capacity(capacity)),
+        }))
+    }
+
+    /// Append `msg` to the queue. If the queue is full, `msg` is handed back.
+    pub fn send(&self, msg: Message) -> Result<(), Message> {
+        let mut queue = self.queue.lock();
+
+        if queue.len() >= self.capacity {
+            Err(msg)
+        } else {
+            queue.push_back(msg);
+            Ok(())
+        }
+    }
+
+    /// Remove and return the oldest message in the queue, if any.
+    pub fn recv(&self) -> Option<Message> {
+        self.queue.lock().pop_front()
+    }
+
+    /// Return the oldest message in the queue without removing it, if any.
+    pub fn peek(&self) -> Option<Message> {
+        self.queue.lock().front().cloned()
+    }
+
+    /// The maximum number of messages that can be buffered.
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    /// The number of messages in the queue.
+    pub fn len(&self) -> usize {
+        self.queue.lock().len()
+    }
+}
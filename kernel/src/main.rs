@@ -6,6 +6,7 @@
     abi_x86_interrupt,
     panic_info_message,
     drain_filter,
+    global_asm,
     naked_functions
 )]
 // Compile without libstd
@@ -22,18 +23,39 @@ mod bare_bones;
 #[macro_use]
 mod cap;
 mod continuation;
+mod entropy;
+mod error;
 mod interrupts;
 mod io;
+mod ipc;
 mod memory;
 mod sched;
+mod smp;
 mod time;
 
-use alloc::vec;
+use alloc::{vec, vec::Vec};
+
+use core::{iter, mem};
 
 use bootloader::BootInfo;
 
+use crate::cap::{CapabilityGroup, HandleTable, ResourceHandle, Rights};
 use crate::continuation::{ContResult, Continuation, Event, EventKind};
-use crate::time::SysTime;
+use crate::error::KernelError;
+use crate::interrupts::irq::Irq;
+use crate::io::ports::IoPortRange;
+use crate::ipc::{Channel, Message};
+use crate::sched::policy::PolicyKind;
+
+/// The scheduling policy of all cores, unless another one is chosen at boot.
+const DEFAULT_SCHED_POLICY: PolicyKind = PolicyKind::Fifo;
+
+/// The fw_cfg file that names the scheduling policy to use (see `PolicyKind::from_name`), e.g. with
+/// `-fw_cfg name=opt/sched-policy,string=lottery`.
+const SCHED_POLICY_FILE: &str = "opt/sched-policy";
+
+/// The number of keys the user-space keyboard driver can send before we read them.
+const KEYS_CAPACITY: usize = 16;
 
 /// The kernel heap
 #[global_allocator]
@@ -54,6 +76,9 @@ fn kernel_main(boot_info: &'static BootInfo) -> ! {
     // Make sure interrupts are off
     x86_64::instructions::interrupts::disable();
 
+    // Let `smp::cpu` tell which core this is without `cpuid` from now on.
+    smp::init_cpu_id();
+
     // Let everyone know we are here
     printk!("\nYo Yo Yo! Made it to `kernel_main`! Hooray!\n");
 
@@ -64,9 +89,15 @@ fn kernel_main(boot_info: &'static BootInfo) -> ! {
     memory::init(unsafe { &mut ALLOCATOR }, boot_info);
     printk!("Memory ✔\n");
 
+    // Capabilities. The scheduler's stacks are capabilities, so this comes first.
+    printk!("Capabilities ...\n");
+    cap::init();
+    printk!("Capabilities ✔\n");
+
     // Set up interrupt/exception handling
     printk!("Interrupts...\n\t");
     interrupts::init();
+    time::init();
     sched::user::init();
     printk!("Interrupts ✔\n");
 
@@ -75,53 +106,161 @@ fn kernel_main(boot_info: &'static BootInfo) -> ! {
     io::init();
     printk!("I/O ✔\n");
 
+    // Start the other cores. They wait for the scheduler to be created.
+    printk!("SMP ...\n");
+    smp::init(boot_info);
+    printk!("SMP ✔\n");
+
     // Create the init task, which finishes initialization.
     printk!("Taskes");
-    sched::init(Continuation::new(|_| {
+    let init = Continuation::new(|_| {
         printk!("Init task running!\n");
 
         late_init();
 
+        #[cfg(feature = "sched-bench")]
+        sched::bench::run();
+
         ///////////////////////////////////////////////////////////////////////
         // Init done!
         //
 
-        // Run a test
+        // Run a test. The ELF loader goes a few calls deep, so give it some headroom.
         ContResult::Success(vec![(
-            EventKind::Until(SysTime::now().after(4)),
-            Continuation::new(|_| {
+            EventKind::Now,
+            sched::future::spawn_with_stack_pages(8, async {
+                sched::future::sleep(4).await;
                 printk!("Init waited for 4 seconds! Success 🎉\n");
-                ContResult::Success(vec![(
-                    EventKind::Keyboard,
-                    Continuation::new(|ev| {
-                        if let Event::Keyboard(c) = ev {
+
+                match sched::future::wait(EventKind::Keyboard.with_timeout(10)).await {
+                    Event::Any { branch: 0, event } => {
+                        if let Event::Keyboard(c) = *event {
                             printk!("User typed '{}'\n", c as char);
                         } else {
                             unreachable!();
                         }
-                        ContResult::Success(vec![(
-                            EventKind::Now,
-                            Continuation::new(|_| {
-                                printk!("Attempting to switch to user!\n");
-
-                                let (_handle, rip) = user::load_user_elf(core::include_bytes!(
-                                    "../../user/target/x86_64-unknown-elf/release/test-user"
-                                ));
-                                let rsp = user::allocate_user_stack().with(|cap| {
-                                    let region = cap_unwrap!(VirtualMemoryRegion(cap));
-                                    let start = region.start();
-                                    let len = region.len();
-                                    unsafe { start.offset(len as isize) }
-                                });
-
-                                user::start_user_task(rip as u64, rsp as u64);
-                            }),
-                        )])
-                    }),
-                )])
+                    }
+                    Event::Any { .. } => printk!("Nobody typed anything for 10s...\n"),
+                    _ => unreachable!(),
+                }
+
+                // The user task is a keyboard driver, which sends us what is typed.
+                let (driver, keys) = match keyboard_driver_caps() {
+                    Ok(caps) => caps,
+                    Err(err) => return ContResult::Error(err, None),
+                };
+
+                let task = user::TaskHandle::new();
+
+                let binary =
+                    core::include_bytes!("../../user/target/x86_64-unknown-elf/release/test-user");
+                let (mut regions, rip) = match user::load_user_elf(binary) {
+                    Ok(loaded) => loaded,
+                    Err(err) => return ContResult::Error(err, None),
+                };
+                let stack = match user::allocate_user_stack() {
+                    Ok(stack) => stack,
+                    Err(err) => return ContResult::Error(err, None),
+                };
+                let rsp = match stack.with(|cap| {
+                    let region = cap_unwrap!(VirtualMemoryRegion(cap));
+                    region.start() as u64 + region.len()
+                }) {
+                    Ok(rsp) => rsp,
+                    Err(err) => return ContResult::Error(err, None),
+                };
+                regions.push(stack);
+
+                // The task only gets its own memory and the driver's capabilities, and it names
+                // them by their position here. Its handles are derived from ours, so releasing
+                // them can't free its own code or stack while it runs.
+                let task_caps = regions
+                    .iter()
+                    .chain(iter::once(&driver))
+                    .map(|handle| {
+                        handle
+                            .rights()
+                            .and_then(|rights| cap::derive(handle, rights))
+                    })
+                    .collect::<Result<Vec<_>, _>>();
+                let task_caps = match task_caps {
+                    Ok(task_caps) => task_caps,
+                    Err(err) => return ContResult::Error(err, None),
+                };
+                let mut handles = match HandleTable::new(task_caps.clone()) {
+                    Ok(handles) => handles,
+                    Err(err) => return ContResult::Error(err, None),
+                };
+
+                ContResult::Success(vec![
+                    (
+                        EventKind::Now,
+                        sched::future::spawn(async move {
+                            let code = loop {
+                                let typed_or_exited = EventKind::Any(vec![
+                                    EventKind::ChannelRecv(keys.clone()),
+                                    EventKind::TaskExit(task),
+                                ]);
+
+                                match sched::future::wait(typed_or_exited).await {
+                                    Event::Any { branch: 0, event } => {
+                                        if let Event::Message(Message::Word(c)) = *event {
+                                            printk!("{}", c as u8 as char);
+                                        }
+                                    }
+                                    Event::Any { event, .. } => match *event {
+                                        Event::TaskExited { code } => break code,
+                                        _ => unreachable!(),
+                                    },
+                                    _ => unreachable!(),
+                                }
+                            };
+
+                            printk!("User task exited with code {}\n", code);
+
+                            // The task is gone, so its memory can be freed, and the keyboard goes
+                            // back to the kernel's driver. Revoking our handles gets rid of the
+                            // task's too. Nobody else can get rid of our handles, so any failure
+                            // (even `MissingCapability`) is a bug. Report each one, but keep going
+                            // so that one bad handle doesn't leak the rest.
+                            let mut failed = Vec::new();
+                            let members = driver.members().unwrap_or_else(|err| {
+                                failed.push(err);
+                                Vec::new()
+                            });
+                            let revoked =
+                                regions.iter().chain(iter::once(&driver)).map(cap::revoke);
+                            let destroyed =
+                                members.iter().chain(iter::once(&keys)).map(cap::destroy);
+                            failed.extend(revoked.chain(destroyed).filter_map(Result::err));
+
+                            for err in failed.iter() {
+                                printk!("Unable to free the user task's resources: {:?}\n", err);
+                            }
+
+                            match failed.into_iter().next() {
+                                Some(err) => ContResult::Error(err, None),
+                                None => ContResult::Done,
+                            }
+                        }),
+                    ),
+                    (
+                        EventKind::Now,
+                        Continuation::new(move |_| {
+                            printk!("Attempting to switch to user!\n");
+                            user::start_user_task(task, mem::take(&mut handles), rip, rsp);
+                        })
+                        .with_label("user-start")
+                        .with_caps(task_caps),
+                    ),
+                ])
             }),
         )])
-    }));
+    })
+    .with_label("init");
+    let policy = sched_policy();
+    printk!(" (policy {:?})", policy);
+    sched::init(init.on_error(init_failed), policy);
 
     printk!(" ✔\n");
 
@@ -131,13 +270,57 @@ fn kernel_main(boot_info: &'static BootInfo) -> ! {
     // We never return...
 }
 
+/// Handles errors in the init task and its descendants.
+fn init_failed() -> Continuation {
+    Continuation::new(|ev| {
+        if let Event::Error(err) = ev {
+            printk!("Init failed: {:?}\n", err);
+        } else {
+            unreachable!();
+        }
+        ContResult::Done
+    })
+    .with_label("init-failed")
+}
+
+/// The scheduling policy named in the `SCHED_POLICY_FILE` fw_cfg file, or the default one.
+fn sched_policy() -> PolicyKind {
+    let mut name = [0; 16];
+    let len = match io::fw_cfg::read_file(SCHED_POLICY_FILE, &mut name) {
+        Some(len) => len,
+        None => return DEFAULT_SCHED_POLICY,
+    };
+
+    match core::str::from_utf8(&name[..len])
+        .ok()
+        .and_then(PolicyKind::from_name)
+    {
+        Some(policy) => policy,
+        None => {
+            printk!(" (unknown policy in {})", SCHED_POLICY_FILE);
+            DEFAULT_SCHED_POLICY
+        }
+    }
+}
+
+/// Capabilities for a keyboard driver in user space (see `user/src/main.rs`), in one group: the
+/// PS/2 controller's data and status ports, its IRQ line, and a channel that the driver may only
+/// send keys on. Returns the group along with the channel itself, for receiving the keys.
+fn keyboard_driver_caps() -> Result<(ResourceHandle, ResourceHandle), KernelError> {
+    let irq = Irq::claim(1)?.register();
+    let data = IoPortRange::new(0x60, 0x60).register();
+    let status = IoPortRange::new(0x64, 0x64).register();
+
+    let keys = Channel::new(KEYS_CAPACITY).register();
+    let send = cap::derive(&keys, Rights::WRITE)?;
+
+    let driver = CapabilityGroup::create(vec![irq, data, status, send])?.register();
+
+    Ok((driver, keys))
+}
+
 /// Initialization that happens after the first task is created.
 fn late_init() {
-    // Capabilities
-    printk!("Capabilities ...\n");
-    cap::init();
-    printk!("Capabilities ✔\n");
-
     // We can turn on interrupts now.
     x86_64::instructions::interrupts::enable();
 }
@@ -4,10 +4,14 @@ use bootloader::BootInfo;
 
 use x86_64::structures::idt::InterruptDescriptorTable;
 
-use crate::interrupts::IRQ_IST_FRAME_INDEX;
+use crate::interrupts::PAGE_FAULT_IST_FRAME_INDEX;
 
 pub use self::heap::KernelAllocator;
-pub use self::paging::{map_region, VirtualMemoryRegion, AVAILABLE_VADDR_START};
+pub use self::paging::{
+    guard_stack, identity_map, map_frames, map_region, populate_region, region_allows,
+    rights_needed, FrameQuota, PhysicalFrames, Placement, VirtualMemoryRegion,
+    AVAILABLE_VADDR_START,
+};
 
 mod heap;
 mod paging;
@@ -35,5 +39,5 @@ pub fn init(allocator: &mut KernelAllocator, boot_info: &'static BootInfo) {
 pub unsafe fn init_pf_handler(idt: &mut InterruptDescriptorTable) {
     idt.page_fault
         .set_handler_fn(crate::memory::paging::handle_page_fault)
-        .set_stack_index(IRQ_IST_FRAME_INDEX);
+        .set_stack_index(PAGE_FAULT_IST_FRAME_INDEX);
 }
@@ -12,28 +12,46 @@
 //! Physical memory is arranged by the bootloader, which first runs E820 to get a memory map. The
 //! `BootInfo` struct contains the current state of memory, including memory already allocated by
 //! the bootload for page tables, kernel text, etc...
+//!
+//! Drivers that need to know where their memory is physically (e.g. for DMA) or that need a
+//! device's registers (e.g. a PCI BAR) get a `PhysicalFrames` capability instead, and map it into a
+//! `VirtualMemoryRegion` right away with `map_frames`.
+
+use alloc::{
+    collections::{btree_map::Entry, BTreeMap, BTreeSet},
+    sync::Arc,
+    vec,
+    vec::Vec,
+};
 
-use alloc::collections::BTreeMap;
+use core::{mem, sync::atomic::spin_loop_hint};
 
-use bootloader::BootInfo;
+use bootloader::{
+    bootinfo::{MemoryMap, MemoryRegionType},
+    BootInfo,
+};
 
 use buddy::BuddyAllocator;
 
-use spin::Mutex;
+use spin::{Mutex, Once};
 
 use x86_64::{
     registers::model_specific::{Efer, EferFlags},
     structures::{
         idt::{InterruptStackFrame, PageFaultErrorCode},
         paging::{
-            FrameAllocator, Mapper, Page, PageSize, PageTable, PageTableFlags, PageTableIndex,
-            PhysFrame, RecursivePageTable, Size2MiB, Size4KiB, UnusedPhysFrame,
+            mapper::MapToError, FrameAllocator, Mapper, Page, PageSize, PageTable, PageTableFlags,
+            PageTableIndex, PhysFrame, RecursivePageTable, Size2MiB, Size4KiB, UnusedPhysFrame,
         },
     },
     PhysAddr, VirtAddr,
 };
 
-use crate::cap::{Capability, ResourceHandle, UnregisteredResourceHandle};
+use crate::{
+    cap::{Capability, ResourceHandle, Rights, UnregisteredResourceHandle},
+    error::KernelError,
+    smp::{self, TlbShootdown},
+};
 
 /// The kernel's physical frame allocator. It returns frame numbers, not physical addresses.
 static PHYS_MEM_ALLOC: Mutex<Option<phys::BuddyAllocator>> = Mutex::new(None);
@@ -52,6 +70,21 @@ static PAGE_TABLES: Mutex<Option<RecursivePageTable>> = Mutex::new(None);
 /// TODO: We should check permissions/capabilities for the fault first.
 static ALLOWED: Mutex<Option<BTreeMap<u64, (u64, PageTableFlags)>>> = Mutex::new(None);
 
+/// The guard pages of continuation stacks, by address. A fault on one of them means that a stack
+/// overflowed.
+static STACK_GUARDS: Mutex<Option<BTreeSet<u64>>> = Mutex::new(None);
+
+/// The physical memory mapped into memory regions with `map_frames`, by the address of the region.
+/// This keeps the memory from being freed while it is mapped.
+static MAPPED_FRAMES: Mutex<Option<BTreeMap<u64, Arc<Capability>>>> = Mutex::new(None);
+
+/// Memory regions that have been unmapped, but that other cores may still have in their TLBs. Their
+/// memory is freed once every core has flushed its TLB.
+static UNMAPPED: Mutex<Option<Vec<Unmapped>>> = Mutex::new(None);
+
+/// The memory map from the bootloader, for checking that device memory is not RAM.
+static MEMORY_MAP: Once<&'static MemoryMap> = Once::new();
+
 /// Address of guard page of the kernel heap (page before the first page of the heap).
 pub const KERNEL_HEAP_GUARD: u64 = (32 << 20) - (1 << 12);
 
@@ -260,6 +293,12 @@ pub fn init(boot_info: &'static BootInfo) {
     let mut allowed = ALLOWED.lock();
     *allowed = Some(BTreeMap::new());
 
+    *STACK_GUARDS.lock() = Some(BTreeSet::new());
+
+    *MAPPED_FRAMES.lock() = Some(BTreeMap::new());
+    *UNMAPPED.lock() = Some(Vec::new());
+    MEMORY_MAP.call_once(|| &boot_info.memory_map);
+
     printk!("\tvirtual address allocator inited\n");
 
     ///////////////////////////////////////////////////////////////////////////
@@ -308,7 +347,8 @@ pub fn init(boot_info: &'static BootInfo) {
     }
 }
 
-/// Capability on a memory region.
+/// Capability on a memory region. When the capability is destroyed (see `cap::destroy`), the
+/// region is unmapped, and its memory and address space are freed.
 #[derive(Debug)]
 pub struct VirtualMemoryRegion {
     /// The first virtual address of the memory region (bytes).
@@ -316,43 +356,66 @@ pub struct VirtualMemoryRegion {
 
     /// The length of the memory region (bytes).
     len: u64,
+
+    /// Is the region surrounded by guard pages (see `guard`)?
+    guarded: bool,
+}
+
+/// The memory of a region that has been unmapped, waiting for other cores to flush their TLBs.
+struct Unmapped {
+    /// The frames that backed the region, by frame number.
+    frames: Vec<usize>,
+
+    /// Physical memory that was mapped into the region with `map_frames`, which must not be freed
+    /// before the region's mappings are gone either.
+    pinned: Option<Arc<Capability>>,
+
+    /// The first page number and the number of pages of the region's address space, including any
+    /// guard pages.
+    pages: (usize, usize),
+
+    /// Tells when every core has flushed the region's mappings from its TLB.
+    shootdown: TlbShootdown,
 }
 
 impl VirtualMemoryRegion {
     /// Allocate a region of virtual memory (but not backed by physical memory). Specifically, allocate
-    /// the given number of pages. These allocations are basically parmanent.
+    /// the given number of pages. The region is freed when its capability is destroyed.
     ///
     /// No page table mappings are created. It is the user's responsibility to make sure the memory is
     /// mapped before it is used.
     ///
-    /// Return a capability for the allocated region.
-    ///
-    /// # Panics
-    ///
-    /// If we exhaust the virtual address space.
-    pub fn alloc(npages: usize) -> UnregisteredResourceHandle {
+    /// Return a capability for the allocated region, or `OutOfVirtualMemory` if we exhaust the
+    /// virtual address space.
+    pub fn alloc(npages: usize) -> Result<UnregisteredResourceHandle, KernelError> {
+        // Address space from regions that were unmapped a while ago may be reusable by now.
+        reclaim();
+
         let mem = VIRT_MEM_ALLOC
             .lock()
             .as_mut()
             .unwrap()
             .alloc(npages)
-            .expect("Out of virtual memory.");
-
-        UnregisteredResourceHandle::new(Capability::VirtualMemoryRegion(VirtualMemoryRegion {
-            addr: mem as u64 * Size4KiB::SIZE,
-            len: npages as u64 * Size4KiB::SIZE,
-        }))
+            .ok_or(KernelError::OutOfVirtualMemory)?;
+
+        Ok(UnregisteredResourceHandle::new(
+            Capability::VirtualMemoryRegion(VirtualMemoryRegion {
+                addr: mem as u64 * Size4KiB::SIZE,
+                len: npages as u64 * Size4KiB::SIZE,
+                guarded: false,
+            }),
+        ))
     }
 
     /// Like `alloc`, but adds 2 to npages and calls `guard`.
-    pub fn alloc_with_guard(npages: usize) -> UnregisteredResourceHandle {
-        let mut mem = Self::alloc(npages + 2);
+    pub fn alloc_with_guard(npages: usize) -> Result<UnregisteredResourceHandle, KernelError> {
+        let mut mem = Self::alloc(npages + 2)?;
         if let Capability::VirtualMemoryRegion(mem) = mem.as_mut_ref() {
             mem.guard();
         } else {
             unreachable!();
         }
-        mem
+        Ok(mem)
     }
 
     /// The first virtual address of the memory region.
@@ -372,23 +435,584 @@ impl VirtualMemoryRegion {
     pub fn guard(&mut self) {
         self.addr += Size4KiB::SIZE;
         self.len -= Size4KiB::SIZE * 2;
+        self.guarded = true;
+    }
+}
+
+impl Drop for VirtualMemoryRegion {
+    /// Unmap the region, free the frames backing it, and return its address space (including any
+    /// guard pages) to the allocator. The memory is only freed once no core can still access it
+    /// through its TLB (see `reclaim`).
+    fn drop(&mut self) {
+        ALLOWED.lock().as_mut().unwrap().remove(&self.addr);
+
+        if self.guarded {
+            let mut guards = STACK_GUARDS.lock();
+            let guards = guards.as_mut().unwrap();
+            guards.remove(&(self.addr - Size4KiB::SIZE));
+            guards.remove(&(self.addr + self.len));
+        }
+
+        // Frames mapped with `map_frames` belong to their own capability, so they are not freed
+        // here.
+        let pinned = MAPPED_FRAMES.lock().as_mut().unwrap().remove(&self.addr);
+        let borrowed = |paddr| match pinned.as_deref() {
+            Some(Capability::PhysicalFrames(frames)) => frames.contains(paddr),
+            _ => false,
+        };
+
+        // Only pages that have been touched are mapped.
+        let mut frames = Vec::new();
+        let first: Page<Size4KiB> = Page::containing_address(VirtAddr::new(self.addr));
+        let end: Page<Size4KiB> = Page::containing_address(VirtAddr::new(self.addr + self.len));
+        for page in Page::range(first, end) {
+            let unmapped = PAGE_TABLES.lock().as_mut().unwrap().unmap(page);
+            if let Ok((frame, flush)) = unmapped {
+                flush.flush();
+                if !borrowed(frame.start_address().as_u64()) {
+                    frames.push((frame.start_address().as_u64() / Size4KiB::SIZE) as usize);
+                }
+            }
+        }
+
+        let (start, npages) = if self.guarded {
+            (self.addr - Size4KiB::SIZE, self.len / Size4KiB::SIZE + 2)
+        } else {
+            (self.addr, self.len / Size4KiB::SIZE)
+        };
+
+        // Other cores may still have the old mappings in their TLBs.
+        UNMAPPED.lock().as_mut().unwrap().push(Unmapped {
+            frames,
+            pinned,
+            pages: ((start / Size4KiB::SIZE) as usize, npages as usize),
+            shootdown: smp::tlb_shootdown(),
+        });
+
+        reclaim();
+    }
+}
+
+/// Free the memory of unmapped regions that no core can access through its TLB anymore.
+fn reclaim() {
+    let done: Vec<Unmapped> = {
+        let mut unmapped = UNMAPPED.lock();
+        let unmapped = unmapped.as_mut().unwrap();
+        let (done, pending) = mem::take(unmapped)
+            .into_iter()
+            .partition(|unmapped| unmapped.shootdown.done());
+        *unmapped = pending;
+        done
+    };
+
+    for unmapped in done {
+        let mut pmem_alloc = PHYS_MEM_ALLOC.lock();
+        for &frame in unmapped.frames.iter() {
+            pmem_alloc.as_mut().unwrap().free(frame, 1);
+        }
+        drop(pmem_alloc); // unlock
+
+        let (start, npages) = unmapped.pages;
+        VIRT_MEM_ALLOC.lock().as_mut().unwrap().free(start, npages);
+
+        // Physical memory that was mapped with `map_frames` may be freed now if nobody has a
+        // handle on it.
+        drop(unmapped.pinned);
+    }
+}
+
+/// Capability on physical memory: either RAM from the frame allocator, e.g. for a device to access
+/// with DMA, or device memory, e.g. a PCI BAR. Map it into a memory region with `map_frames` to use
+/// it.
+///
+/// RAM is zeroed when it is allocated, and it is freed when the capability is dropped, which
+/// doesn't happen while it is mapped anywhere. Device memory is never freed.
+#[derive(Debug)]
+pub struct PhysicalFrames {
+    /// The physically contiguous runs of frames, in order: the physical address of the first frame
+    /// of each run (bytes) and the number of frames in it.
+    runs: Vec<(u64, u64)>,
+
+    /// The blocks to return to the frame allocator when the capability is dropped: the first frame
+    /// number and the number of frames of each. This is empty for device memory.
+    allocated: Vec<(usize, usize)>,
+
+    /// The quota that the RAM was charged to and the number of frames charged, which are given
+    /// back when the capability is dropped. Device memory isn't charged to any quota.
+    charged: Option<(FrameQuota, usize)>,
+}
+
+/// Where the frames of a `PhysicalFrames::alloc` may be.
+#[derive(Copy, Clone, Debug)]
+pub enum Placement {
+    /// Anywhere: the frames need not be contiguous.
+    Any,
+
+    /// In one physically contiguous run, starting at a multiple of `align` bytes (a power of two).
+    Contiguous { align: u64 },
+}
+
+/// A limit on the number of frames of RAM that may be allocated with `PhysicalFrames::alloc`. The
+/// frames count against it until they are freed. Clones share the same limit.
+#[derive(Clone, Debug)]
+pub struct FrameQuota {
+    /// The number of frames that may still be allocated.
+    left: Arc<Mutex<usize>>,
+}
+
+impl FrameQuota {
+    /// A quota of `nframes` frames.
+    pub fn new(nframes: usize) -> Self {
+        FrameQuota {
+            left: Arc::new(Mutex::new(nframes)),
+        }
+    }
+
+    /// Take `nframes` frames from the quota. Returns `QuotaExceeded` if there aren't that many
+    /// left.
+    fn charge(&self, nframes: usize) -> Result<(), KernelError> {
+        let mut left = self.left.lock();
+        *left = left
+            .checked_sub(nframes)
+            .ok_or(KernelError::QuotaExceeded)?;
+        Ok(())
+    }
+
+    /// Give `nframes` frames back to the quota.
+    fn refund(&self, nframes: usize) {
+        *self.left.lock() += nframes;
+    }
+}
+
+impl PhysicalFrames {
+    /// Allocate `nframes` frames of RAM, placed as `placement` says, and zero them. The frames are
+    /// charged to `quota` until the capability is dropped.
+    ///
+    /// Returns a capability on the frames, `OutOfPhysicalMemory` if there are not enough free
+    /// frames (or none contiguous enough), `QuotaExceeded` if `quota` doesn't have `nframes` left,
+    /// and `BadAlignment` if the alignment is not a power of two.
+    pub fn alloc(
+        nframes: usize,
+        placement: Placement,
+        quota: &FrameQuota,
+    ) -> Result<UnregisteredResourceHandle, KernelError> {
+        let align = match placement {
+            Placement::Any => 1,
+            Placement::Contiguous { align } if align.is_power_of_two() => {
+                (align / Size4KiB::SIZE).max(1) as usize
+            }
+            Placement::Contiguous { .. } => return Err(KernelError::BadAlignment),
+        };
+        let padded = nframes
+            .checked_add(align - 1)
+            .ok_or(KernelError::OutOfPhysicalMemory)?;
+
+        // From here on, dropping `frames` frees whatever has been allocated and refunds the quota.
+        quota.charge(nframes)?;
+        let mut frames = PhysicalFrames {
+            runs: Vec::new(),
+            allocated: Vec::new(),
+            charged: Some((quota.clone(), nframes)),
+        };
+
+        {
+            let mut pmem_alloc = PHYS_MEM_ALLOC.lock();
+            let pmem_alloc = pmem_alloc.as_mut().unwrap();
+
+            // Ask for enough extra frames that an aligned run fits in the block somewhere.
+            if let Some(first) = pmem_alloc.alloc(padded) {
+                let start = (first + align - 1) / align * align;
+                frames.allocated.push((first, padded));
+                frames
+                    .runs
+                    .push((start as u64 * Size4KiB::SIZE, nframes as u64));
+            } else if let Placement::Any = placement {
+                // Memory is fragmented, so take the frames one at a time.
+                for _ in 0..nframes {
+                    let frame = pmem_alloc
+                        .alloc(1)
+                        .ok_or(KernelError::OutOfPhysicalMemory)?;
+                    frames.allocated.push((frame, 1));
+                    frames.runs.push((frame as u64 * Size4KiB::SIZE, 1));
+                }
+            } else {
+                return Err(KernelError::OutOfPhysicalMemory);
+            }
+        }
+
+        // The frames may still hold whatever their last user left there.
+        frames.zero()?;
+
+        Ok(UnregisteredResourceHandle::new(Capability::PhysicalFrames(
+            frames,
+        )))
+    }
+
+    /// Create a capability on the device memory (e.g. a PCI BAR) of `len` bytes at physical
+    /// address `paddr`, rounded out to whole frames.
+    ///
+    /// Returns `NotDeviceMemory` if any of it is RAM or is otherwise used by the system (i.e. the
+    /// bootloader's memory map says it is anything but reserved).
+    pub fn mmio(paddr: u64, len: u64) -> Result<UnregisteredResourceHandle, KernelError> {
+        let first = paddr / Size4KiB::SIZE;
+        let end = (paddr + len + Size4KiB::SIZE - 1) / Size4KiB::SIZE;
+
+        let used = MEMORY_MAP
+            .r#try()
+            .expect("paging not initialized")
+            .iter()
+            .any(|region| {
+                region.region_type != MemoryRegionType::Reserved
+                    && region.range.start_frame_number < end
+                    && first < region.range.end_frame_number
+            });
+        if used {
+            return Err(KernelError::NotDeviceMemory);
+        }
+
+        Ok(UnregisteredResourceHandle::new(Capability::PhysicalFrames(
+            PhysicalFrames {
+                runs: vec![(first * Size4KiB::SIZE, end - first)],
+                allocated: Vec::new(),
+                charged: None,
+            },
+        )))
+    }
+
+    /// The physical address of the first frame. If the frames are contiguous, this is where all of
+    /// them start.
+    pub fn start_address(&self) -> u64 {
+        self.runs.first().map_or(0, |&(paddr, _)| paddr)
+    }
+
+    /// The length of the memory (in bytes).
+    pub fn len(&self) -> u64 {
+        self.runs.iter().map(|&(_, n)| n).sum::<u64>() * Size4KiB::SIZE
+    }
+
+    /// The physical addresses of the frames, in order.
+    pub fn frames(&self) -> impl Iterator<Item = u64> + '_ {
+        self.runs
+            .iter()
+            .flat_map(|&(paddr, n)| (0..n).map(move |i| paddr + i * Size4KiB::SIZE))
+    }
+
+    /// Is the physical address `paddr` in this memory?
+    pub fn contains(&self, paddr: u64) -> bool {
+        self.runs
+            .iter()
+            .any(|&(start, n)| start <= paddr && paddr < start + n * Size4KiB::SIZE)
+    }
+
+    /// Zero all of the frames by mapping each of them into the kernel's address space for a
+    /// moment.
+    fn zero(&self) -> Result<(), KernelError> {
+        let vaddr = VIRT_MEM_ALLOC
+            .lock()
+            .as_mut()
+            .unwrap()
+            .alloc(1)
+            .ok_or(KernelError::OutOfVirtualMemory)? as u64
+            * Size4KiB::SIZE;
+        let page: Page<Size4KiB> = Page::containing_address(VirtAddr::new(vaddr));
+
+        let mut result = Ok(());
+        for paddr in self.frames() {
+            result = map_frame(
+                vaddr,
+                paddr,
+                PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE,
+            );
+            if result.is_err() {
+                break;
+            }
+
+            unsafe {
+                core::ptr::write_bytes(vaddr as *mut u8, 0, Size4KiB::SIZE as usize);
+            }
+
+            let (_, flush) = PAGE_TABLES
+                .lock()
+                .as_mut()
+                .unwrap()
+                .unmap(page)
+                .expect("page was just mapped");
+            flush.flush();
+        }
+
+        VIRT_MEM_ALLOC
+            .lock()
+            .as_mut()
+            .unwrap()
+            .free((vaddr / Size4KiB::SIZE) as usize, 1);
+
+        result
+    }
+}
+
+impl Drop for PhysicalFrames {
+    /// Return RAM to the frame allocator and its frames to the quota.
+    fn drop(&mut self) {
+        let mut pmem_alloc = PHYS_MEM_ALLOC.lock();
+        for &(frame, n) in self.allocated.iter() {
+            pmem_alloc.as_mut().unwrap().free(frame, n);
+        }
+
+        if let Some((quota, nframes)) = &self.charged {
+            quota.refund(*nframes);
+        }
     }
 }
 
 /// Mark the `region` as usable with the given `flags`. This does not allocate any physical memory.
-/// Pages will be allocated by demand paging.
-pub fn map_region(region: ResourceHandle, flags: PageTableFlags) {
+/// Pages will be allocated by demand paging. Pages that are already mapped (e.g. because they have
+/// been written) get the new flags right away.
+///
+/// Returns `MissingCapability` if `region` is not held or has been revoked, and
+/// `InsufficientRights` if `flags` allow more than the rights of `region`.
+pub fn map_region(region: &ResourceHandle, flags: PageTableFlags) -> Result<(), KernelError> {
+    region.check_rights(rights_needed(flags))?;
+
     let (start, len) = {
         region.with(|cap| {
             let region = cap_unwrap!(VirtualMemoryRegion(cap));
             (region.start(), region.len())
-        })
+        })?
     };
     ALLOWED
         .lock()
         .as_mut()
         .unwrap()
         .insert(start as u64, (len, flags));
+
+    let first: Page<Size4KiB> = Page::containing_address(VirtAddr::new(start as u64));
+    let end: Page<Size4KiB> = Page::containing_address(VirtAddr::new(start as u64 + len));
+    let mut updated = false;
+    for page in Page::range(first, end) {
+        // Pages that haven't been touched yet are not mapped.
+        let flush = PAGE_TABLES
+            .lock()
+            .as_mut()
+            .unwrap()
+            .update_flags(page, flags);
+        if let Ok(flush) = flush {
+            flush.flush();
+            updated = true;
+        }
+    }
+
+    // The new flags may allow less than the old ones, so other cores must not keep using the old.
+    if updated {
+        let shootdown = smp::tlb_shootdown();
+        while !shootdown.done() {
+            spin_loop_hint();
+        }
+    }
+
+    Ok(())
+}
+
+/// Map all of the physical memory `frames` into the beginning of `region` right away, with the
+/// given `flags` (e.g. `NO_CACHE` or `WRITE_THROUGH` for device memory). The memory stays mapped,
+/// and so it is not freed, until `region` is destroyed.
+///
+/// Returns `MissingCapability` if `region` or `frames` is not held or has been revoked,
+/// `InsufficientRights` if `flags` allow more than the rights of either, `WrongCapabilityType` if
+/// they are not a memory region and physical memory, `RegionTooSmall` if the memory doesn't fit in
+/// `region`, and `AlreadyMapped` if `region` already has physical memory mapped into it or any of
+/// its pages are mapped.
+pub fn map_frames(
+    region: &ResourceHandle,
+    frames: &ResourceHandle,
+    flags: PageTableFlags,
+) -> Result<(), KernelError> {
+    region.check_rights(rights_needed(flags))?;
+    frames.check_rights(rights_needed(flags))?;
+
+    let (start, len) = region.with(|cap| match cap {
+        Capability::VirtualMemoryRegion(region) => Ok((region.start() as u64, region.len())),
+        _ => Err(KernelError::WrongCapabilityType),
+    })??;
+
+    let pinned = frames.pin()?;
+    let frames = match &*pinned {
+        Capability::PhysicalFrames(frames) => frames,
+        _ => return Err(KernelError::WrongCapabilityType),
+    };
+
+    if frames.len() > len {
+        return Err(KernelError::RegionTooSmall);
+    }
+
+    // Remember the memory before mapping any of it, so that destroying the region never frees it,
+    // even if mapping fails part of the way through.
+    match MAPPED_FRAMES.lock().as_mut().unwrap().entry(start) {
+        Entry::Occupied(_) => return Err(KernelError::AlreadyMapped),
+        Entry::Vacant(entry) => {
+            entry.insert(pinned.clone());
+        }
+    }
+
+    for (n, paddr) in frames.frames().enumerate() {
+        map_frame(start + n as u64 * Size4KiB::SIZE, paddr, flags)?;
+    }
+
+    Ok(())
+}
+
+/// The rights needed on a memory region to map it with `flags`.
+pub fn rights_needed(flags: PageTableFlags) -> Rights {
+    let mut rights = Rights::MAP;
+
+    if flags.contains(PageTableFlags::PRESENT) {
+        rights = rights | Rights::READ;
+    }
+    if flags.contains(PageTableFlags::WRITABLE) {
+        rights = rights | Rights::WRITE;
+    }
+    if !flags.contains(PageTableFlags::NO_EXECUTE) {
+        rights = rights | Rights::EXECUTE;
+    }
+
+    rights
+}
+
+/// Remember that the guard pages around `region` (see `VirtualMemoryRegion::alloc_with_guard`)
+/// protect a continuation stack, so that faults on them are reported as stack overflows.
+pub fn guard_stack(region: &ResourceHandle) -> Result<(), KernelError> {
+    let (start, len) = region.with(|cap| {
+        let region = cap_unwrap!(VirtualMemoryRegion(cap));
+        (region.start() as u64, region.len())
+    })?;
+
+    let mut guards = STACK_GUARDS.lock();
+    let guards = guards.as_mut().unwrap();
+    guards.insert(start - Size4KiB::SIZE);
+    guards.insert(start + len);
+
+    Ok(())
+}
+
+/// Is all of `[addr, addr + len)` in a single region marked usable by `map_region` with (at least)
+/// the given `flags`? System calls use this to check addresses passed from user space.
+pub fn region_allows(addr: u64, len: u64, flags: PageTableFlags) -> bool {
+    let end = match addr.checked_add(len) {
+        Some(end) => end,
+        None => return false,
+    };
+
+    match ALLOWED.lock().as_ref().unwrap().range(0..=addr).next_back() {
+        Some((&start, &(region_len, region_flags))) => {
+            end <= start + region_len && region_flags.contains(flags)
+        }
+        None => false,
+    }
+}
+
+/// Map all of the `region` right away with the flags it was given by `map_region`, rather than
+/// one page at a time as it is touched. The kernel does this before it fills in memory, since a
+/// page fault has nobody to return an error to. Pages that are already mapped are left alone.
+///
+/// Returns `OutOfPhysicalMemory` if we run out of frames part of the way through. The pages mapped
+/// so far stay mapped. Returns `MissingCapability` if `region` is not held or has been revoked.
+pub fn populate_region(region: &ResourceHandle) -> Result<(), KernelError> {
+    let (start, len) = region.with(|cap| {
+        let region = cap_unwrap!(VirtualMemoryRegion(cap));
+        (region.start() as u64, region.len())
+    })?;
+    let flags = ALLOWED
+        .lock()
+        .as_ref()
+        .unwrap()
+        .get(&start)
+        .map(|&(_, flags)| flags)
+        .expect("region has not been mapped with `map_region`");
+
+    let first: Page<Size4KiB> = Page::containing_address(VirtAddr::new(start));
+    let end: Page<Size4KiB> = Page::containing_address(VirtAddr::new(start + len));
+    for page in Page::range(first, end) {
+        map_fresh_page(page, flags)?;
+    }
+
+    Ok(())
+}
+
+/// Map `page` to a newly allocated frame with the given `flags`, unless it is mapped already.
+///
+/// Returns `OutOfPhysicalMemory` if there are no free frames left for the page or its page tables.
+fn map_fresh_page(page: Page<Size4KiB>, flags: PageTableFlags) -> Result<(), KernelError> {
+    let mut page_tables = PAGE_TABLES.lock();
+    let page_tables = page_tables.as_mut().unwrap();
+
+    // Another core may have faulted on the same page and mapped it already.
+    if page_tables.translate_page(page).is_ok() {
+        return Ok(());
+    }
+
+    let frame = PHYS_MEM_ALLOC
+        .lock()
+        .as_mut()
+        .unwrap()
+        .allocate_frame()
+        .ok_or(KernelError::OutOfPhysicalMemory)?;
+    let frame_number = (frame.start_address().as_u64() / Size4KiB::SIZE) as usize;
+
+    let mapped = page_tables.map_to(page, frame, flags, PHYS_MEM_ALLOC.lock().as_mut().unwrap());
+
+    match mapped {
+        Ok(flush) => {
+            flush.flush();
+            Ok(())
+        }
+        Err(MapToError::FrameAllocationFailed) => {
+            PHYS_MEM_ALLOC
+                .lock()
+                .as_mut()
+                .unwrap()
+                .free(frame_number, 1);
+            Err(KernelError::OutOfPhysicalMemory)
+        }
+        Err(err) => panic!("Unable to map page {:?}: {:?}", page, err),
+    }
+}
+
+/// Map the page at physical address `paddr` to the same virtual address, e.g. for code that
+/// starts running before paging is enabled. The page must be in the first 2MiB, which the kernel
+/// doesn't otherwise use.
+pub fn identity_map(paddr: u64, flags: PageTableFlags) -> Result<(), KernelError> {
+    assert!(
+        paddr < 2 << 20,
+        "identity mapping {:#x} would clash with the kernel",
+        paddr
+    );
+    map_frame(paddr, paddr, flags)
+}
+
+/// Map the page at `vaddr` to the frame at `paddr`, which is not managed by the physical memory
+/// allocator or belongs to a `PhysicalFrames`.
+fn map_frame(vaddr: u64, paddr: u64, flags: PageTableFlags) -> Result<(), KernelError> {
+    let page: Page<Size4KiB> =
+        Page::from_start_address(VirtAddr::new(vaddr)).expect("Page is unaligned");
+    let frame = PhysFrame::from_start_address(PhysAddr::new(paddr)).expect("Frame is unaligned");
+
+    PAGE_TABLES
+        .lock()
+        .as_mut()
+        .unwrap()
+        .map_to(
+            page,
+            // The allocator won't hand out this frame, so nobody else is using it.
+            unsafe { UnusedPhysFrame::new(frame) },
+            flags,
+            PHYS_MEM_ALLOC.lock().as_mut().unwrap(),
+        )
+        .map_err(|err| match err {
+            MapToError::FrameAllocationFailed => KernelError::OutOfPhysicalMemory,
+            _ => KernelError::AlreadyMapped,
+        })?
+        .flush();
+
+    Ok(())
 }
 
 /// Handle a page fault
@@ -430,25 +1054,41 @@ pub extern "x86-interrupt" fn handle_page_fault(
             );
 
             // Map the correct region
-            let page: Page<Size4KiB> =
-                Page::from_start_address(VirtAddr::new(start)).expect("Region is unaligned");
-            let frame = PHYS_MEM_ALLOC
-                .lock()
-                .as_mut()
-                .unwrap()
-                .allocate_frame()
-                .expect("Unable to allocate physical memory");
-            PAGE_TABLES
-                .lock()
-                .as_mut()
-                .unwrap()
-                .map_to(page, frame, *flags, PHYS_MEM_ALLOC.lock().as_mut().unwrap())
-                .expect("Unable to map page")
-                .flush();
-
-            printk!("\tDone with page fault.\n");
+            let page: Page<Size4KiB> = Page::containing_address(VirtAddr::new(cr2));
+            match map_fresh_page(page, *flags) {
+                Ok(()) => printk!("\tDone with page fault.\n"),
+
+                // A user task can't go on without the page, so it ends with the error.
+                Err(err) if esf.code_segment & 3 == 3 => crate::sched::user::fault(esf, err),
+
+                // The kernel maps its memory with `populate_region` first, so that it gets the
+                // error instead.
+                Err(err) => panic!(
+                    "Unable to map page at ip {:x}, addr {:x}: {:?}",
+                    esf.instruction_pointer.as_u64(),
+                    cr2,
+                    err
+                ),
+            }
         }
 
+        // Stack overflow (or underflow). We are on the page fault stack, not the one that
+        // overflowed, but the heap may be locked (or broken), so report it without allocating.
+        _ if is_stack_guard(cr2) => match crate::sched::stats::running() {
+            Some((id, label)) => panic!(
+                "continuation stack overflow at ip {:x}, addr {:x}, in {:?} #{}",
+                esf.instruction_pointer.as_u64(),
+                cr2,
+                label,
+                id
+            ),
+            None => panic!(
+                "continuation stack overflow at ip {:x}, addr {:x}, in the scheduler",
+                esf.instruction_pointer.as_u64(),
+                cr2
+            ),
+        },
+
         // Segfault
         _ => {
             panic!(
@@ -459,3 +1099,13 @@ pub extern "x86-interrupt" fn handle_page_fault(
         }
     }
 }
+
+/// Is `addr` in the guard page of a continuation stack?
+fn is_stack_guard(addr: u64) -> bool {
+    let page = addr & !(Size4KiB::SIZE - 1);
+
+    // Don't deadlock if we faulted while registering a stack.
+    STACK_GUARDS
+        .try_lock()
+        .map_or(false, |guards| guards.as_ref().unwrap().contains(&page))
+}
@@ -0,0 +1,47 @@
+//! A benchmark of scheduling decisions with lots of sleeping continuations.
+//!
+//! Build the kernel with `--features sched-bench` to run it during boot. Each scheduling decision
+//! should take roughly the same time no matter how many continuations are asleep.
+
+use core::arch::x86_64::_rdtsc;
+
+use crate::continuation::{ContResult, Continuation, EventKind};
+use crate::time::SysTime;
+
+use super::wait::WaitQueues;
+
+/// The number of scheduling decisions to time for each configuration.
+const DECISIONS: u64 = 1000;
+
+/// Run the benchmark and print the results.
+pub fn run() {
+    for &sleeping in &[0, 10, 100, 1000, 5000] {
+        let mut queues = WaitQueues::new();
+
+        // Half of the sleepers wait on timers that won't expire during the benchmark and the other
+        // half wait on keyboard input.
+        let later = SysTime::now().after(3600);
+        for i in 0..sleeping {
+            let kind = if i % 2 == 0 {
+                EventKind::Until(later)
+            } else {
+                EventKind::Keyboard
+            };
+            queues.enqueue(kind, Continuation::new(|_| ContResult::Done));
+        }
+
+        // Time how long it takes to enqueue and pick a runnable continuation.
+        let start = unsafe { _rdtsc() };
+        for _ in 0..DECISIONS {
+            queues.enqueue(EventKind::Now, Continuation::new(|_| ContResult::Done));
+            queues.next().expect("nothing ready");
+        }
+        let cycles = unsafe { _rdtsc() } - start;
+
+        printk!(
+            "sched-bench: {:5} sleeping, {:8} cycles/decision\n",
+            sleeping,
+            cycles / DECISIONS
+        );
+    }
+}
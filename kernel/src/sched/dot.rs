@@ -0,0 +1,129 @@
+//! Dump the continuation graph in Graphviz DOT format, for debugging.
+//!
+//! Paste the output into `dot -Tsvg` to see which continuations are pending, what they are waiting
+//! for, and which continuations produced them.
+
+use alloc::{format, string::String, vec::Vec};
+
+use spin::Mutex;
+
+use crate::continuation::ContId;
+use crate::time::SysTime;
+
+use super::{Core, Scheduler};
+
+/// A copy of the parts of the scheduler state that go in the graph. Printing over the serial port
+/// is slow, so the graph is copied while the scheduler is locked and printed after it is unlocked.
+pub(super) struct Graph {
+    /// Recent parent/child edges: the parent, and the child.
+    edges: Vec<(ContId, Node)>,
+
+    /// Pending continuations.
+    pending: Vec<Node>,
+}
+
+/// A continuation in the graph.
+struct Node {
+    id: ContId,
+    label: &'static str,
+    created: SysTime,
+
+    /// What the continuation is waiting for, if it is pending.
+    waiting: Option<String>,
+}
+
+impl Graph {
+    /// Copy the graph out of the given scheduler state and cores.
+    pub(super) fn new(s: &Scheduler, cores: &[Mutex<Option<Core>>]) -> Self {
+        let edges = s
+            .edges
+            .iter()
+            .map(|edge| {
+                let child = Node {
+                    id: edge.child,
+                    label: edge.label,
+                    created: edge.created,
+                    waiting: None,
+                };
+                (edge.parent, child)
+            })
+            .collect();
+
+        let mut pending: Vec<_> = s
+            .queues
+            .iter()
+            .map(|(kind, cont)| Node {
+                id: cont.id(),
+                label: cont.label(),
+                created: cont.created(),
+                waiting: Some(match kind {
+                    Some(kind) => escape(format!("{:?}", kind)),
+                    None => String::from("nothing (ready)"),
+                }),
+            })
+            .collect();
+
+        // Continuations on each core's run queue.
+        for (cpu, core) in cores.iter().enumerate() {
+            if let Some(core) = core.lock().as_ref() {
+                for (_, cont) in core.run.iter() {
+                    pending.push(Node {
+                        id: cont.id(),
+                        label: cont.label(),
+                        created: cont.created(),
+                        waiting: Some(format!("nothing (ready on core {})", cpu)),
+                    });
+                }
+            }
+        }
+
+        Graph { edges, pending }
+    }
+
+    /// Print the graph.
+    pub(super) fn print(&self) {
+        printk!("digraph continuations {{\n");
+        printk!("\tnode [shape=box];\n");
+
+        // Recent edges. Children that are still pending are redrawn below.
+        for (parent, child) in self.edges.iter() {
+            child.print();
+            printk!("\tc{} -> c{};\n", parent, child.id);
+        }
+
+        // Pending continuations, along with what they are waiting for.
+        for node in self.pending.iter() {
+            node.print();
+        }
+
+        printk!("}}\n");
+    }
+}
+
+impl Node {
+    /// Print the node. Pending nodes are drawn in bold, along with the event they wait for.
+    fn print(&self) {
+        match &self.waiting {
+            Some(waiting) => printk!(
+                "\tc{} [label=\"{} #{}\\ncreated {:?}\\nwaiting {}\", style=bold];\n",
+                self.id,
+                self.label,
+                self.id,
+                self.created,
+                waiting
+            ),
+            None => printk!(
+                "\tc{} [label=\"{} #{}\\ncreated {:?}\"];\n",
+                self.id,
+                self.label,
+                self.id,
+                self.created
+            ),
+        }
+    }
+}
+
+/// Escape quotes so that `s` can go in a DOT label.
+fn escape(s: String) -> String {
+    s.replace('"', "\\\"")
+}
@@ -0,0 +1,247 @@
+//! Running `core::future::Future`s on top of the continuation scheduler.
+//!
+//! Writing long chains of continuations by hand gets painful quickly, so this module allows
+//! writing them as `async` code instead. `spawn` turns a future into a `Continuation`, which can
+//! be scheduled like any other continuation. Each time the future is polled and returns
+//! `Poll::Pending`, the continuation waits for the kernel events requested by the future (e.g. via
+//! `wait` or `sleep`) and polls it again when one of them happens. If the future awaits several
+//! events at once (e.g. with a `select`), the continuation waits for an `EventKind::Any` of all of
+//! them.
+//!
+//! The future's output is the `ContResult` of the continuation, so `async` code can still fan out
+//! into ordinary continuations when it finishes.
+//!
+//! Futures that are not waiting on kernel events (e.g. hand-written futures) can be woken with the
+//! usual `Waker`. Wakers must not be used from interrupt handlers, since they enqueue continuations
+//! in the scheduler. A task that is waiting on kernel events is only re-polled when one of them
+//! happens, even if it is woken in the meantime. A woken task keeps the capabilities and error
+//! handlers it had when it went to sleep.
+
+use alloc::{boxed::Box, sync::Arc, vec, vec::Vec};
+
+use core::{
+    future::Future,
+    mem,
+    pin::Pin,
+    sync::atomic::{AtomicUsize, Ordering},
+    task::{Context, Poll, RawWaker, RawWakerVTable, Waker},
+};
+
+use spin::Mutex;
+
+use crate::{
+    cap::{self, CapabilityGroup},
+    continuation::{self, ContId, ContResult, Continuation, Event, EventKind, HandlerChain},
+    smp::PerCpu,
+    time::SysTime,
+};
+
+/// The task currently being polled on each core, if any.
+static CURRENT: PerCpu<Option<Arc<Task>>> = PerCpu::new();
+
+/// The next token to be handed out to a `WaitFor`.
+static NEXT_TOKEN: AtomicUsize = AtomicUsize::new(0);
+
+/// A future being driven by the scheduler.
+struct Task {
+    /// All of the continuations that poll this task share one id, so that the task can be joined.
+    id: ContId,
+
+    /// The size of the stacks the task is polled on, in pages.
+    stack_pages: usize,
+
+    future: Mutex<Pin<Box<dyn Future<Output = ContResult> + Send>>>,
+    state: Mutex<TaskState>,
+}
+
+/// Book-keeping for a `Task`.
+#[derive(Default)]
+struct TaskState {
+    /// The kernel events requested by the task's futures during the last poll, along with the
+    /// token of the `WaitFor` that requested each one.
+    requests: Vec<(usize, EventKind)>,
+
+    /// The event delivered since the last poll, along with the token of the `WaitFor` it is for.
+    delivered: Option<(usize, Event)>,
+
+    /// If the task is not waiting on anything in the scheduler, so it will only run again if woken,
+    /// the capabilities and error handlers it had when it parked. It gets them back when it wakes.
+    parked: Option<(CapabilityGroup, Option<Arc<HandlerChain>>)>,
+
+    /// The task was woken while it was running.
+    woken: bool,
+}
+
+/// Returns a continuation that drives `future` to completion. The output of `future` is the result
+/// of the continuation.
+pub fn spawn<F>(future: F) -> Continuation
+where
+    F: 'static + Send + Future<Output = ContResult>,
+{
+    spawn_with_stack_pages(super::STACK_PAGES, future)
+}
+
+/// Like `spawn`, but `future` is always polled on a stack of at least `npages` pages (see
+/// `Continuation::with_stack_pages`), e.g. because it makes deep calls.
+pub fn spawn_with_stack_pages<F>(npages: usize, future: F) -> Continuation
+where
+    F: 'static + Send + Future<Output = ContResult>,
+{
+    poll_cont(Arc::new(Task {
+        id: ContId::new(),
+        stack_pages: npages,
+        future: Mutex::new(Box::pin(future)),
+        state: Mutex::new(TaskState::default()),
+    }))
+}
+
+/// Returns a continuation that polls `task` once.
+fn poll_cont(task: Arc<Task>) -> Continuation {
+    let id = task.id;
+    let stack_pages = task.stack_pages;
+    Continuation::new(move |event| poll(&task, event))
+        .with_label("future")
+        .with_id(id)
+        .with_stack_pages(stack_pages)
+}
+
+/// Poll `task`, which was waiting for `event`.
+fn poll(task: &Arc<Task>, event: Event) -> ContResult {
+    // Figure out which request (if any) the event is for.
+    {
+        let mut state = task.state.lock();
+        let requests = mem::take(&mut state.requests);
+
+        state.delivered = match (requests.len(), event) {
+            // Just spawned or woken up.
+            (0, _) => None,
+            (1, event) => Some((requests[0].0, event)),
+            (_, Event::Any { branch, event }) => Some((requests[branch].0, *event)),
+            _ => unreachable!(),
+        };
+    }
+
+    // Poll the future.
+    let waker = waker(task.clone());
+    let mut cx = Context::from_waker(&waker);
+
+    *CURRENT.get().lock() = Some(task.clone());
+    let result = task.future.lock().as_mut().poll(&mut cx);
+    *CURRENT.get().lock() = None;
+
+    let pending = match result {
+        Poll::Ready(result) => return result,
+        Poll::Pending => {
+            let mut state = task.state.lock();
+
+            // If nobody claimed the event, the future that wanted it must have gone away.
+            state.delivered = None;
+
+            if mem::replace(&mut state.woken, false) {
+                state.requests.clear();
+                EventKind::Now
+            } else {
+                match state.requests.len() {
+                    0 => {
+                        // This continuation has no children to pass its capabilities to, so keep
+                        // a copy of them for the continuation that `wake` creates.
+                        state.parked = Some((cap::current(), continuation::current_handlers()));
+                        return ContResult::Success(vec![]);
+                    }
+                    1 => state.requests[0].1.clone(),
+                    _ => EventKind::Any(state.requests.iter().map(|(_, k)| k.clone()).collect()),
+                }
+            }
+        }
+    };
+
+    ContResult::Success(vec![(pending, poll_cont(task.clone()))])
+}
+
+/// Wake up `task` so that it is polled again.
+fn wake(task: Arc<Task>) {
+    let mut state = task.state.lock();
+
+    if let Some((caps, handlers)) = state.parked.take() {
+        drop(state);
+        let cont = poll_cont(task)
+            .with_caps(caps.iter().cloned().collect())
+            .with_handlers(handlers);
+        super::enqueue(vec![(EventKind::Now, cont)]);
+    } else {
+        state.woken = true;
+    }
+}
+
+/// Create a `Waker` for the given task.
+fn waker(task: Arc<Task>) -> Waker {
+    unsafe { Waker::from_raw(raw_waker(task)) }
+}
+
+fn raw_waker(task: Arc<Task>) -> RawWaker {
+    RawWaker::new(Arc::into_raw(task) as *const (), &VTABLE)
+}
+
+static VTABLE: RawWakerVTable = RawWakerVTable::new(
+    // clone
+    |data| unsafe {
+        let task = Arc::from_raw(data as *const Task);
+        let clone = task.clone();
+        mem::forget(task);
+        raw_waker(clone)
+    },
+    // wake
+    |data| unsafe { wake(Arc::from_raw(data as *const Task)) },
+    // wake_by_ref
+    |data| unsafe {
+        let task = Arc::from_raw(data as *const Task);
+        wake(task.clone());
+        mem::forget(task);
+    },
+    // drop
+    |data| unsafe { drop(Arc::from_raw(data as *const Task)) },
+);
+
+/// A future that completes with the `Event` when the kernel event it waits for happens.
+pub struct WaitFor {
+    kind: EventKind,
+
+    /// Identifies this `WaitFor` among the requests of its task.
+    token: usize,
+}
+
+impl Future for WaitFor {
+    type Output = Event;
+
+    fn poll(self: Pin<&mut Self>, _cx: &mut Context) -> Poll<Event> {
+        let task = CURRENT
+            .get()
+            .lock()
+            .clone()
+            .expect("kernel futures must be spawned with `sched::future::spawn`");
+        let mut state = task.state.lock();
+
+        // Is the event here?
+        match state.delivered.take() {
+            Some((token, event)) if token == self.token => return Poll::Ready(event),
+            other => state.delivered = other,
+        }
+
+        // No. Ask the scheduler to wait for it.
+        state.requests.push((self.token, self.kind.clone()));
+        Poll::Pending
+    }
+}
+
+/// Wait for the given kernel event.
+pub fn wait(kind: EventKind) -> WaitFor {
+    WaitFor {
+        kind,
+        token: NEXT_TOKEN.fetch_add(1, Ordering::Relaxed),
+    }
+}
+
+/// Wait for `secs` seconds to pass.
+pub async fn sleep(secs: usize) {
+    wait(EventKind::Until(SysTime::now().after(secs))).await;
+}
@@ -1,157 +1,335 @@
 //! The scheduler
-
+//!
+//! Each core has its own scheduler stacks and its own run queue of continuations that are ready to
+//! run. Continuations waiting on events are shared by all cores: whichever core notices an event
+//! runs the continuation waiting for it (or leaves it for another core to pick up). A core that
+//! runs out of work steals from the other cores' run queues before going idle.
+//!
+//! The order in which each core runs its ready continuations is decided by the scheduling policy
+//! chosen at boot (see `policy`).
+
+pub mod future;
+pub mod policy;
+pub mod replay;
+pub mod stats;
 pub mod user;
 
-use alloc::{boxed::Box, collections::linked_list::LinkedList, vec, vec::Vec};
+#[cfg(feature = "sched-bench")]
+pub mod bench;
+mod dot;
+mod wait;
+
+use alloc::{boxed::Box, collections::VecDeque, format, vec, vec::Vec};
+
+use core::{
+    mem,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use spin::{Mutex, Once};
 
-use core::{borrow::Borrow, mem};
+use x86_64::structures::paging::{PageSize, PageTableFlags, Size4KiB};
 
-use spin::Mutex;
+use crate::cap::{self, KernelAuthority, ResourceHandle};
+use crate::continuation::{ContId, ContValue, Continuation, Event, EventKind};
+use crate::error::KernelError;
+use crate::memory::{self, VirtualMemoryRegion};
+use crate::smp::{self, PerCpu, MAX_CPUS};
+use crate::time::{self, AtomicSysTime, SysTime};
 
-use crate::continuation::{Continuation, Event, EventKind};
-use crate::time::SysTime;
+use self::policy::{PolicyKind, SchedPolicy};
+use self::user::TaskHandle;
+use self::wait::WaitQueues;
 
-/// The size of a stack in words
-const STACK_WORDS: usize = 1 << 12; // 16KB
+/// The number of recent parent/child edges remembered for debugging.
+const RECENT_EDGES: usize = 128;
 
-/// The kernel task scheduler instance
+/// The default size of a continuation stack in pages. Continuations can ask for bigger stacks with
+/// `Continuation::with_stack_pages`.
+pub const STACK_PAGES: usize = 4; // 16KB
+
+/// The kernel task scheduler instance, shared by all cores.
 static SCHEDULER: Mutex<Option<Scheduler>> = Mutex::new(None);
 
-/// The head of the current stack
+/// The per-core parts of the scheduler.
+static CORES: PerCpu<Option<Core>> = PerCpu::new();
+
+/// The scheduling policy of every core, chosen at boot.
+static POLICY: Once<PolicyKind> = Once::new();
+
+/// Set once the scheduler has been created, so that the other cores can start scheduling.
+static READY: AtomicBool = AtomicBool::new(false);
+
+/// The earliest time any continuation is waiting for. The timer interrupt handler uses this to
+/// decide whether to wake up idle cores, so it needs to be readable without taking a lock.
+static NEXT_DEADLINE: AtomicSysTime = AtomicSysTime::new();
+
+/// The head of the current stack of each core. The syscall handler finds its core's entry through
+/// `KERNEL_GS_BASE`.
 // I think the scheduler and the syscall handler are the only ones using this,
-// and by construction at most one of them can be running at a time...
-static mut CURRENT_STACK_HEAD: u64 = 0;
+// and by construction at most one of them can be running at a time on each core...
+static mut STACK_HEADS: [u64; MAX_CPUS] = [0; MAX_CPUS];
 
 /// The kernel task scheduler
 struct Scheduler {
-    /// The list of outstanding continuations that have yet to be scheduled, along with the event
-    /// each one is waiting on.
-    next: LinkedList<(EventKind, Continuation)>,
+    /// The outstanding continuations that have yet to be scheduled, indexed by the event each one
+    /// is waiting on. Continuations whose event has happened wait here for any core to take them.
+    queues: WaitQueues,
+
+    /// The most recent parent/child edges in the continuation graph, oldest first.
+    edges: VecDeque<Edge>,
+}
+
+/// The part of the scheduler that belongs to a single core.
+struct Core {
+    /// Continuations that are ready to run on this core (unless another core steals them), along
+    /// with the `Event` each one was waiting for, in the order chosen by the scheduling policy.
+    run: Box<dyn SchedPolicy>,
 
-    // Because every core is single-threaded, we only need one stack. After a task executes, we can
-    // just clean it up and reuse it. However, to make life a bit easier, we just allocate two
-    // stacks: one for the current task and one for the next task.
+    // Because every core is single-threaded, we only need one stack per core. After a task
+    // executes, we can just clean it up and reuse it. However, to make life a bit easier, we just
+    // allocate two stacks: one for the current task and one for the next task.
     /// The stack of the current task
     current_stack: Stack,
 
     /// A clean stack for the next task
     clean_stack: Stack,
+
+    /// A continuation that wanted a bigger stack than the current one, on its way to the clean
+    /// stack.
+    bigger_stack: Option<(Event, Continuation)>,
 }
 
 impl Scheduler {
-    /// Get the next continuation to run along with the `Event` that it was waiting for. If no
-    /// continuation exists or no continuation is ready, return None.
-    pub fn next(&mut self) -> Option<(Event, Continuation)> {
-        // Iterate through all current outstanding tasks. Choose the first one that is ready.
-        for _ in 0..self.next.len() {
-            match self.next.pop_front()? {
-                // Not waiting? Great!
-                (EventKind::Now, cont) => return Some((Event::Now, cont)),
-
-                // Timer events? Is the requested time here?
-                (EventKind::Until(time), cont) => {
-                    if SysTime::now() >= time {
-                        return Some((Event::Timer, cont));
-                    } else {
-                        // Not ready; put it back.
-                        self.next.push_back((EventKind::Until(time), cont));
-                    }
-                }
+    /// Take all continuations whose event has happened along with their `Event`s.
+    pub fn take_ready(&mut self) -> Vec<(Event, Continuation)> {
+        core::iter::from_fn(|| self.queues.next()).collect()
+    }
 
-                // Waiting for kbd input?
-                (EventKind::Keyboard, cont) => {
-                    if let Some(c) = crate::io::kbd::kbd_next() {
-                        return Some((Event::Keyboard(c), cont));
-                    } else {
-                        // Not ready; put it back.
-                        self.next.push_back((EventKind::Keyboard, cont));
-                    }
+    /// Enqueue the given list of continuations. Continuations that can run right away are
+    /// returned so that the caller can put them on its run queue.
+    pub fn enqueue(
+        &mut self,
+        mut cont: Vec<(EventKind, Continuation)>,
+    ) -> Vec<(Event, Continuation)> {
+        for (_, cont) in cont.iter() {
+            if let Some(parent) = cont.parent() {
+                if self.edges.len() >= RECENT_EDGES {
+                    self.edges.pop_front();
                 }
+                self.edges.push_back(Edge {
+                    parent,
+                    child: cont.id(),
+                    label: cont.label(),
+                    created: cont.created(),
+                });
+            }
+        }
+
+        let mut ready = vec![];
+        for (kind, mut cont) in cont.drain(..) {
+            cont.set_waiting(&kind);
+
+            match kind {
+                EventKind::Now => ready.push((Event::Now, cont)),
+                kind => self.queues.enqueue(kind, cont),
             }
         }
 
-        // Didn't find anything (ready)...
-        None
+        ready
+    }
+
+    /// Record that the given continuation has finished with the given result, if anyone is going to
+    /// join it.
+    pub fn finished(&mut self, id: ContId, value: Option<ContValue>) {
+        self.queues.finished(id, value);
+    }
+
+    /// Record that the given user task has exited with the given exit code.
+    pub fn task_exited(&mut self, task: TaskHandle, code: isize) {
+        self.queues.task_exited(task, code);
+    }
+
+    /// Something was sent or received on the channel `chan`.
+    pub fn channel_activity(&mut self, chan: ResourceHandle) {
+        self.queues.channel_activity(chan);
     }
 
-    /// Enqueue the given list of continuations.
-    pub fn enqueue(&mut self, mut cont: Vec<(EventKind, Continuation)>) {
-        self.next.extend(cont.drain(..));
+    /// Some capabilities have been revoked.
+    pub fn revoked(&mut self) {
+        self.queues.revoked();
     }
 }
 
-/// An stack for execution of continuations
-struct Stack(Box<[usize; STACK_WORDS]>);
+/// A parent/child edge in the continuation graph, remembered for debugging.
+struct Edge {
+    parent: ContId,
+    child: ContId,
+
+    /// The label of the child.
+    label: &'static str,
+
+    /// When the child was created.
+    created: SysTime,
+}
+
+/// An stack for execution of continuations. It is surrounded by unmapped guard pages, so an
+/// overflow (or a bug that unwinds too far) causes a page fault instead of corrupting memory.
+struct Stack {
+    /// The memory region of the stack, which is freed when the stack is dropped.
+    region: ResourceHandle,
+
+    /// The first address of the stack.
+    start: *mut usize,
+
+    /// The size of the stack in words.
+    words: usize,
+}
+
+// The stack is only ever used by the core that owns it.
+unsafe impl Send for Stack {}
 
 impl Stack {
-    /// Returns a new clean stack
-    pub fn new() -> Self {
-        Stack(box [0; STACK_WORDS]) // initialize in place
+    /// Returns a new clean stack of `npages` pages.
+    pub fn new(npages: usize) -> Result<Self, KernelError> {
+        // The stack belongs to the kernel, not to whatever continuation is running (if any).
+        let kernel = unsafe { KernelAuthority::new() };
+        let (region, start, len) = cap::as_kernel(&kernel, || {
+            let region = VirtualMemoryRegion::alloc_with_guard(npages)?.register();
+            memory::map_region(
+                &region,
+                PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE,
+            )?;
+            memory::guard_stack(&region)?;
+
+            let (start, len) = region.with(|cap| {
+                let region = cap_unwrap!(VirtualMemoryRegion(cap));
+                (region.start(), region.len())
+            })?;
+
+            Ok::<_, KernelError>((region, start, len))
+        })?;
+
+        let mut stack = Stack {
+            region,
+            start: start as *mut usize,
+            words: len as usize / mem::size_of::<usize>(),
+        };
+
+        // Fault in the whole stack now, rather than while running on it.
+        stack.clear();
+
+        Ok(stack)
+    }
+
+    /// The size of this stack in pages.
+    pub fn pages(&self) -> usize {
+        self.words * mem::size_of::<usize>() / Size4KiB::SIZE as usize
     }
 
     /// Returns the stack pointer to use for this stack
     pub fn first_rsp(&self) -> usize {
-        /// Add a little padding in case a bug causes us to unwind too far.
-        const PADDING: usize = 400; // words
-
-        // The end of the array is the "bottom" (highest address) in the stack.
-        let stack: &[usize; STACK_WORDS] = self.0.borrow();
-        let bottom = stack.as_ptr();
-        unsafe { bottom.add(STACK_WORDS - PADDING) as usize }
+        // The end of the region is the "bottom" (highest address) in the stack.
+        unsafe { self.start.add(self.words) as usize }
     }
 
     /// Clear the contents of this stack
     pub fn clear(&mut self) {
-        for word in self.0.iter_mut() {
+        let stack = unsafe { core::slice::from_raw_parts_mut(self.start, self.words) };
+        for word in stack.iter_mut() {
             *word = 0xDEADBEEF_DEADBEEF;
         }
     }
 }
 
-/// Start the first task. This is only called by `kernel_main`!
+impl Drop for Stack {
+    fn drop(&mut self) {
+        // Nobody else holds the region, so this frees it.
+        let kernel = unsafe { KernelAuthority::new() };
+        cap::as_kernel(&kernel, || cap::destroy(&self.region))
+            .expect("continuation stack is not registered");
+    }
+}
+
+/// Start the first task on this core. This is only called by `kernel_main` and by the other cores
+/// when they start.
 pub fn start() -> ! {
     sched()
 }
 
-/// Initialize the process/scheduling subsystem with the initial continuation.
-pub fn init(init: Continuation) {
-    let mut s = SCHEDULER.lock();
-
-    let mut next = LinkedList::new();
-    next.push_back((EventKind::Now, init));
+/// Initialize the process/scheduling subsystem with the initial continuation and the scheduling
+/// policy for all cores. This is called by the bootstrap core.
+pub fn init(init: Continuation, policy: PolicyKind) {
+    POLICY.call_once(|| policy);
 
     // Create the scheduler
-    *s = Some(Scheduler {
-        next,
-        current_stack: Stack::new(),
-        clean_stack: Stack::new(),
+    *SCHEDULER.lock() = Some(Scheduler {
+        queues: WaitQueues::new(),
+        edges: VecDeque::new(),
     });
+    stats::init();
+    replay::init();
+
+    init_cpu();
+    CORES
+        .get()
+        .lock()
+        .as_mut()
+        .unwrap()
+        .run
+        .push(Event::Now, init);
+
+    // Let the other cores in.
+    READY.store(true, Ordering::Release);
+}
+
+/// Create the calling core's stacks and run queue.
+pub fn init_cpu() {
+    let policy = *POLICY.r#try().expect("scheduling policy not chosen yet");
+    let core = Core {
+        run: policy.create(),
+        current_stack: Stack::new(STACK_PAGES).expect("unable to allocate a continuation stack"),
+        clean_stack: Stack::new(STACK_PAGES).expect("unable to allocate a continuation stack"),
+        bigger_stack: None,
+    };
 
     // Set the current stack
     unsafe {
-        CURRENT_STACK_HEAD = s.as_ref().unwrap().current_stack.first_rsp() as u64;
+        STACK_HEADS[smp::cpu()] = core.current_stack.first_rsp() as u64;
     }
+
+    *CORES.get().lock() = Some(core);
+}
+
+/// Has the scheduler been created yet?
+pub fn is_ready() -> bool {
+    READY.load(Ordering::Acquire)
 }
 
 /// Run the scheduler to choose a task. Then switch to that task, discarding the current task as
 /// complete. This should be called after all clean up has been completed. If no next task exists,
 /// the idle continuation is used.
 pub fn sched() -> ! {
-    // Get the scheduler
-    let mut sched = SCHEDULER.lock();
-    let s = sched.as_mut().unwrap();
+    // Whatever was running is done now (e.g. a user task may have exited), so we are no longer
+    // restricted to its capabilities.
+    let _ = crate::cap::leave();
+    stats::stopped();
+
+    // Get this core's scheduler
+    let mut core = CORES.get().lock();
+    let c = core.as_mut().unwrap();
 
     // Make the clean stack the current stack
-    mem::swap(&mut s.current_stack, &mut s.clean_stack);
+    mem::swap(&mut c.current_stack, &mut c.clean_stack);
 
     // switch to clean stack.
-    let rsp = s.current_stack.first_rsp();
+    let rsp = c.current_stack.first_rsp();
 
     unsafe {
-        CURRENT_STACK_HEAD = rsp as u64;
+        STACK_HEADS[smp::cpu()] = rsp as u64;
     }
 
-    drop(sched); // unlock
+    drop(core); // unlock
 
     unsafe {
         sched_part_2_thunk(rsp);
@@ -178,49 +356,250 @@ unsafe fn sched_part_2_thunk(rsp: usize) -> ! {
 /// Now that we are running on the new stack, we can clean the old one. Then, switch to the next
 /// task and start running it.
 unsafe fn sched_part_3() -> ! {
-    // Get the scheduler
-    let mut sched = SCHEDULER.lock();
-    let s = sched.as_mut().unwrap();
-
-    // clean old stack
-    s.clean_stack.clear();
+    // clean old stack, and pick up a continuation that we switched stacks for, if any
+    let bigger = {
+        let mut core = CORES.get().lock();
+        let c = core.as_mut().unwrap();
+        c.clean_stack.clear();
+        c.bigger_stack.take()
+    };
 
     // get the next task
-    let (event, next) = if let Some(next) = s.next() {
+    let (event, next) = if let Some(next) = bigger.or_else(next) {
         next
     } else {
         (Event::Now, make_idle_cont())
     };
 
-    drop(sched); // unlock
+    // If the task wants a bigger stack than this one, make sure the clean stack is big enough, and
+    // go around again on the clean stack.
+    {
+        let mut core = CORES.get().lock();
+        let c = core.as_mut().unwrap();
+
+        if next.stack_pages() > c.current_stack.pages() {
+            if next.stack_pages() > c.clean_stack.pages() {
+                c.clean_stack = Stack::new(next.stack_pages())
+                    .expect("unable to allocate a continuation stack");
+            }
+
+            c.bigger_stack = Some((event, next));
+            drop(core); // unlock
+            sched();
+        }
+    }
 
     // run the task
     next.run(event)
 }
 
-/// Enqueue the given list of continuations in the scheduler.
+/// Get the next continuation for this core to run. Continuations whose event just happened join
+/// this core's run queue, and the scheduling policy picks one of those. If there are none, one is
+/// stolen from another core.
+fn next() -> Option<(Event, Continuation)> {
+    if let Some(expected) = replay::expected() {
+        if let Some(next) = replay_next(expected) {
+            return Some(next);
+        }
+    }
+
+    let woken = with_scheduler(|s| s.take_ready());
+
+    {
+        let mut core = CORES.get().lock();
+        let run = &mut core.as_mut().unwrap().run;
+
+        for (event, cont) in woken {
+            run.push(event, cont);
+        }
+
+        if let Some(next) = run.pop() {
+            return Some(next);
+        }
+    }
+
+    steal()
+}
+
+/// Get the continuation that the replayed log says should run next, delivering the input it was
+/// woken by first. Returns `None` if that continuation isn't ready, in which case the run has
+/// diverged from the log.
+fn replay_next(expected: replay::Expected) -> Option<(Event, Continuation)> {
+    let woken = with_scheduler(|s| {
+        if let Some(input) = expected.input {
+            s.queues.inject(expected.id, &expected.path, input);
+        }
+        s.take_ready()
+    });
+
+    let mut core = CORES.get().lock();
+    let run = &mut core.as_mut().unwrap().run;
+
+    for (event, cont) in woken {
+        run.push(event, cont);
+    }
+
+    match run.take(expected.id) {
+        Some((event, cont)) => {
+            let actual = replay::describe(&event);
+            if actual != expected.event {
+                replay::diverged(
+                    expected.seq,
+                    &format!(
+                        "#{} got {} instead of {}",
+                        expected.id, actual, expected.event
+                    ),
+                );
+            }
+            Some((event, cont))
+        }
+        None => {
+            replay::diverged(expected.seq, &format!("#{} is not ready", expected.id));
+            None
+        }
+    }
+}
+
+/// Steal a continuation from another core's run queue. Cores whose run queue is locked are
+/// skipped, so two cores stealing from each other can't deadlock.
+fn steal() -> Option<(Event, Continuation)> {
+    let me = smp::cpu();
+
+    CORES
+        .all()
+        .iter()
+        .enumerate()
+        .filter(|&(cpu, _)| cpu != me)
+        .filter_map(|(_, core)| core.try_lock())
+        .find_map(|mut core| core.as_mut().and_then(|core| core.run.steal()))
+}
+
+/// Is there anything for this core to do? This is only a hint, since other cores may get to it
+/// first.
+fn has_work() -> bool {
+    let expired = timer_expired();
+    let woken = SCHEDULER.lock().as_ref().unwrap().queues.has_ready();
+    let queued = CORES.all().iter().any(|core| {
+        core.try_lock().map_or(false, |core| {
+            core.as_ref().map_or(false, |core| !core.run.is_empty())
+        })
+    });
+
+    expired || woken || queued
+}
+
+/// Run `f` on the shared part of the scheduler. Afterwards, wake up idle cores if there are
+/// continuations waiting to be run.
+fn with_scheduler<R>(f: impl FnOnce(&mut Scheduler) -> R) -> R {
+    let mut sched = SCHEDULER.lock();
+    let s = sched.as_mut().unwrap();
+
+    // The queues act on behalf of the continuations waiting in them, which may hold capabilities
+    // that the current one doesn't. They were checked when the continuations were enqueued.
+    let kernel = unsafe { KernelAuthority::new() };
+    let result = cap::as_kernel(&kernel, || f(s));
+
+    let deadline = s.queues.next_deadline();
+    NEXT_DEADLINE.store(deadline);
+    let ready = s.queues.has_ready();
+
+    drop(sched); // unlock
+
+    if let Some(deadline) = deadline {
+        time::arm(deadline);
+    }
+
+    if ready {
+        smp::wake_idle();
+    }
+
+    result
+}
+
+/// Has the earliest timer any continuation is waiting for expired? If not, make sure the timer
+/// interrupt happens when it does. This is called by the timer interrupt handler, so it doesn't
+/// take the scheduler lock.
+pub fn timer_expired() -> bool {
+    match NEXT_DEADLINE.load() {
+        Some(deadline) if deadline <= SysTime::now() => true,
+        Some(deadline) => {
+            time::arm(deadline);
+            false
+        }
+        None => false,
+    }
+}
+
+/// Enqueue the given list of continuations in the scheduler. Continuations that can run right away
+/// go on this core's run queue, and idle cores are woken up to steal them.
 pub fn enqueue(cont: Vec<(EventKind, Continuation)>) {
-    SCHEDULER.lock().as_mut().unwrap().enqueue(cont);
+    let ready = with_scheduler(|s| s.enqueue(cont));
+
+    if !ready.is_empty() {
+        let mut core = CORES.get().lock();
+        let run = &mut core.as_mut().unwrap().run;
+        for (event, cont) in ready {
+            run.push(event, cont);
+        }
+        drop(core); // unlock
+
+        smp::wake_idle();
+    }
 }
 
-/// Returns the idle continuation.
-pub fn make_idle_cont() -> Continuation {
-    Continuation::new(|_| {
-        printk!("Idle\n");
+/// Record that the continuation `id` has finished with the given result. Any continuations waiting
+/// on it with `EventKind::Join` may become ready.
+pub fn finished(id: ContId, value: Option<ContValue>) {
+    with_scheduler(|s| s.finished(id, value));
+}
 
-        // Make sure interrupts are enabled here. Otherwise, hlt will never return.
-        x86_64::instructions::interrupts::enable();
+/// Something was sent or received on the channel `chan` other than through `EventKind::ChannelSend`
+/// or `EventKind::ChannelRecv`. Any continuations waiting on the channel get a chance to make
+/// progress.
+pub fn channel_activity(chan: ResourceHandle) {
+    with_scheduler(|s| s.channel_activity(chan));
+}
 
-        // Wait a bit before rescheduling
-        x86_64::instructions::hlt();
+/// Some capabilities have been revoked. Any continuations waiting on channels or IRQ lines through
+/// them can never make progress, so they get `Event::Error` instead.
+pub fn revoked() {
+    with_scheduler(|s| s.revoked());
+}
 
-        sched();
-    })
+/// Record that the given user task has exited with the given exit code. Any continuations waiting
+/// on `EventKind::TaskExit(task)` become ready.
+pub fn task_exited(task: TaskHandle, code: isize) {
+    with_scheduler(|s| s.task_exited(task, code));
 }
 
-/// Enqueue the idle continuation. This continuation just calls the scheduler to schedule something
-/// else if possible.
-pub fn idle() {
-    let cont = make_idle_cont();
-    enqueue(vec![(EventKind::Now, cont)]);
+/// Dump the pending continuations and recent parent/child edges as a Graphviz DOT graph over the
+/// serial port.
+pub fn dump_dot() {
+    let graph = dot::Graph::new(SCHEDULER.lock().as_ref().unwrap(), CORES.all());
+    graph.print();
+}
+
+/// Returns the idle continuation. Each core makes its own when it has nothing else to run, and it
+/// never goes on a run queue, so other cores can't steal it.
+fn make_idle_cont() -> Continuation {
+    Continuation::idle(|_| {
+        stats::idle_loop();
+
+        // Let the other cores know that we want to be woken up when there is work to do. We only
+        // check for work after that, so that we can't miss a wakeup.
+        x86_64::instructions::interrupts::disable();
+        smp::set_idle(true);
+
+        // Wait a bit before rescheduling. This enables interrupts. Otherwise, hlt will never
+        // return.
+        if !has_work() {
+            smp::halt();
+        }
+
+        smp::set_idle(false);
+        x86_64::instructions::interrupts::enable();
+
+        sched();
+    })
+    .with_label("idle")
 }
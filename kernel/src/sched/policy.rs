@@ -0,0 +1,293 @@
+//! Scheduling policies, which decide the order in which ready continuations run.
+//!
+//! Each core keeps its ready continuations in a `SchedPolicy`. Continuations whose event happened
+//! are moved onto the run queue of the core that noticed, so the policy sees every continuation
+//! before it runs. The policy is selected at boot with `PolicyKind`.
+//!
+//! Boosts, like the one for continuations woken by keyboard input, carry over to the children that
+//! a boosted continuation spawns to run right away, so the rest of the work stays boosted too.
+
+use alloc::{
+    boxed::Box,
+    collections::{BTreeMap, VecDeque},
+    vec::Vec,
+};
+
+use core::{arch::x86_64::_rdtsc, cmp::Reverse};
+
+use crate::{
+    continuation::{ContId, Continuation, Event},
+    time::SysTime,
+};
+
+/// The priority of continuations that don't ask for one. Higher priorities run first.
+pub const DEFAULT_PRIORITY: usize = 0;
+
+/// The number of lottery tickets of continuations that don't ask for a number.
+pub const DEFAULT_TICKETS: usize = 10;
+
+/// The priority of continuations woken by keyboard input.
+pub const INTERACTIVE_PRIORITY: usize = DEFAULT_PRIORITY + 10;
+
+/// The number of lottery tickets of continuations woken by keyboard input.
+pub const INTERACTIVE_TICKETS: usize = DEFAULT_TICKETS * 10;
+
+/// A run queue that decides which ready continuation runs next.
+pub trait SchedPolicy: Send {
+    /// Add a continuation that is ready to run, along with the `Event` it was waiting for.
+    fn push(&mut self, event: Event, cont: Continuation);
+
+    /// Remove the continuation that should run next.
+    fn pop(&mut self) -> Option<(Event, Continuation)>;
+
+    /// Remove a continuation for another core to run. By default, this is the one that would run
+    /// next.
+    fn steal(&mut self) -> Option<(Event, Continuation)> {
+        self.pop()
+    }
+
+    /// Remove the continuation `id`, if it is ready to run. This is only used to replay a log (see
+    /// `replay`), so by default it just pops everything and pushes back the rest.
+    fn take(&mut self, id: ContId) -> Option<(Event, Continuation)> {
+        let mut found = None;
+        let mut rest = Vec::new();
+
+        while let Some((event, cont)) = self.pop() {
+            if cont.id() == id {
+                found = Some((event, cont));
+            } else {
+                rest.push((event, cont));
+            }
+        }
+
+        for (event, cont) in rest {
+            self.push(event, cont);
+        }
+
+        found
+    }
+
+    /// Are there no continuations ready to run?
+    fn is_empty(&self) -> bool;
+
+    /// All continuations ready to run, in no particular order.
+    fn iter<'a>(&'a self) -> Box<dyn Iterator<Item = &'a (Event, Continuation)> + 'a>;
+}
+
+/// The available scheduling policies.
+#[derive(Copy, Clone, Debug)]
+pub enum PolicyKind {
+    /// First come, first served. See `Fifo`.
+    Fifo,
+
+    /// Strict priority. See `Priority`.
+    Priority,
+
+    /// Earliest deadline first. See `Edf`.
+    Edf,
+
+    /// Lottery scheduling. See `Lottery`.
+    Lottery,
+}
+
+impl PolicyKind {
+    /// The policy called `name` (e.g. "lottery"), if there is one.
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name.trim() {
+            "fifo" => Some(PolicyKind::Fifo),
+            "priority" => Some(PolicyKind::Priority),
+            "edf" => Some(PolicyKind::Edf),
+            "lottery" => Some(PolicyKind::Lottery),
+            _ => None,
+        }
+    }
+
+    /// Create an empty run queue with this policy.
+    pub fn create(self) -> Box<dyn SchedPolicy> {
+        match self {
+            PolicyKind::Fifo => Box::new(Fifo::default()),
+            PolicyKind::Priority => Box::new(Priority::default()),
+            PolicyKind::Edf => Box::new(Edf::default()),
+            PolicyKind::Lottery => Box::new(Lottery::new()),
+        }
+    }
+}
+
+/// Continuations run in the order they became ready. Other cores steal the newest ones.
+#[derive(Default)]
+pub struct Fifo {
+    queue: VecDeque<(Event, Continuation)>,
+}
+
+impl SchedPolicy for Fifo {
+    fn push(&mut self, event: Event, cont: Continuation) {
+        self.queue.push_back((event, cont));
+    }
+
+    fn pop(&mut self) -> Option<(Event, Continuation)> {
+        self.queue.pop_front()
+    }
+
+    fn steal(&mut self) -> Option<(Event, Continuation)> {
+        self.queue.pop_back()
+    }
+
+    fn is_empty(&self) -> bool {
+        self.queue.is_empty()
+    }
+
+    fn iter<'a>(&'a self) -> Box<dyn Iterator<Item = &'a (Event, Continuation)> + 'a> {
+        Box::new(self.queue.iter())
+    }
+}
+
+/// The continuation with the highest priority runs first; continuations with the same priority
+/// run in the order they became ready. Other cores steal the lowest-priority ones.
+#[derive(Default)]
+pub struct Priority {
+    /// Ready continuations, by priority and then by the order they became ready.
+    queue: BTreeMap<(Reverse<usize>, usize), (Event, Continuation)>,
+
+    /// The next sequence number to hand out.
+    next_seq: usize,
+}
+
+impl SchedPolicy for Priority {
+    fn push(&mut self, event: Event, cont: Continuation) {
+        let key = (Reverse(cont.priority()), self.next_seq);
+        self.next_seq += 1;
+        self.queue.insert(key, (event, cont));
+    }
+
+    fn pop(&mut self) -> Option<(Event, Continuation)> {
+        let key = *self.queue.keys().next()?;
+        self.queue.remove(&key)
+    }
+
+    fn steal(&mut self) -> Option<(Event, Continuation)> {
+        let key = *self.queue.keys().next_back()?;
+        self.queue.remove(&key)
+    }
+
+    fn is_empty(&self) -> bool {
+        self.queue.is_empty()
+    }
+
+    fn iter<'a>(&'a self) -> Box<dyn Iterator<Item = &'a (Event, Continuation)> + 'a> {
+        Box::new(self.queue.values())
+    }
+}
+
+/// The continuation with the earliest deadline runs first. Continuations without a deadline run
+/// after all of those with one, in the order they became ready. Other cores steal the ones with
+/// the latest deadlines.
+#[derive(Default)]
+pub struct Edf {
+    /// Ready continuations, by deadline and then by the order they became ready. The first part of
+    /// the key sorts continuations without a deadline last.
+    queue: BTreeMap<(bool, Option<SysTime>, usize), (Event, Continuation)>,
+
+    /// The next sequence number to hand out.
+    next_seq: usize,
+}
+
+impl SchedPolicy for Edf {
+    fn push(&mut self, event: Event, cont: Continuation) {
+        let deadline = cont.deadline();
+        let key = (deadline.is_none(), deadline, self.next_seq);
+        self.next_seq += 1;
+        self.queue.insert(key, (event, cont));
+    }
+
+    fn pop(&mut self) -> Option<(Event, Continuation)> {
+        let key = *self.queue.keys().next()?;
+        self.queue.remove(&key)
+    }
+
+    fn steal(&mut self) -> Option<(Event, Continuation)> {
+        let key = *self.queue.keys().next_back()?;
+        self.queue.remove(&key)
+    }
+
+    fn is_empty(&self) -> bool {
+        self.queue.is_empty()
+    }
+
+    fn iter<'a>(&'a self) -> Box<dyn Iterator<Item = &'a (Event, Continuation)> + 'a> {
+        Box::new(self.queue.values())
+    }
+}
+
+/// Each continuation holds some lottery tickets, and the next continuation to run is the holder of
+/// a ticket drawn at random. Continuations with more tickets tend to run sooner, but every
+/// continuation eventually runs.
+pub struct Lottery {
+    queue: Vec<(Event, Continuation)>,
+
+    /// The total number of tickets held by the continuations in `queue`.
+    tickets: usize,
+
+    /// The state of the random number generator (xorshift64).
+    rng: u64,
+}
+
+impl Lottery {
+    pub fn new() -> Self {
+        Lottery {
+            queue: Vec::new(),
+            tickets: 0,
+
+            // xorshift must not start at 0.
+            rng: unsafe { _rdtsc() } | 1,
+        }
+    }
+
+    /// Returns a random number.
+    fn random(&mut self) -> u64 {
+        self.rng ^= self.rng << 13;
+        self.rng ^= self.rng >> 7;
+        self.rng ^= self.rng << 17;
+        self.rng
+    }
+}
+
+impl SchedPolicy for Lottery {
+    fn push(&mut self, event: Event, cont: Continuation) {
+        self.tickets += cont.tickets();
+        self.queue.push((event, cont));
+    }
+
+    fn pop(&mut self) -> Option<(Event, Continuation)> {
+        if self.queue.is_empty() {
+            return None;
+        }
+
+        // Draw a ticket, and find out who holds it. If nobody has any tickets, the first
+        // continuation wins.
+        let mut ticket = (self.random() % self.tickets.max(1) as u64) as usize;
+        let winner = self
+            .queue
+            .iter()
+            .position(|(_, cont)| {
+                if ticket < cont.tickets() {
+                    true
+                } else {
+                    ticket -= cont.tickets();
+                    false
+                }
+            })
+            .unwrap_or(0);
+
+        let (event, cont) = self.queue.swap_remove(winner);
+        self.tickets -= cont.tickets();
+        Some((event, cont))
+    }
+
+    fn is_empty(&self) -> bool {
+        self.queue.is_empty()
+    }
+
+    fn iter<'a>(&'a self) -> Box<dyn Iterator<Item = &'a (Event, Continuation)> + 'a> {
+        Box::new(self.queue.iter())
+    }
+}
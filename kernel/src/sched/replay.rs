@@ -0,0 +1,245 @@
+//! Record and replay of scheduling decisions, for reproducing timing-dependent bugs.
+//!
+//! Build the kernel with `--features sched-record` to log every scheduling decision: which
+//! continuation ran, when, on which core, and the `Event` (with its payload) it was given. The log
+//! is dumped over the serial port whenever the buffer fills up and when the kernel panics, as
+//! lines starting with `replay:`.
+//!
+//! To replay a run, save those lines to `kernel/replay.log` and build the kernel with `--features
+//! sched-replay`. The scheduler then runs continuations in the logged order. Timer, keyboard, and
+//! IRQ events (see `interrupts::irq`), which are the only events that come from outside the kernel,
+//! are delivered when the log says they were, with the logged payload, rather than when they really
+//! happen. If the run diverges from the log, the scheduler says so and carries on normally. The
+//! same happens when the log runs out.
+//!
+//! Both modes only use the bootstrap core and don't preempt user tasks, since we can't replay
+//! either. The idle continuation isn't logged, since how often a core goes idle depends on timing.
+
+use alloc::{
+    format,
+    string::{String, ToString},
+    vec::Vec,
+};
+
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+use spin::Mutex;
+
+use crate::{
+    continuation::{ContId, Event},
+    smp,
+    time::SysTime,
+};
+
+/// Are we recording?
+pub const RECORD: bool = cfg!(feature = "sched-record");
+
+/// The number of decisions buffered before the log is dumped.
+const CAPACITY: usize = 1024;
+
+/// The log to replay.
+#[cfg(feature = "sched-replay")]
+const REPLAY_LOG: &str = include_str!("../../replay.log");
+#[cfg(not(feature = "sched-replay"))]
+const REPLAY_LOG: &str = "";
+
+/// Recorded decisions that have not been dumped yet.
+static RECORDED: Mutex<Option<Vec<Decision>>> = Mutex::new(None);
+
+/// The number of decisions recorded so far.
+static NEXT_SEQ: AtomicUsize = AtomicUsize::new(0);
+
+/// The decisions left to replay.
+static REPLAY: Mutex<Option<Vec<Expected>>> = Mutex::new(None);
+
+/// Are we replaying? This is cleared when the log runs out or the run diverges.
+static REPLAYING: AtomicBool = AtomicBool::new(false);
+
+/// A recorded scheduling decision.
+struct Decision {
+    seq: usize,
+    time: SysTime,
+    cpu: usize,
+    id: ContId,
+    label: &'static str,
+
+    /// The event the continuation was given, as formatted by `describe`.
+    event: String,
+}
+
+/// A scheduling decision to replay.
+pub struct Expected {
+    /// The position in the log, for error messages.
+    pub seq: usize,
+
+    /// The continuation that ran.
+    pub id: ContId,
+
+    /// The event the continuation was given, as formatted by `describe`.
+    pub event: String,
+
+    /// The branch of each `EventKind::Any` that fired, outermost first.
+    pub path: Vec<usize>,
+
+    /// The event from outside the kernel that made the continuation ready, if any.
+    pub input: Option<Input>,
+}
+
+/// An event from outside the kernel that replay delivers itself.
+#[derive(Copy, Clone)]
+pub enum Input {
+    Timer,
+    Keyboard(u8),
+    Irq(u8),
+}
+
+/// Start recording or replaying, if the kernel was built to.
+pub fn init() {
+    if RECORD {
+        *RECORDED.lock() = Some(Vec::with_capacity(CAPACITY));
+        printk!("replay: recording scheduling decisions\n");
+    }
+
+    if cfg!(feature = "sched-replay") {
+        let expected: Vec<_> = REPLAY_LOG.lines().filter_map(parse).collect();
+        printk!(
+            "replay: replaying {} scheduling decisions\n",
+            expected.len()
+        );
+
+        *REPLAY.lock() = Some(expected.into_iter().rev().collect());
+        REPLAYING.store(true, Ordering::Release);
+    }
+}
+
+/// Record that the continuation `id` labelled `label` is about to run with `event`.
+pub fn record(id: ContId, label: &'static str, event: &Event) {
+    if !RECORD || id == ContId::IDLE {
+        return;
+    }
+
+    let decision = Decision {
+        seq: NEXT_SEQ.fetch_add(1, Ordering::Relaxed),
+        time: SysTime::now(),
+        cpu: smp::cpu(),
+        id,
+        label,
+        event: describe(event),
+    };
+
+    let mut recorded = RECORDED.lock();
+    if let Some(recorded) = recorded.as_mut() {
+        recorded.push(decision);
+        if recorded.len() >= CAPACITY {
+            dump_decisions(recorded);
+        }
+    }
+}
+
+/// Dump the decisions recorded since the last dump over the serial port. This is called when the
+/// kernel panics, so it gives up rather than wait for a lock.
+pub fn dump() {
+    if let Some(mut recorded) = RECORDED.try_lock() {
+        if let Some(recorded) = recorded.as_mut() {
+            dump_decisions(recorded);
+        }
+    }
+}
+
+/// Print `recorded` and empty it.
+fn dump_decisions(recorded: &mut Vec<Decision>) {
+    for d in recorded.drain(..) {
+        printk!(
+            "replay: {} {:?} {} {} {} {}\n",
+            d.seq,
+            d.time,
+            d.cpu,
+            d.id,
+            d.event,
+            d.label
+        );
+    }
+}
+
+/// Are we replaying a log?
+pub fn replaying() -> bool {
+    REPLAYING.load(Ordering::Acquire)
+}
+
+/// The next decision to replay, if we are replaying and the log has not run out.
+pub fn expected() -> Option<Expected> {
+    if !replaying() {
+        return None;
+    }
+
+    let next = REPLAY.lock().as_mut().and_then(|replay| replay.pop());
+    if next.is_none() {
+        printk!("replay: reached the end of the log\n");
+        REPLAYING.store(false, Ordering::Release);
+    }
+
+    next
+}
+
+/// The scheduler did not do what the log says (`what`) at decision `seq`. Stop replaying.
+pub fn diverged(seq: usize, what: &str) {
+    printk!("replay: diverged from the log at {}: {}\n", seq, what);
+    REPLAYING.store(false, Ordering::Release);
+}
+
+/// Format `event` for the log. Anything that `parse` needs is included.
+pub fn describe(event: &Event) -> String {
+    match event {
+        Event::Now => "Now".to_string(),
+        Event::Keyboard(c) => format!("Keyboard({})", c),
+        Event::Timer => "Timer".to_string(),
+        Event::TaskExited { code } => format!("TaskExited({})", code),
+        Event::Message(_) => "Message".to_string(),
+        Event::MessageSent => "MessageSent".to_string(),
+        Event::Irq(irq) => format!("Irq({})", irq),
+        Event::Joined(results) => format!("Joined({})", results.len()),
+        // Log entries are split on whitespace, so errors (e.g. `BadElf`) must not contain any.
+        Event::Error(err) => format!("Error({:?})", err).replace(' ', "_"),
+        Event::Any { branch, event } => format!("Any({},{})", branch, describe(event)),
+    }
+}
+
+/// Parse a line of the log: `replay: <seq> <time> <cpu> <id> <event> <label>`. Other lines are
+/// ignored, so the whole serial output can be used as the log.
+fn parse(line: &str) -> Option<Expected> {
+    let mut fields = line.trim().strip_prefix("replay:")?.split_whitespace();
+
+    let seq = fields.next()?.parse().ok()?;
+    let _time = fields.next()?;
+    let _cpu = fields.next()?;
+    let id = ContId::from_raw(fields.next()?.parse().ok()?);
+    let event = fields.next()?.to_string();
+
+    // Peel off the `Any`s to find which branches fired and what the innermost event was.
+    let mut path = Vec::new();
+    let mut inner = event.as_str();
+    while let Some(rest) = inner.strip_prefix("Any(") {
+        let rest = rest.strip_suffix(')')?;
+        let comma = rest.find(',')?;
+        path.push(rest[..comma].parse().ok()?);
+        inner = &rest[comma + 1..];
+    }
+
+    let input = if inner == "Timer" {
+        Some(Input::Timer)
+    } else if let Some(c) = inner.strip_prefix("Keyboard(") {
+        Some(Input::Keyboard(c.strip_suffix(')')?.parse().ok()?))
+    } else if let Some(irq) = inner.strip_prefix("Irq(") {
+        Some(Input::Irq(irq.strip_suffix(')')?.parse().ok()?))
+    } else {
+        None
+    };
+
+    Some(Expected {
+        seq,
+        id,
+        event,
+        path,
+        input,
+    })
+}
@@ -0,0 +1,310 @@
+//! CPU accounting for continuations and user tasks, for finding out who is eating the CPU.
+//!
+//! Runtime is measured with the TSC from the moment a continuation starts running until its core
+//! calls `sched` again. Continuations are accounted by label, since every step of a computation is
+//! a new continuation. The time a user task runs is also charged to the task. Wait times are
+//! measured from when a continuation is enqueued until it starts running, and are broken down by
+//! the `EventKind` it waited for.
+//!
+//! User tasks get the statistics as a `UserStats` from the `SYS_SCHED_STATS` system call.
+
+use alloc::collections::BTreeMap;
+
+use spin::Mutex;
+
+use crate::{
+    continuation::ContId,
+    smp::PerCpu,
+    time::{cycles_to_us, SysTime},
+};
+
+use super::user::TaskHandle;
+
+/// The longest label or `EventKind` name in a `UserStats`. Longer names are cut short.
+pub const USER_NAME_LEN: usize = 24;
+
+/// The number of continuation labels that fit in a `UserStats`.
+pub const USER_LABELS: usize = 16;
+
+/// The number of `EventKind`s that fit in a `UserLabelStats`. This is more than there are.
+pub const USER_WAITS: usize = 16;
+
+/// The statistics collected so far. Created by `init`.
+static STATS: Mutex<Option<Stats>> = Mutex::new(None);
+
+/// What each core is running, so that its runtime can be charged when it stops.
+static RUNNING: PerCpu<Option<Running>> = PerCpu::new();
+
+/// Statistics for all continuations and user tasks.
+struct Stats {
+    /// Statistics for continuations, by label.
+    conts: BTreeMap<&'static str, ContStats>,
+
+    /// Statistics for user tasks.
+    tasks: BTreeMap<TaskHandle, TaskStats>,
+
+    /// The number of times any core went idle.
+    idle_loops: usize,
+}
+
+/// Statistics for the continuations with some label.
+#[derive(Default)]
+struct ContStats {
+    /// The number of times a continuation with this label ran.
+    runs: usize,
+
+    /// The total time continuations with this label ran, in TSC cycles.
+    runtime: u64,
+
+    /// How often and how long continuations with this label waited, by the name of the
+    /// `EventKind` they waited for.
+    waits: BTreeMap<&'static str, WaitStats>,
+}
+
+/// Statistics for the waits on some kind of event.
+#[derive(Default)]
+struct WaitStats {
+    /// The number of waits.
+    count: usize,
+
+    /// The total time waited, in TSC cycles.
+    time: u64,
+}
+
+/// Statistics for a user task.
+#[derive(Default)]
+struct TaskStats {
+    /// The number of times the task was scheduled, i.e. started or resumed after preemption.
+    runs: usize,
+
+    /// The total time the task ran (including system calls), in TSC cycles.
+    runtime: u64,
+}
+
+/// The statistics given to a user task by `SYS_SCHED_STATS`. Times are in microseconds, and names
+/// are padded with NULs.
+#[repr(C)]
+pub struct UserStats {
+    /// The number of times any core went idle.
+    pub idle_loops: u64,
+
+    /// The number of times the calling task was scheduled.
+    pub task_runs: u64,
+
+    /// The total time the calling task ran.
+    pub task_runtime_us: u64,
+
+    /// The number of entries of `labels` that are filled in. Labels that don't fit are left out.
+    pub nlabels: u64,
+
+    /// Statistics for continuations, by label.
+    pub labels: [UserLabelStats; USER_LABELS],
+}
+
+/// The statistics for the continuations with some label in a `UserStats`.
+#[repr(C)]
+pub struct UserLabelStats {
+    pub label: [u8; USER_NAME_LEN],
+
+    /// The number of times a continuation with this label ran.
+    pub runs: u64,
+
+    /// The total time continuations with this label ran.
+    pub runtime_us: u64,
+
+    /// The number of entries of `waits` that are filled in.
+    pub nwaits: u64,
+
+    /// How often and how long continuations with this label waited, by `EventKind`.
+    pub waits: [UserWaitStats; USER_WAITS],
+}
+
+/// The waits on some kind of event in a `UserLabelStats`.
+#[repr(C)]
+pub struct UserWaitStats {
+    /// The name of the `EventKind`.
+    pub kind: [u8; USER_NAME_LEN],
+
+    /// The number of waits.
+    pub count: u64,
+
+    /// The total time waited.
+    pub time_us: u64,
+}
+
+/// What a core is running.
+struct Running {
+    /// The id of the running continuation.
+    id: ContId,
+
+    /// The label of the running continuation.
+    label: &'static str,
+
+    /// The user task the continuation is running, if any.
+    task: Option<TaskHandle>,
+
+    /// When the continuation started running.
+    since: SysTime,
+}
+
+/// Start collecting statistics.
+pub fn init() {
+    *STATS.lock() = Some(Stats {
+        conts: BTreeMap::new(),
+        tasks: BTreeMap::new(),
+        idle_loops: 0,
+    });
+}
+
+/// The continuation `id` labelled `label` is starting to run on this core. `waited` is the name of
+/// the `EventKind` it waited for and when it started waiting, if it was enqueued in the scheduler.
+pub fn started(id: ContId, label: &'static str, waited: Option<(&'static str, SysTime)>) {
+    let now = SysTime::now();
+
+    if let Some(stats) = STATS.lock().as_mut() {
+        let cont = stats.conts.entry(label).or_default();
+        cont.runs += 1;
+
+        if let Some((kind, since)) = waited {
+            let wait = cont.waits.entry(kind).or_default();
+            wait.count += 1;
+            wait.time += now.cycles_since(since);
+        }
+    }
+
+    *RUNNING.get().lock() = Some(Running {
+        id,
+        label,
+        task: None,
+        since: now,
+    });
+}
+
+/// The continuation running on this core is starting or resuming the user task `task`.
+pub fn task_started(task: TaskHandle) {
+    if let Some(running) = RUNNING.get().lock().as_mut() {
+        running.task = Some(task);
+    }
+
+    if let Some(stats) = STATS.lock().as_mut() {
+        stats.tasks.entry(task).or_default().runs += 1;
+    }
+}
+
+/// The continuation running on this core (if any) has stopped running. Charge its runtime.
+pub fn stopped() {
+    let running = match RUNNING.get().lock().take() {
+        Some(running) => running,
+        None => return,
+    };
+
+    let runtime = SysTime::now().cycles_since(running.since);
+
+    if let Some(stats) = STATS.lock().as_mut() {
+        stats.conts.entry(running.label).or_default().runtime += runtime;
+
+        if let Some(task) = running.task {
+            stats.tasks.entry(task).or_default().runtime += runtime;
+        }
+    }
+}
+
+/// The id and label of the continuation running on this core, if any. This is meant for fault
+/// handlers, so it gives up rather than wait for a lock.
+pub fn running() -> Option<(ContId, &'static str)> {
+    RUNNING
+        .get()
+        .try_lock()
+        .and_then(|running| running.as_ref().map(|running| (running.id, running.label)))
+}
+
+/// This core is going idle.
+pub fn idle_loop() {
+    if let Some(stats) = STATS.lock().as_mut() {
+        stats.idle_loops += 1;
+    }
+}
+
+/// Fill in `out` with the statistics for the user task `task`. `out` is filled in place, since it
+/// is too big to build on the stack.
+pub fn write_user(task: TaskHandle, out: &mut UserStats) {
+    let stats = STATS.lock();
+    let stats = match stats.as_ref() {
+        Some(stats) => stats,
+        None => {
+            out.idle_loops = 0;
+            out.task_runs = 0;
+            out.task_runtime_us = 0;
+            out.nlabels = 0;
+            return;
+        }
+    };
+
+    let task = stats.tasks.get(&task);
+    out.idle_loops = stats.idle_loops as u64;
+    out.task_runs = task.map_or(0, |task| task.runs as u64);
+    out.task_runtime_us = task.map_or(0, |task| cycles_to_us(task.runtime));
+
+    let labels = stats.conts.iter().zip(out.labels.iter_mut());
+    out.nlabels = labels.len() as u64;
+    for ((label, cont), out) in labels {
+        copy_name(&mut out.label, label);
+        out.runs = cont.runs as u64;
+        out.runtime_us = cycles_to_us(cont.runtime);
+
+        let waits = cont.waits.iter().zip(out.waits.iter_mut());
+        out.nwaits = waits.len() as u64;
+        for ((kind, wait), out) in waits {
+            copy_name(&mut out.kind, kind);
+            out.count = wait.count as u64;
+            out.time_us = cycles_to_us(wait.time);
+        }
+    }
+}
+
+/// Copy `name` into `out`, cutting it short or padding it with NULs as needed.
+fn copy_name(out: &mut [u8; USER_NAME_LEN], name: &str) {
+    let len = name.len().min(USER_NAME_LEN);
+    out[..len].copy_from_slice(&name.as_bytes()[..len]);
+    for byte in out[len..].iter_mut() {
+        *byte = 0;
+    }
+}
+
+/// Print all statistics over the serial port.
+pub fn dump() {
+    let stats = STATS.lock();
+    let stats = match stats.as_ref() {
+        Some(stats) => stats,
+        None => return,
+    };
+
+    printk!("scheduler stats: {} idle loops\n", stats.idle_loops);
+
+    for (label, cont) in stats.conts.iter() {
+        printk!(
+            "\tcont {:?}: {} runs, {} us\n",
+            label,
+            cont.runs,
+            cycles_to_us(cont.runtime)
+        );
+
+        for (kind, wait) in cont.waits.iter() {
+            printk!(
+                "\t\twaited on {}: {} times, {} us\n",
+                kind,
+                wait.count,
+                cycles_to_us(wait.time)
+            );
+        }
+    }
+
+    for (task, stats) in stats.tasks.iter() {
+        printk!(
+            "\ttask {:?}: {} runs, {} us\n",
+            task,
+            stats.runs,
+            cycles_to_us(stats.runtime)
+        );
+    }
+}
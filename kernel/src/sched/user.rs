@@ -1,26 +1,61 @@
 //! System calls and kernel <-> user mode switching...
 
 use alloc::collections::BTreeMap;
+use alloc::vec;
 use alloc::vec::Vec;
 
+use core::{
+    mem,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
 use elfloader::{ElfBinary, ElfLoader, LoadableHeaders, Rela, TypeRela64, VAddr, P64};
 
+use spin::Mutex;
+
 use x86_64::{
     registers::{
         model_specific::{Efer, EferFlags, Msr},
         rflags::{self, RFlags},
     },
-    structures::paging::{PageSize, PageTableFlags, Size4KiB},
+    structures::{
+        idt::InterruptStackFrame,
+        paging::{PageSize, PageTableFlags, Size4KiB},
+    },
+    VirtAddr,
 };
 
 use crate::{
-    cap::ResourceHandle,
+    cap::{self, HandleTable, ResourceHandle},
+    continuation::{Continuation, EventKind},
+    error::KernelError,
     interrupts::SELECTORS,
-    memory::{map_region, VirtualMemoryRegion},
+    memory::{map_region, populate_region, rights_needed, FrameQuota, VirtualMemoryRegion},
+    smp::{self, PerCpu},
 };
 
 const USER_STACK_SIZE: usize = 1; // pages
 
+/// The number of frames of RAM that each task may have allocated with `SYS_FRAME_ALLOC` at once
+/// (4MiB).
+const TASK_FRAME_QUOTA: usize = 1024;
+
+/// The next task handle to be handed out.
+static NEXT_TASK: AtomicUsize = AtomicUsize::new(0);
+
+/// The user task that is currently running (or was most recently running) on each core, if any.
+static CURRENT_TASK: PerCpu<Option<TaskHandle>> = PerCpu::new();
+
+/// The handle table of the user task that is currently running on each core, if any. It moves
+/// along with the task when the task blocks or is preempted.
+static HANDLES: PerCpu<Option<HandleTable>> = PerCpu::new();
+
+/// The frame quota of each task that has allocated frames, until it exits.
+static FRAME_QUOTAS: Mutex<Option<BTreeMap<TaskHandle, FrameQuota>>> = Mutex::new(None);
+
+/// The error that the user task on each core faulted with, on its way to `faulted`.
+static FAULT: PerCpu<Option<KernelError>> = PerCpu::new();
+
 // Some MSRs used for system call handling.
 
 /// Contains the stack and code segmets for syscall/sysret.
@@ -32,7 +67,10 @@ const LSTAR: Msr = Msr::new(0xC000_0082);
 /// Contains the kernel rflags mask for syscall.
 const FMASK: Msr = Msr::new(0xC000_0084);
 
-#[derive(Debug, Default)]
+/// Swapped with the GS base by `swapgs`. We use it to find the current core's kernel stack.
+const KERNEL_GS_BASE: Msr = Msr::new(0xC000_0102);
+
+#[derive(Clone, Debug, Default)]
 #[repr(C)]
 struct SavedRegs {
     pub rax: u64,
@@ -57,15 +95,31 @@ struct SavedRegs {
     pub rsp: u64,
 }
 
+/// Identifies a user task. Continuations can wait for a task to exit with
+/// `EventKind::TaskExit(handle)`.
+#[derive(Copy, Clone, Debug, Eq, Ord, PartialEq, PartialOrd)]
+pub struct TaskHandle(usize);
+
+impl TaskHandle {
+    /// Returns a fresh handle for a new user task.
+    pub fn new() -> Self {
+        TaskHandle(NEXT_TASK.fetch_add(1, Ordering::Relaxed))
+    }
+}
+
 /// An ELF loader that loads binaries for execution in userspace.
 struct KElfLoader {
     /// Base virtual address for the binary. All addresses in the binary are offset by this
     /// address.
     vbase: u64,
 
-    /// Resource handles for all code sections loaded, indexed by starting address of the ELF
-    /// region in memory.
-    user_code_sections: BTreeMap<u64, ResourceHandle>,
+    /// Resource handles for all code sections loaded, along with the page table flags their program
+    /// headers ask for, indexed by starting address of the ELF region in memory.
+    user_code_sections: BTreeMap<u64, (ResourceHandle, PageTableFlags)>,
+
+    /// Why loading failed, if it was for lack of memory. `ElfLoader` methods can only fail with a
+    /// string, so this lets `load_user_elf` return the actual error.
+    error: Option<KernelError>,
 }
 
 impl KElfLoader {
@@ -73,28 +127,31 @@ impl KElfLoader {
         KElfLoader {
             vbase: crate::memory::AVAILABLE_VADDR_START,
             user_code_sections: BTreeMap::new(),
+            error: None,
         }
     }
 
-    /// Get the address at which `raw_address` has been loaded.
-    pub fn compute_loaded_address(&self, address: u64) -> u64 {
-        let (base, loaded_base) = self
+    /// Get the address at which `raw_address` has been loaded, or `BadElf` if it is not in any of
+    /// the loaded sections.
+    pub fn compute_loaded_address(&self, address: u64) -> Result<u64, KernelError> {
+        let (base, (section, _)) = self
             .user_code_sections
             .range(((address >> 12) << 12)..=address)
             .next()
-            .map(|(base, address)| {
-                (
-                    base,
-                    address.with(|cap| cap_unwrap!(VirtualMemoryRegion(cap)).start()),
-                )
-            })
-            .unwrap();
+            .ok_or(KernelError::BadElf("Address is not in a loaded section"))?;
+        let loaded_base = section.with(|cap| cap_unwrap!(VirtualMemoryRegion(cap)).start())?;
 
         let diff = address - base;
 
         let start = unsafe { loaded_base.add(diff as usize) };
 
-        start as u64
+        Ok(start as u64)
+    }
+
+    /// Remember `error` for `load_user_elf`, and turn it into a string for `ElfLoader`.
+    fn fail(&mut self, error: KernelError) -> &'static str {
+        self.error = Some(error);
+        "Out of memory"
     }
 }
 
@@ -107,18 +164,30 @@ impl ElfLoader for KElfLoader {
             } else {
                 (size >> 12) + 1
             };
-            let user_code_section = VirtualMemoryRegion::alloc_with_guard(size as usize).register();
+            let user_code_section = match VirtualMemoryRegion::alloc_with_guard(size as usize) {
+                Ok(section) => section.register(),
+                Err(err) => return Err(self.fail(err)),
+            };
 
-            // Map the code section.
+            // Map the code section writable for now, so that it can be loaded.
             map_region(
-                user_code_section,
+                &user_code_section,
                 PageTableFlags::PRESENT
                     | PageTableFlags::WRITABLE
                     | PageTableFlags::USER_ACCESSIBLE,
-            );
+            )
+            .map_err(|_| "Code section revoked")?;
+
+            let mut flags = PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE;
+            if header.flags().is_write() {
+                flags |= PageTableFlags::WRITABLE;
+            }
+            if !header.flags().is_execute() {
+                flags |= PageTableFlags::NO_EXECUTE;
+            }
 
             self.user_code_sections
-                .insert(header.virtual_addr(), user_code_section);
+                .insert(header.virtual_addr(), (user_code_section, flags));
         }
 
         Ok(())
@@ -143,15 +212,22 @@ impl ElfLoader for KElfLoader {
     }
 
     fn load(&mut self, base: VAddr, region: &[u8]) -> Result<(), &'static str> {
-        let user_code_section = self.user_code_sections[&base];
+        let (user_code_section, _) = &self.user_code_sections[&base];
+
+        // Map the memory before writing it, so that running out doesn't happen in a page fault.
+        if let Err(err) = populate_region(user_code_section) {
+            return Err(self.fail(err));
+        }
 
         // Load the segment at base + self.vbase
-        user_code_section.with(|cap| unsafe {
-            let start = cap_unwrap!(VirtualMemoryRegion(cap)).start();
-            for (i, b) in region.iter().enumerate() {
-                start.offset(i as isize).write(*b);
-            }
-        });
+        user_code_section
+            .with(|cap| unsafe {
+                let start = cap_unwrap!(VirtualMemoryRegion(cap)).start();
+                for (i, b) in region.iter().enumerate() {
+                    start.offset(i as isize).write(*b);
+                }
+            })
+            .map_err(|_| "Code section revoked")?;
 
         Ok(())
     }
@@ -162,22 +238,34 @@ impl ElfLoader for KElfLoader {
 /// magic bytes, headers, text, etc.
 ///
 /// Returns the virtual address regions where the code has been loaded and the first RIP to start
-/// executing.
-pub fn load_user_elf(binary: &[u8]) -> (Vec<ResourceHandle>, u64) {
+/// executing, `BadElf` if the binary could not be loaded, or `OutOfVirtualMemory` or
+/// `OutOfPhysicalMemory` if there was not enough memory to load it. The regions are mapped, and the
+/// handles on them only have the rights that the program headers ask for (e.g. read/execute for
+/// code).
+pub fn load_user_elf(binary: &[u8]) -> Result<(Vec<ResourceHandle>, u64), KernelError> {
     let mut loader = KElfLoader::new();
-    let bin = ElfBinary::new("user", binary).expect("Not an ELF binary");
-    bin.load(&mut loader).expect("Unable to load ELF binary");
-
-    let entry = loader.compute_loaded_address(bin.entry_point());
+    let bin = ElfBinary::new("user", binary).map_err(KernelError::BadElf)?;
+    if let Err(reason) = bin.load(&mut loader) {
+        return Err(loader.error.take().unwrap_or(KernelError::BadElf(reason)));
+    }
 
-    (
-        loader
-            .user_code_sections
-            .into_iter()
-            .map(|(_, rh)| rh)
-            .collect(),
-        entry,
-    )
+    let entry = loader.compute_loaded_address(bin.entry_point())?;
+
+    // Hand out weaker handles, and get rid of our own, so that the sections are freed once the
+    // caller destroys the handles it gets. Now that the sections are loaded, they only need the
+    // access their program headers ask for.
+    let sections = loader
+        .user_code_sections
+        .into_iter()
+        .map(|(_, (section, flags))| {
+            let derived = cap::derive(&section, rights_needed(flags))?;
+            cap::destroy(&section)?;
+            map_region(&derived, flags)?;
+            Ok(derived)
+        })
+        .collect::<Result<_, KernelError>>()?;
+
+    Ok((sections, entry))
 }
 
 /// Allocates virtual address space for the user stack (fixed size). Adds appropriate page table
@@ -186,23 +274,24 @@ pub fn load_user_elf(binary: &[u8]) -> (Vec<ResourceHandle>, u64) {
 /// Returns the virtual address region of the stack. The first and last pages are left unmapped as
 /// guard pages. The stack should be used from the end (high-addresses) of the region (top of
 /// stack), since it grows downward.
-pub fn allocate_user_stack() -> ResourceHandle {
+pub fn allocate_user_stack() -> Result<ResourceHandle, KernelError> {
     // Allocate the stack the user will run on.
-    let user_stack = VirtualMemoryRegion::alloc_with_guard(USER_STACK_SIZE).register();
+    let user_stack = VirtualMemoryRegion::alloc_with_guard(USER_STACK_SIZE)?.register();
 
     // Map the stack into the address space.
     map_region(
-        user_stack,
+        &user_stack,
         PageTableFlags::PRESENT
             | PageTableFlags::WRITABLE
             | PageTableFlags::USER_ACCESSIBLE
             | PageTableFlags::NO_EXECUTE,
-    );
+    )?;
 
-    user_stack
+    Ok(user_stack)
 }
 
-/// Set some MSRs, registers to enable syscalls and user/kernel context switching.
+/// Set some MSRs, registers to enable syscalls and user/kernel context switching. Every core needs
+/// to do this.
 pub fn init() {
     unsafe {
         // Need to set IA32_EFER.SCE
@@ -228,15 +317,27 @@ pub fn init() {
         //
         // Want to disable interrupt until we switch to the kernel stack.
         FMASK.write(RFlags::INTERRUPT_FLAG.bits());
+
+        // KERNEL_GS_BASE: this core's kernel stack head
+        KERNEL_GS_BASE.write(&super::STACK_HEADS[smp::cpu()] as *const u64 as u64);
     }
 }
 
-pub fn start_user_task(start_rip: u64, start_rsp: u64) -> ! {
+/// Start running the user task `task` at the given `start_rip` with the given `start_rsp`. The task
+/// can name the handles in `handles` (which the current continuation must hold) and nothing else.
+/// When the task exits, any continuations waiting on `EventKind::TaskExit(task)` are woken up.
+pub fn start_user_task(
+    task: TaskHandle,
+    handles: HandleTable,
+    start_rip: u64,
+    start_rsp: u64,
+) -> ! {
     // Enable interrupts for user mode.
     let rflags = (rflags::read() | rflags::RFlags::INTERRUPT_FLAG).bits();
 
     printk!(
-        "Starting user task at rip={:x}, rsp={:x}\n",
+        "Starting user task {:?} at rip={:x}, rsp={:x}\n",
+        task,
         start_rip,
         start_rsp
     );
@@ -249,26 +350,572 @@ pub fn start_user_task(start_rip: u64, start_rsp: u64) -> ! {
         ..SavedRegs::default()
     };
 
-    syscall::switch_to_user(&registers)
+    resume_user_task(task, handles, &registers)
+}
+
+/// Resume running the user task `task` with its handle table and the given registers, e.g. after it
+/// was preempted.
+fn resume_user_task(task: TaskHandle, handles: HandleTable, registers: &SavedRegs) -> ! {
+    *CURRENT_TASK.get().lock() = Some(task);
+    *HANDLES.get().lock() = Some(handles);
+    preempt::start_slice();
+    super::stats::task_started(task);
+
+    syscall::switch_to_user(registers)
+}
+
+/// Make the calling user task wait for `kind`, and schedule something else. When the event
+/// happens, the task is resumed with `registers` by a continuation labelled `label`. This is how
+/// system calls block, and how preempted tasks give up their core.
+fn block(registers: SavedRegs, kind: EventKind, label: &'static str) -> ! {
+    let task = CURRENT_TASK
+        .get()
+        .lock()
+        .take()
+        .expect("blocking without a current task");
+
+    // The task keeps its capabilities and handle table while it waits.
+    let caps = cap::leave();
+    let mut handles = take_handles();
+    let cont =
+        Continuation::new(move |_| resume_user_task(task, mem::take(&mut handles), &registers))
+            .with_label(label)
+            .with_caps(caps.iter().cloned().collect());
+
+    super::enqueue(vec![(kind, cont)]);
+    super::sched()
+}
+
+/// Take the handle table of the user task that was running on this core.
+fn take_handles() -> HandleTable {
+    HANDLES
+        .get()
+        .lock()
+        .take()
+        .expect("no handle table for the current task")
+}
+
+/// The quota that the frames allocated by `task` are charged to. Each task gets its own, of
+/// `TASK_FRAME_QUOTA` frames.
+fn frame_quota(task: TaskHandle) -> FrameQuota {
+    FRAME_QUOTAS
+        .lock()
+        .get_or_insert_with(BTreeMap::new)
+        .entry(task)
+        .or_insert_with(|| FrameQuota::new(TASK_FRAME_QUOTA))
+        .clone()
+}
+
+/// End the user task `task`, which is no longer running, with the exit code `code`, and schedule
+/// something else. Frames it allocated that are still around (e.g. because it passed them on)
+/// stay charged to its quota, but nothing can allocate from that quota any more.
+fn end_task(task: TaskHandle, code: isize) -> ! {
+    printk!("Task {:?} completed with code {}.\n", task, code);
+
+    // The task can't name its handles anymore. Whoever started it still holds them.
+    drop(take_handles());
+
+    if let Some(quotas) = FRAME_QUOTAS.lock().as_mut() {
+        quotas.remove(&task);
+    }
+
+    super::task_exited(task, code);
+    super::sched()
+}
+
+/// The user task running on this core took a fault it can't recover from with the error `err`
+/// (e.g. there was no memory for a page it touched). Return from the fault to `faulted` in kernel
+/// mode rather than to the task. `esf` must be from user mode.
+pub fn fault(esf: &mut InterruptStackFrame, err: KernelError) {
+    *FAULT.get().lock() = Some(err);
+
+    // Return on this core's kernel stack with interrupts disabled, as `preempt` does.
+    let selectors = SELECTORS.lock();
+    let stack_head = unsafe { super::STACK_HEADS[smp::cpu()] };
+
+    unsafe {
+        let frame = esf.as_mut();
+        frame.instruction_pointer = VirtAddr::new(faulted as u64);
+        frame.code_segment = selectors.kernel_cs.0 as u64;
+        frame.cpu_flags = 0x2;
+        frame.stack_pointer = VirtAddr::new((stack_head & !0xF) - 8);
+        frame.stack_segment = selectors.kernel_ds.0 as u64;
+    }
+}
+
+/// The user task on this core faulted. End it with the status code of the error as its exit
+/// code, as a system call would have returned it (e.g. `ERR_NO_MEMORY`). Interrupts are disabled
+/// on entry.
+extern "C" fn faulted() -> ! {
+    let err = FAULT.get().lock().take().expect("faulted without an error");
+    let task = CURRENT_TASK
+        .get()
+        .lock()
+        .take()
+        .expect("fault without a current task");
+
+    smp::set_user(false);
+    x86_64::instructions::interrupts::enable();
+
+    printk!("Task {:?} faulted: {:?}\n", task, err);
+    end_task(task, syscall::error_code(err) as isize)
+}
+
+pub mod preempt {
+    //! Preemption of user tasks.
+    //!
+    //! The timer interrupt (on the bootstrap core) and the preemption IPI (on the other cores) save
+    //! all registers in a `TrapFrame`. If the interrupted code was a user task whose time slice is
+    //! over, the handler keeps the task's registers and returns to `preempted` in kernel mode
+    //! rather than to the task. `preempted` turns the task into a continuation that resumes it, and
+    //! then calls the scheduler.
+    //!
+    //! Each core has its own time slice, which starts whenever a user task is started or resumed
+    //! on it, and the timer is armed for its end. Only the bootstrap core gets timer interrupts, so
+    //! it checks the slices of the other cores and sends the preemption IPI to those whose slice is
+    //! over.
+
+    use x86_64::{instructions::interrupts, structures::idt::HandlerFunc};
+
+    use crate::{
+        continuation::EventKind,
+        interrupts::SELECTORS,
+        smp::{self, PerCpu},
+        time::{self, SysTime},
+    };
+
+    use super::{block, SavedRegs};
+
+    /// The length of a time slice, in milliseconds.
+    const TIME_SLICE_MS: usize = 10;
+
+    /// When the time slice of the user task running on each core ends.
+    static SLICE_END: PerCpu<Option<SysTime>> = PerCpu::new();
+
+    /// The registers of the user task that was just preempted on each core, on their way to
+    /// `preempted`.
+    static PREEMPTED: PerCpu<Option<SavedRegs>> = PerCpu::new();
+
+    /// The registers saved by the entry points below, followed by the interrupt stack frame pushed
+    /// by the hardware.
+    #[repr(C)]
+    struct TrapFrame {
+        pub rax: u64,
+        pub rbx: u64,
+        pub rcx: u64,
+        pub rdx: u64,
+        pub rdi: u64,
+        pub rsi: u64,
+        pub rbp: u64,
+        pub r8: u64,
+        pub r9: u64,
+        pub r10: u64,
+        pub r11: u64,
+        pub r12: u64,
+        pub r13: u64,
+        pub r14: u64,
+        pub r15: u64,
+
+        pub rip: u64,
+        pub cs: u64,
+        pub rflags: u64,
+        pub rsp: u64,
+        pub ss: u64,
+    }
+
+    /// The IDT handler for the timer interrupt.
+    pub fn timer_handler() -> HandlerFunc {
+        // The entry point is not really an `x86-interrupt` function, but it behaves like one.
+        unsafe { core::mem::transmute(timer_entry as unsafe extern "C" fn()) }
+    }
+
+    /// The IDT handler for the preemption IPI.
+    pub fn ipi_handler() -> HandlerFunc {
+        // The entry point is not really an `x86-interrupt` function, but it behaves like one.
+        unsafe { core::mem::transmute(ipi_entry as unsafe extern "C" fn()) }
+    }
+
+    /// Entry point of the timer interrupt. Saves all registers and calls `handle_timer_trap`.
+    #[naked]
+    unsafe extern "C" fn timer_entry() {
+        asm!(
+            "
+            pushq %r15
+            pushq %r14
+            pushq %r13
+            pushq %r12
+            pushq %r11
+            pushq %r10
+            pushq %r9
+            pushq %r8
+            pushq %rbp
+            pushq %rsi
+            pushq %rdi
+            pushq %rdx
+            pushq %rcx
+            pushq %rbx
+            pushq %rax
+
+            # the registers and the interrupt stack frame are at the top of the stack
+            mov %rsp, %rdi
+            call handle_timer_trap
+
+            popq %rax
+            popq %rbx
+            popq %rcx
+            popq %rdx
+            popq %rdi
+            popq %rsi
+            popq %rbp
+            popq %r8
+            popq %r9
+            popq %r10
+            popq %r11
+            popq %r12
+            popq %r13
+            popq %r14
+            popq %r15
+
+            iretq
+            "
+            : /* no outputs */
+            : /* no inputs */
+            : "memory"
+            : "volatile"
+        );
+    }
+
+    /// Entry point of the preemption IPI. Saves all registers and calls `handle_preempt_trap`.
+    #[naked]
+    unsafe extern "C" fn ipi_entry() {
+        asm!(
+            "
+            pushq %r15
+            pushq %r14
+            pushq %r13
+            pushq %r12
+            pushq %r11
+            pushq %r10
+            pushq %r9
+            pushq %r8
+            pushq %rbp
+            pushq %rsi
+            pushq %rdi
+            pushq %rdx
+            pushq %rcx
+            pushq %rbx
+            pushq %rax
+
+            # the registers and the interrupt stack frame are at the top of the stack
+            mov %rsp, %rdi
+            call handle_preempt_trap
+
+            popq %rax
+            popq %rbx
+            popq %rcx
+            popq %rdx
+            popq %rdi
+            popq %rsi
+            popq %rbp
+            popq %r8
+            popq %r9
+            popq %r10
+            popq %r11
+            popq %r12
+            popq %r13
+            popq %r14
+            popq %r15
+
+            iretq
+            "
+            : /* no outputs */
+            : /* no inputs */
+            : "memory"
+            : "volatile"
+        );
+    }
+
+    /// Start a new time slice on this core for the user task that is about to run on it.
+    pub(super) fn start_slice() {
+        // Preemption can't be replayed, so user tasks run until they block while recording or
+        // replaying (see `sched::replay`).
+        if cfg!(any(feature = "sched-record", feature = "sched-replay")) {
+            return;
+        }
+
+        // The timer interrupt must not find the lock held on this core.
+        interrupts::without_interrupts(|| {
+            *SLICE_END.get().lock() = Some(SysTime::now().after_ms(TIME_SLICE_MS));
+        });
+    }
+
+    /// Make sure the timer fires at the end of this core's time slice. This is called whenever a
+    /// core enters user mode, including when a system call returns after the slice is over.
+    pub(super) fn arm_slice() {
+        let end = interrupts::without_interrupts(|| *SLICE_END.get().lock());
+        if let Some(end) = end {
+            time::arm(end);
+        }
+    }
+
+    /// Is the time slice on the given core over? A slice that is being started right now is not.
+    fn slice_over(cpu: usize) -> bool {
+        SLICE_END.all()[cpu]
+            .try_lock()
+            .map_or(false, |end| end.map_or(false, |end| end <= SysTime::now()))
+    }
+
+    /// Handle the timer interrupt. Preempt any user tasks whose time slice is over.
+    #[no_mangle]
+    extern "C" fn handle_timer_trap(frame: &mut TrapFrame) {
+        crate::interrupts::timer_irq();
+
+        // The other cores don't get timer interrupts, so tell them.
+        smp::preempt_user(slice_over);
+        preempt(frame);
+
+        // The timer may have been armed for something else, so arm it again for the end of the
+        // slices that are not over yet.
+        let now = SysTime::now();
+        for end in SLICE_END.all().iter() {
+            match end.try_lock().and_then(|end| *end) {
+                Some(end) if end > now => time::arm(end),
+                _ => {}
+            }
+        }
+    }
+
+    /// Handle the preemption IPI sent by the bootstrap core at the end of this core's time slice.
+    #[no_mangle]
+    extern "C" fn handle_preempt_trap(frame: &mut TrapFrame) {
+        smp::ack_ipi();
+        preempt(frame);
+    }
+
+    /// If `frame` is from a user task whose time slice is over, save its registers and return to
+    /// `preempted` instead.
+    fn preempt(frame: &mut TrapFrame) {
+        // Only user tasks are preempted. If the task is in a system call, its slice stays over, so
+        // it is preempted on a later tick.
+        if frame.cs & 3 != 3 || !slice_over(smp::cpu()) {
+            return;
+        }
+        *SLICE_END.get().lock() = None;
+
+        *PREEMPTED.get().lock() = Some(SavedRegs {
+            rax: frame.rax,
+            rbx: frame.rbx,
+            rcx: frame.rcx,
+            rdx: frame.rdx,
+            rdi: frame.rdi,
+            rsi: frame.rsi,
+            rbp: frame.rbp,
+            r8: frame.r8,
+            r9: frame.r9,
+            r10: frame.r10,
+            r11: frame.r11,
+            r12: frame.r12,
+            r13: frame.r13,
+            r14: frame.r14,
+            r15: frame.r15,
+
+            rflags: frame.rflags,
+            rip: frame.rip,
+
+            rsp: frame.rsp,
+        });
+
+        // Return to `preempted` in kernel mode on this core's kernel stack, with interrupts
+        // disabled (only the reserved bit 1 of rflags is set). The stack is misaligned by a word,
+        // as if `preempted` had been called.
+        let selectors = SELECTORS.lock();
+        let stack_head = unsafe { super::super::STACK_HEADS[smp::cpu()] };
+
+        frame.rip = preempted as u64;
+        frame.cs = selectors.kernel_cs.0 as u64;
+        frame.rflags = 0x2;
+        frame.rsp = (stack_head & !0xF) - 8;
+        frame.ss = selectors.kernel_ds.0 as u64;
+    }
+
+    /// A user task was preempted. Make it into a continuation that resumes it, and schedule
+    /// something else. Interrupts are disabled on entry.
+    extern "C" fn preempted() -> ! {
+        let registers = PREEMPTED
+            .get()
+            .lock()
+            .take()
+            .expect("preempted without saved registers");
+
+        smp::set_user(false);
+        x86_64::instructions::interrupts::enable();
+
+        // The task is ready to run again right away.
+        block(registers, EventKind::Now, "preempted")
+    }
 }
 
 mod syscall {
     //! System call handling.
 
-    use super::SavedRegs;
+    use core::mem;
+
+    use x86_64::structures::paging::{PageSize, PageTableFlags, Size4KiB};
+
+    use crate::{
+        cap::{
+            self, Capability, CapabilityType, ResourceHandle, Rights, TableEntry,
+            UnregisteredResourceHandle,
+        },
+        continuation::EventKind,
+        error::KernelError,
+        interrupts::SELECTORS,
+        io,
+        ipc::Message,
+        memory::{self, map_frames, PhysicalFrames, Placement, VirtualMemoryRegion},
+        sched::stats::UserStats,
+        smp,
+    };
+
+    use super::{SavedRegs, CURRENT_TASK, HANDLES};
+
+    /// Terminate the calling task. The exit code is passed in %rdi.
+    const SYS_EXIT: u64 = 0xDEADBEEF;
+
+    // Handles are passed and returned as indices into the calling task's `HandleTable`. Where a
+    // channel, IRQ line, or physical memory is expected, a group with one among its members may be
+    // passed instead. The first one in the group is used.
+
+    /// Send the word in %rsi on the channel whose handle is in %rdi, without blocking.
+    const SYS_CHAN_SEND: u64 = 1;
+
+    /// Receive a word from the channel whose handle is in %rdi, without blocking. The word is
+    /// returned in %rdi.
+    const SYS_CHAN_RECV: u64 = 2;
+
+    /// Dump the continuation graph over the serial port, for debugging.
+    const SYS_DEBUG_DOT: u64 = 3;
+
+    /// Get the scheduler statistics: those of the calling task and of each continuation label,
+    /// and the number of idle loops. They are written as a `sched::stats::UserStats` to the address
+    /// in %rdi, which must be aligned and in memory the task may write to.
+    const SYS_SCHED_STATS: u64 = 4;
+
+    /// Dump the scheduler statistics of all continuations and tasks over the serial port, for
+    /// debugging.
+    const SYS_DEBUG_STATS: u64 = 5;
+
+    /// Get the `n`th handle held by the calling task, where `n` is in %rdi, leaving out any that
+    /// have been revoked. The handle is returned in %rdi. Handles are in order of index.
+    const SYS_CAP_LIST: u64 = 6;
+
+    /// Get the metadata of the capability whose handle is in %rdi. The type of the capability
+    /// (see `TYPE_*`) is returned in the low byte of %rdi and the rights the handle grants in the
+    /// next byte. Two more words are returned in %rsi and %rdx, depending on the type:
+    /// - Group: the number of members, and 0.
+    /// - Memory region: the first address, and the length in bytes.
+    /// - Channel: the number of messages queued, and the capacity.
+    /// - I/O ports: the first port, and the number of ports.
+    /// - IRQ: the IRQ line, and 0.
+    /// - Physical memory: the physical address of the first frame, and the length in bytes.
+    const SYS_CAP_INSPECT: u64 = 7;
+
+    /// Derive a new handle from the one in %rdi that grants only those of its rights that are in
+    /// %rsi. The new handle is returned in %rdi.
+    const SYS_CAP_DUPLICATE: u64 = 8;
+
+    /// Destroy the handle in %rdi (see `cap::destroy`), and remove it from the task's table (even
+    /// if it had been revoked).
+    const SYS_CAP_RELEASE: u64 = 9;
+
+    /// Revoke the handle in %rdi and every handle derived from it (see `cap::revoke`). The handle
+    /// stays in the task's table until it is released. Anyone waiting on a channel or IRQ line
+    /// through a revoked handle gets an error.
+    const SYS_CAP_REVOKE: u64 = 10;
+
+    /// Block until there is an interrupt on the IRQ line whose handle is in %rdi (see
+    /// `interrupts::irq`). The line stays masked until `SYS_IRQ_ACK`.
+    const SYS_IRQ_WAIT: u64 = 11;
+
+    /// Acknowledge the last interrupt on the IRQ line whose handle is in %rdi, so that the
+    /// next one can be delivered.
+    const SYS_IRQ_ACK: u64 = 12;
+
+    /// Get the physical address of the `n`th frame of the physical memory whose handle is in %rdi,
+    /// where `n` is in %rsi, e.g. to give a device a list of frames it may use. The address is
+    /// returned in %rdi.
+    const SYS_FRAME_ADDRESS: u64 = 13;
+
+    /// Allocate the number of frames of physical memory in %rdi, zeroed, e.g. for DMA buffers. If
+    /// %rsi is not 0, the frames are physically contiguous and start at a multiple of %rsi bytes,
+    /// which must be a power of two. The new handle is returned in %rdi. The frames count against
+    /// the task's quota of `TASK_FRAME_QUOTA` frames until they are freed.
+    const SYS_FRAME_ALLOC: u64 = 14;
+
+    /// Map the physical memory whose handle is in %rdi into a new memory region right away, with
+    /// the caching flags in %rsi (see `MAP_*`). The memory is writable if the handle grants
+    /// writing. The region's handle is returned in %rdi and its first address in %rsi.
+    const SYS_FRAME_MAP: u64 = 15;
+
+    // Caching flags for `SYS_FRAME_MAP`.
+
+    /// Don't cache the memory, e.g. for device registers.
+    const MAP_NO_CACHE: u64 = 1 << 0;
+
+    /// Write through the cache rather than back.
+    const MAP_WRITE_THROUGH: u64 = 1 << 1;
+
+    // Capability types returned by `SYS_CAP_INSPECT`.
+
+    const TYPE_GROUP: u64 = 0;
+    const TYPE_REGION: u64 = 1;
+    const TYPE_CHANNEL: u64 = 2;
+    const TYPE_IO_PORTS: u64 = 3;
+    const TYPE_IRQ: u64 = 4;
+    const TYPE_FRAMES: u64 = 5;
+
+    // Error codes returned in %rax. Success is 0.
+
+    /// The given handle does not name a capability held by the task.
+    const ERR_BAD_HANDLE: u64 = !0;
+
+    /// The given handle names the wrong kind of capability, or the message is of the wrong type.
+    const ERR_WRONG_TYPE: u64 = !1;
+
+    /// The channel is full.
+    const ERR_FULL: u64 = !2;
+
+    /// The channel is empty.
+    const ERR_EMPTY: u64 = !3;
+
+    /// The given address is not in memory the task may access.
+    const ERR_BAD_POINTER: u64 = !4;
+
+    /// The given handle does not grant the rights needed.
+    const ERR_NO_RIGHTS: u64 = !5;
+
+    /// The given index is past the end.
+    const ERR_OUT_OF_RANGE: u64 = !6;
+
+    /// There is not enough memory (or address space) left, or the task's quota is used up.
+    const ERR_NO_MEMORY: u64 = !7;
+
+    /// An argument is invalid, e.g. an alignment is not a power of two.
+    const ERR_INVALID: u64 = !8;
 
     /// Handle a `syscall` instruction from userspace.
     ///
     /// This is not to be called from kernel mode! And it should never be called more than once at a
-    /// time.
+    /// time on each core.
     ///
     /// Interrupts are disabled on entry.
     ///
     /// Contract with userspace (beyond what the ISA does):
-    /// - System call argument is passed in %rax
+    /// - System call number is passed in %rax
+    /// - System call arguments are passed in %rdi, %rsi, %r10 (in that order)
     /// - We may clobber %rdx
     /// - We will save and restore all other registers, including the stack pointer
-    /// - We will return values in %rax
+    /// - We will return values in %rax (and %rdi, %rsi, and %rdx for more values)
     #[naked]
     pub(super) unsafe extern "C" fn entry() {
         // Switch to tmp stack, save user regs
@@ -277,9 +924,11 @@ mod syscall {
             # save the user stack pointer to %rdx before we switch stacks.
             mov %rsp, %rdx
 
-            # switch to the tmp stack
-            mov $0, %rsp
-            mov (%rsp), %rsp
+            # switch to the tmp stack. KERNEL_GS_BASE points at this core's stack head, so swap it
+            # in just long enough to read the head.
+            swapgs
+            mov %gs:0, %rsp
+            swapgs
 
             # start saving stuff
             pushq %rdx # user rsp
@@ -308,7 +957,7 @@ mod syscall {
             call handle_syscall
             "
             : /* no outputs */
-            : "i"(&super::super::CURRENT_STACK_HEAD)
+            : /* no inputs */
             : "memory", "rax", "rbx", "rcx", "rdx", "rdi", "rsi", "r8", "r9", "r10", "r11", "r12",
               "r13", "r14", "r15", "rbp", "stack"
             : "volatile"
@@ -321,16 +970,80 @@ mod syscall {
     /// assumes we are still running on the tmp stack. It switches to the saved kernel stack.
     #[no_mangle]
     unsafe extern "C" fn handle_syscall(saved_regs: &mut SavedRegs) {
+        smp::set_user(false);
+
         // Re-enable interrupts
         x86_64::instructions::interrupts::enable();
 
         // Handle the system call. The syscall number is passed in %rax.
         match saved_regs.rax {
-            0xDEADBEEF => {
-                printk!("Task completed.\n");
-
-                crate::sched::sched();
+            SYS_EXIT => {
+                let code = saved_regs.rdi as isize;
+                let task = CURRENT_TASK
+                    .get()
+                    .lock()
+                    .take()
+                    .expect("exit syscall without a current task");
+
+                super::end_task(task, code);
+            }
+            SYS_CHAN_SEND => saved_regs.rax = sys_chan_send(saved_regs),
+            SYS_CHAN_RECV => {
+                let (status, word) = sys_chan_recv(saved_regs);
+                saved_regs.rax = status;
+                saved_regs.rdi = word;
             }
+            SYS_DEBUG_DOT => crate::sched::dump_dot(),
+            SYS_SCHED_STATS => saved_regs.rax = sys_sched_stats(saved_regs),
+            SYS_DEBUG_STATS => crate::sched::stats::dump(),
+            SYS_CAP_LIST => match sys_cap_list(saved_regs) {
+                Ok(index) => {
+                    saved_regs.rax = 0;
+                    saved_regs.rdi = index;
+                }
+                Err(status) => saved_regs.rax = status,
+            },
+            SYS_CAP_INSPECT => match sys_cap_inspect(saved_regs) {
+                Ok((info, a, b)) => {
+                    saved_regs.rax = 0;
+                    saved_regs.rdi = info;
+                    saved_regs.rsi = a;
+                    saved_regs.rdx = b;
+                }
+                Err(status) => saved_regs.rax = status,
+            },
+            SYS_CAP_DUPLICATE => match sys_cap_duplicate(saved_regs) {
+                Ok(handle) => return_handle(saved_regs, handle),
+                Err(status) => saved_regs.rax = status,
+            },
+            SYS_CAP_RELEASE => saved_regs.rax = sys_cap_release(saved_regs),
+            SYS_CAP_REVOKE => saved_regs.rax = sys_cap_revoke(saved_regs),
+            SYS_FRAME_ALLOC => match sys_frame_alloc(saved_regs) {
+                Ok(handle) => return_handle(saved_regs, handle),
+                Err(status) => saved_regs.rax = status,
+            },
+            SYS_FRAME_MAP => match sys_frame_map(saved_regs) {
+                Ok((region, start)) => {
+                    return_handle(saved_regs, region);
+                    saved_regs.rsi = start;
+                }
+                Err(status) => saved_regs.rax = status,
+            },
+            SYS_IRQ_WAIT => match sys_irq_wait(saved_regs) {
+                Ok(irq) => {
+                    saved_regs.rax = 0;
+                    super::block(saved_regs.clone(), EventKind::Irq(irq), "irq-wait");
+                }
+                Err(status) => saved_regs.rax = status,
+            },
+            SYS_IRQ_ACK => saved_regs.rax = sys_irq_ack(saved_regs),
+            SYS_FRAME_ADDRESS => match sys_frame_address(saved_regs) {
+                Ok(paddr) => {
+                    saved_regs.rax = 0;
+                    saved_regs.rdi = paddr;
+                }
+                Err(status) => saved_regs.rax = status,
+            },
             n => printk!("unknown syscall #{:#x?}\n", n),
         }
 
@@ -338,20 +1051,382 @@ mod syscall {
         switch_to_user(saved_regs)
     }
 
+    /// Handle `SYS_SCHED_STATS`. Returns the status.
+    fn sys_sched_stats(saved_regs: &SavedRegs) -> u64 {
+        let task = CURRENT_TASK
+            .get()
+            .lock()
+            .expect("stats syscall without a current task");
+
+        let addr = saved_regs.rdi;
+        let flags =
+            PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::USER_ACCESSIBLE;
+        if addr % mem::align_of::<UserStats>() as u64 != 0
+            || !memory::region_allows(addr, mem::size_of::<UserStats>() as u64, flags)
+        {
+            return ERR_BAD_POINTER;
+        }
+
+        // The task can't run (or free its memory) while we write.
+        crate::sched::stats::write_user(task, unsafe { &mut *(addr as *mut UserStats) });
+
+        0
+    }
+
+    /// Get the entry of the handle passed by the user in %rdi, if it is in the calling task's
+    /// table.
+    fn user_handle(saved_regs: &SavedRegs) -> Option<TableEntry> {
+        HANDLES
+            .get()
+            .lock()
+            .as_ref()?
+            .get(saved_regs.rdi as usize)
+            .cloned()
+    }
+
+    /// Like `user_handle`, but if the handle is on a group, returns the entry of its member that is
+    /// a capability of type `ty` instead (see `TableEntry::member`).
+    fn user_handle_of(saved_regs: &SavedRegs, ty: CapabilityType) -> Result<TableEntry, u64> {
+        let entry = user_handle(saved_regs).ok_or(ERR_BAD_HANDLE)?;
+
+        match entry.member(ty) {
+            Ok(member) => Ok(member.clone()),
+            Err(KernelError::WrongCapabilityType) => Ok(entry),
+
+            // The group is there, but it has nothing of type `ty` in it.
+            Err(KernelError::MissingCapability) if entry.rights().is_ok() => Err(ERR_WRONG_TYPE),
+            Err(err) => Err(error_code(err)),
+        }
+    }
+
+    /// Add `handle` to the calling task's table, and return its index to the user in %rdi, with a
+    /// status of 0.
+    fn return_handle(saved_regs: &mut SavedRegs, handle: ResourceHandle) {
+        let inserted = HANDLES
+            .get()
+            .lock()
+            .as_mut()
+            .expect("syscall without a handle table")
+            .insert(handle);
+
+        match inserted {
+            Ok(index) => {
+                saved_regs.rax = 0;
+                saved_regs.rdi = index as u64;
+            }
+            Err(err) => saved_regs.rax = error_code(err),
+        }
+    }
+
+    /// The status code for an error from using a handle or allocating memory.
+    pub(super) fn error_code(err: KernelError) -> u64 {
+        match err {
+            KernelError::InsufficientRights => ERR_NO_RIGHTS,
+            KernelError::WrongCapabilityType => ERR_WRONG_TYPE,
+            KernelError::OutOfPhysicalMemory
+            | KernelError::OutOfVirtualMemory
+            | KernelError::QuotaExceeded => ERR_NO_MEMORY,
+            KernelError::BadAlignment => ERR_INVALID,
+            _ => ERR_BAD_HANDLE,
+        }
+    }
+
+    /// Handle `SYS_CAP_LIST`. Returns the handle or the status code.
+    fn sys_cap_list(saved_regs: &SavedRegs) -> Result<u64, u64> {
+        let handles = HANDLES.get().lock();
+        let handles = handles.as_ref().expect("syscall without a handle table");
+
+        handles
+            .iter()
+            .filter(|(_, entry)| entry.rights().is_ok())
+            .nth(saved_regs.rdi as usize)
+            .map(|(index, _)| index as u64)
+            .ok_or(ERR_OUT_OF_RANGE)
+    }
+
+    /// Handle `SYS_CAP_INSPECT`. Returns the type and rights and the two words of metadata, or the
+    /// status code.
+    fn sys_cap_inspect(saved_regs: &SavedRegs) -> Result<(u64, u64, u64), u64> {
+        let entry = user_handle(saved_regs).ok_or(ERR_BAD_HANDLE)?;
+        let rights = entry.rights().map_err(error_code)?;
+
+        let (ty, a, b) = entry
+            .with(|cap| match cap {
+                Capability::CapabilityGroup(group) => (TYPE_GROUP, group.len() as u64, 0),
+                Capability::VirtualMemoryRegion(region) => {
+                    (TYPE_REGION, region.start() as u64, region.len())
+                }
+                Capability::Channel(chan) => {
+                    (TYPE_CHANNEL, chan.len() as u64, chan.capacity() as u64)
+                }
+                Capability::IoPortRange(ports) => {
+                    (TYPE_IO_PORTS, ports.first() as u64, ports.len() as u64)
+                }
+                Capability::Irq(irq) => (TYPE_IRQ, irq.number() as u64, 0),
+                Capability::PhysicalFrames(frames) => {
+                    (TYPE_FRAMES, frames.start_address(), frames.len())
+                }
+            })
+            .map_err(error_code)?;
+
+        Ok((ty | (rights.bits() as u64) << 8, a, b))
+    }
+
+    /// Handle `SYS_CAP_DUPLICATE`. Returns the new handle or the status code.
+    fn sys_cap_duplicate(saved_regs: &SavedRegs) -> Result<ResourceHandle, u64> {
+        let entry = user_handle(saved_regs).ok_or(ERR_BAD_HANDLE)?;
+        let rights = Rights::from_bits(saved_regs.rsi as u8);
+
+        cap::derive(entry.handle(), rights).map_err(error_code)
+    }
+
+    /// Handle `SYS_CAP_RELEASE`. Returns the status code.
+    fn sys_cap_release(saved_regs: &SavedRegs) -> u64 {
+        let removed = HANDLES
+            .get()
+            .lock()
+            .as_mut()
+            .and_then(|handles| handles.remove(saved_regs.rdi as usize));
+        let entry = match removed {
+            Some(entry) => entry,
+            None => return ERR_BAD_HANDLE,
+        };
+
+        match cap::destroy(entry.handle()) {
+            Ok(()) => 0,
+            Err(err) => error_code(err),
+        }
+    }
+
+    /// Handle `SYS_CAP_REVOKE`. Returns the status code.
+    fn sys_cap_revoke(saved_regs: &SavedRegs) -> u64 {
+        let entry = match user_handle(saved_regs) {
+            Some(entry) => entry,
+            None => return ERR_BAD_HANDLE,
+        };
+
+        match cap::revoke(entry.handle()) {
+            Ok(()) => {
+                crate::sched::revoked();
+                0
+            }
+            Err(err) => error_code(err),
+        }
+    }
+
+    /// Handle `SYS_CHAN_SEND`. Returns the status code.
+    fn sys_chan_send(saved_regs: &SavedRegs) -> u64 {
+        let word = saved_regs.rsi;
+
+        let chan = match user_handle_of(saved_regs, CapabilityType::Channel) {
+            Ok(chan) => chan,
+            Err(status) => return status,
+        };
+
+        if let Err(err) = chan.check_rights(Rights::WRITE) {
+            return error_code(err);
+        }
+
+        let status = chan
+            .with(|cap| match cap {
+                Capability::Channel(chan) => match chan.send(Message::Word(word)) {
+                    Ok(()) => 0,
+                    Err(_) => ERR_FULL,
+                },
+                _ => ERR_WRONG_TYPE,
+            })
+            .unwrap_or_else(error_code);
+
+        // Let any receivers know.
+        if status == 0 {
+            crate::sched::channel_activity(chan.handle().clone());
+        }
+
+        status
+    }
+
+    /// Handle `SYS_CHAN_RECV`. Returns the status code and the received word.
+    fn sys_chan_recv(saved_regs: &SavedRegs) -> (u64, u64) {
+        let chan = match user_handle_of(saved_regs, CapabilityType::Channel) {
+            Ok(chan) => chan,
+            Err(status) => return (status, 0),
+        };
+
+        if let Err(err) = chan.check_rights(Rights::READ) {
+            return (error_code(err), 0);
+        }
+
+        let (status, word) = chan
+            .with(|cap| match cap {
+                // Handles cannot be passed to user space (yet), so leave them for someone else.
+                Capability::Channel(chan) => match chan.peek() {
+                    Some(Message::Word(word)) => {
+                        chan.recv();
+                        (0, word)
+                    }
+                    Some(Message::Handle(_)) => (ERR_WRONG_TYPE, 0),
+                    None => (ERR_EMPTY, 0),
+                },
+                _ => (ERR_WRONG_TYPE, 0),
+            })
+            .unwrap_or_else(|err| (error_code(err), 0));
+
+        // Let any senders know.
+        if status == 0 {
+            crate::sched::channel_activity(chan.handle().clone());
+        }
+
+        (status, word)
+    }
+
+    /// Handle `SYS_IRQ_WAIT`. Returns the handle to wait on or the status code.
+    fn sys_irq_wait(saved_regs: &SavedRegs) -> Result<ResourceHandle, u64> {
+        let irq = user_handle_of(saved_regs, CapabilityType::Irq)?;
+        irq.check_rights(Rights::READ).map_err(error_code)?;
+
+        match irq.with(|cap| cap.ty()).map_err(error_code)? {
+            CapabilityType::Irq => Ok(irq.handle().clone()),
+            _ => Err(ERR_WRONG_TYPE),
+        }
+    }
+
+    /// Handle `SYS_IRQ_ACK`. Returns the status code.
+    fn sys_irq_ack(saved_regs: &SavedRegs) -> u64 {
+        let irq = match user_handle_of(saved_regs, CapabilityType::Irq) {
+            Ok(irq) => irq,
+            Err(status) => return status,
+        };
+
+        if let Err(err) = irq.check_rights(Rights::WRITE) {
+            return error_code(err);
+        }
+
+        irq.with(|cap| match cap {
+            Capability::Irq(irq) => {
+                irq.ack();
+                0
+            }
+            _ => ERR_WRONG_TYPE,
+        })
+        .unwrap_or_else(error_code)
+    }
+
+    /// Handle `SYS_FRAME_ADDRESS`. Returns the physical address or the status code.
+    fn sys_frame_address(saved_regs: &SavedRegs) -> Result<u64, u64> {
+        let frames = user_handle_of(saved_regs, CapabilityType::PhysicalFrames)?;
+        frames.check_rights(Rights::READ).map_err(error_code)?;
+
+        frames
+            .with(|cap| match cap {
+                Capability::PhysicalFrames(frames) => frames
+                    .frames()
+                    .nth(saved_regs.rsi as usize)
+                    .ok_or(ERR_OUT_OF_RANGE),
+                _ => Err(ERR_WRONG_TYPE),
+            })
+            .map_err(error_code)?
+    }
+
+    /// Handle `SYS_FRAME_ALLOC`. Returns the new handle or the status code.
+    fn sys_frame_alloc(saved_regs: &SavedRegs) -> Result<ResourceHandle, u64> {
+        let placement = match saved_regs.rsi {
+            0 => Placement::Any,
+            align => Placement::Contiguous { align },
+        };
+
+        // Asking for more than the whole quota can never succeed.
+        let nframes = match saved_regs.rdi as usize {
+            0 => return Err(ERR_INVALID),
+            n if n > super::TASK_FRAME_QUOTA => return Err(ERR_INVALID),
+            n => n,
+        };
+
+        let task = CURRENT_TASK
+            .get()
+            .lock()
+            .expect("syscall without a current task");
+
+        PhysicalFrames::alloc(nframes, placement, &super::frame_quota(task))
+            .map(UnregisteredResourceHandle::register)
+            .map_err(error_code)
+    }
+
+    /// Handle `SYS_FRAME_MAP`. Returns the new region's handle and first address, or the status
+    /// code.
+    fn sys_frame_map(saved_regs: &SavedRegs) -> Result<(ResourceHandle, u64), u64> {
+        let frames = user_handle_of(saved_regs, CapabilityType::PhysicalFrames)?;
+
+        let len = frames
+            .with(|cap| match cap {
+                Capability::PhysicalFrames(frames) => Ok(frames.len()),
+                _ => Err(ERR_WRONG_TYPE),
+            })
+            .map_err(error_code)??;
+
+        let mut flags =
+            PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE | PageTableFlags::NO_EXECUTE;
+        if frames.check_rights(Rights::WRITE).is_ok() {
+            flags |= PageTableFlags::WRITABLE;
+        }
+        if saved_regs.rsi & MAP_NO_CACHE != 0 {
+            flags |= PageTableFlags::NO_CACHE;
+        }
+        if saved_regs.rsi & MAP_WRITE_THROUGH != 0 {
+            flags |= PageTableFlags::WRITE_THROUGH;
+        }
+
+        let region = VirtualMemoryRegion::alloc((len / Size4KiB::SIZE) as usize)
+            .map_err(error_code)?
+            .register();
+        let start = region
+            .with(|cap| cap_unwrap!(VirtualMemoryRegion(cap)).start() as u64)
+            .map_err(error_code)?;
+
+        if let Err(err) = map_frames(&region, frames.handle(), flags) {
+            let _ = cap::destroy(&region);
+            return Err(error_code(err));
+        }
+
+        Ok((region, start))
+    }
+
     /// Switch to user mode with the given registers.
     pub(super) fn switch_to_user(registers: &SavedRegs) -> ! {
         // https://software.intel.com/sites/default/files/managed/39/c5/325462-sdm-vol-1-2abcd-3abcd.pdf#G43.25974
         //
-        // Set the following and execute the `sysret` instruction:
-        // - user rip: load into rcx before sysret
-        // - rflags: load into r11 before sysret
+        // We use `iretq` rather than `sysret` because `sysret` clobbers %rcx and %r11 (with the
+        // user rip and rflags), and a task that was preempted needs all of its registers back.
+        //
+        // Push the frame that `iretq` pops, restore all registers, and execute `iretq`:
+        // - user ss, rsp, rflags, cs, rip (in that order)
         // - also want to set any register values to be given to the user
-        //      - user rsp
-        //      - clear all other regs
+        let (user_cs, user_ss) = {
+            let selectors = SELECTORS.lock();
+            (selectors.user_cs.0 | 3, selectors.user_ds.0 | 3)
+        };
+
+        // The task may use the I/O ports it holds capabilities on.
+        io::ports::load();
+
+        smp::set_user(true);
+        super::preempt::arm_slice();
+
         unsafe {
             asm!(
                 "
-                # load address of `registers` to `rcx` in inline asm
+                # load address of `registers` to `rcx`, user cs to `rax`, and user ss to `rdx` in
+                # inline asm
+
+                # disable interrupts until we are in user mode; the frame below is on the kernel
+                # stack, and the registers are only half restored.
+                cli
+
+                # the frame for iretq
+                pushq %rdx
+                pushq 0x88(%rcx)
+                pushq 0x78(%rcx)
+                pushq %rax
+                pushq 0x80(%rcx)
 
                 # restore registers
                 movq     (%rcx), %rax
@@ -364,30 +1439,20 @@ mod syscall {
                 movq 0x38(%rcx), %r8
                 movq 0x40(%rcx), %r9
                 movq 0x48(%rcx), %r10
-
+                movq 0x50(%rcx), %r11
                 movq 0x58(%rcx), %r12
                 movq 0x60(%rcx), %r13
                 movq 0x68(%rcx), %r14
                 movq 0x70(%rcx), %r15
 
-                # user rflags
-                movq 0x78(%rcx), %r11
-
-                # disable interrupts before loading the user stack; otherwise, an interrupt may be
-                # serviced on the wrong stack.
-                cli
-
-                # no more stack refs until sysret
-                movq 0x88(%rcx), %rsp
-
-                # user rip
-                movq 0x80(%rcx), %rcx
+                # rcx last, since it points to the registers
+                movq 0x10(%rcx), %rcx
 
                 # return to usermode (ring 3)
-                sysretq
+                iretq
                 "
                 : /* no outputs */
-                : "{rcx}"(registers)
+                : "{rcx}"(registers), "{rax}"(user_cs as u64), "{rdx}"(user_ss as u64)
                 : "memory", "rax", "rbx", "rcx", "rdx", "rdi", "rsi", "r8", "r9", "r10", "r11",
                   "r12", "r13", "r14", "r15", "rbp", "rsp", "stack"
                 : "volatile"
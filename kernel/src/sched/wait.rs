@@ -0,0 +1,547 @@
+//! Wait queues for continuations that are waiting on events.
+//!
+//! Rather than polling every outstanding continuation on every scheduling decision, each waiting
+//! continuation is indexed by the events it waits for:
+//! - timers live in a min-heap keyed by deadline,
+//! - keyboard waiters live in a FIFO, which is only looked at when the keyboard interrupt handler
+//!   has buffered some input,
+//! - IRQ waiters live in a FIFO per IRQ line, which is only looked at when there is an interrupt on
+//!   the line to deliver,
+//! - task-exit, channel, and join waiters are indexed by the task, channel, or continuation they
+//!   wait for, and they are woken when that thing happens.
+//!
+//! Once a continuation's event happens, it moves to the ready queue, which only ever contains
+//! runnable continuations.
+//!
+//! A continuation waiting on an `EventKind::Any` is indexed under each of its branches. When one
+//! branch fires, the continuation is removed from `waiting`, which cancels the other branches. Its
+//! entries in the task, channel, and join indices are removed right away, so those indices do not
+//! fill up with waiters that gave up (e.g. timed out). Its timer and keyboard entries become stale
+//! and are dropped whenever they are next looked at. In particular, stale branches never consume
+//! anything (e.g. keyboard input or messages).
+
+use alloc::{
+    boxed::Box,
+    collections::{BTreeMap, BinaryHeap, VecDeque},
+    vec,
+    vec::Vec,
+};
+
+use core::cmp::Reverse;
+
+use crate::{
+    cap::{Capability, ResourceHandle},
+    continuation::{ContId, ContValue, Continuation, Event, EventKind},
+    error::KernelError,
+    interrupts::irq,
+    io::kbd,
+    time::SysTime,
+};
+
+use super::{
+    policy,
+    replay::{self, Input},
+    user::TaskHandle,
+};
+
+/// Identifies a waiting continuation in `WaitQueues::waiting`.
+#[derive(Copy, Clone, Debug, Eq, Ord, PartialEq, PartialOrd)]
+struct WaitId(usize);
+
+/// A reference to (one branch of) the event a continuation waits for: the waiting continuation and
+/// the path of branch indices through any `EventKind::Any`s to the branch.
+type Waiter = (WaitId, Vec<usize>);
+
+/// All continuations that have yet to run, indexed by what they are waiting for.
+pub struct WaitQueues {
+    /// Continuations that are ready to run, along with the `Event` each one was waiting for.
+    ready: VecDeque<(Event, Continuation)>,
+
+    /// Continuations that are waiting for something, along with what they are waiting for.
+    waiting: BTreeMap<WaitId, (EventKind, Continuation)>,
+
+    /// The next `WaitId` to hand out.
+    next_id: usize,
+
+    /// Waiters on `EventKind::Until`, earliest deadline first.
+    timers: BinaryHeap<Reverse<(SysTime, WaitId, Vec<usize>)>>,
+
+    /// Waiters on `EventKind::Keyboard`, in the order they started waiting.
+    keyboard: VecDeque<Waiter>,
+
+    /// Waiters on `EventKind::Irq`, by IRQ line, in the order they started waiting.
+    irqs: BTreeMap<u8, VecDeque<Waiter>>,
+
+    /// Waiters on `EventKind::TaskExit`, by task.
+    tasks: BTreeMap<TaskHandle, Vec<Waiter>>,
+
+    /// The exit codes of user tasks that have terminated, so that continuations waiting on them
+    /// can be notified.
+    exited: BTreeMap<TaskHandle, isize>,
+
+    /// Waiters on `EventKind::ChannelRecv` and `EventKind::ChannelSend`, by channel, in the order
+    /// they started waiting.
+    channels: BTreeMap<ResourceHandle, VecDeque<Waiter>>,
+
+    /// Waiters on `EventKind::Join`, by the continuations they are waiting for.
+    joins: BTreeMap<ContId, Vec<Waiter>>,
+
+    /// Continuations that have finished and are waited on by some `EventKind::Join`, along with
+    /// their results. An entry is removed once nobody is waiting for it in `joins`.
+    finished: BTreeMap<ContId, Option<ContValue>>,
+}
+
+impl WaitQueues {
+    pub fn new() -> Self {
+        WaitQueues {
+            ready: VecDeque::new(),
+            waiting: BTreeMap::new(),
+            next_id: 0,
+            timers: BinaryHeap::new(),
+            keyboard: VecDeque::new(),
+            irqs: BTreeMap::new(),
+            tasks: BTreeMap::new(),
+            exited: BTreeMap::new(),
+            channels: BTreeMap::new(),
+            joins: BTreeMap::new(),
+            finished: BTreeMap::new(),
+        }
+    }
+
+    /// Get the next continuation to run along with the `Event` that it was waiting for. If no
+    /// continuation is ready, return None.
+    pub fn next(&mut self) -> Option<(Event, Continuation)> {
+        // When replaying a log, timers and keyboard input are delivered by `inject` instead.
+        if replay::replaying() {
+            return self.ready.pop_front();
+        }
+
+        // Expired timers.
+        let now = SysTime::now();
+        while let Some(Reverse((time, _, _))) = self.timers.peek() {
+            if *time > now {
+                break;
+            }
+
+            let Reverse((_, id, path)) = self.timers.pop().unwrap();
+            self.fire(id, &path, Event::Timer);
+        }
+
+        // Keyboard input, if the interrupt handler has buffered any.
+        while kbd::has_input() {
+            match self.keyboard.pop_front() {
+                Some((id, path)) if self.is_waiting(id) => {
+                    let c = kbd::kbd_next().unwrap();
+                    self.fire(id, &path, Event::Keyboard(c));
+                }
+                Some(_stale) => {}
+                None => break,
+            }
+        }
+
+        // Interrupts on claimed IRQ lines.
+        if irq::has_pending() {
+            self.deliver_irqs();
+        }
+
+        self.ready.pop_front()
+    }
+
+    /// Give each pending interrupt to the first continuation waiting on its line, if any.
+    /// Interrupts that nobody is waiting for stay pending.
+    fn deliver_irqs(&mut self) {
+        let lines: Vec<u8> = self
+            .irqs
+            .keys()
+            .copied()
+            .filter(|&line| irq::is_pending(line))
+            .collect();
+
+        for line in lines {
+            while let Some((id, path)) = self.irqs.get_mut(&line).and_then(VecDeque::pop_front) {
+                // The capability may have been revoked since the waiter started waiting, and the
+                // line claimed by someone else.
+                let valid = match self.kind_at(id, &path) {
+                    Some(EventKind::Irq(handle)) => handle
+                        .with(|cap| matches!(cap, Capability::Irq(irq) if irq.number() == line)),
+                    _ => Ok(false),
+                };
+
+                match valid {
+                    Ok(true) => {}
+                    Ok(false) => continue,
+                    Err(err) => {
+                        self.fire(id, &path, Event::Error(err));
+                        continue;
+                    }
+                }
+
+                if irq::take(line) {
+                    self.fire(id, &path, Event::Irq(line));
+                } else {
+                    self.irqs.get_mut(&line).unwrap().push_front((id, path));
+                }
+
+                break;
+            }
+        }
+
+        self.irqs.retain(|_, waiters| !waiters.is_empty());
+    }
+
+    /// Deliver `input` to the branch at `path` of the event the continuation `cont` waits for, as
+    /// recorded in a replayed log. Does nothing if `cont` isn't waiting for that input there.
+    pub fn inject(&mut self, cont: ContId, path: &[usize], input: Input) {
+        let id = match self.waiting.iter().find(|(_, (_, c))| c.id() == cont) {
+            Some((id, _)) => *id,
+            None => return,
+        };
+
+        // The log may not match what the continuation waits for, so walk the path carefully.
+        let mut kind = &self.waiting[&id].0;
+        for &branch in path {
+            kind = match kind {
+                EventKind::Any(kinds) if branch < kinds.len() => &kinds[branch],
+                _ => return,
+            };
+        }
+
+        match (kind, input) {
+            (EventKind::Until(_), Input::Timer) => self.fire(id, path, Event::Timer),
+            (EventKind::Keyboard, Input::Keyboard(c)) => self.fire(id, path, Event::Keyboard(c)),
+            (EventKind::Irq(_), Input::Irq(irq)) => self.fire(id, path, Event::Irq(irq)),
+            _ => {}
+        }
+    }
+
+    /// Are there any continuations ready to run?
+    pub fn has_ready(&self) -> bool {
+        !self.ready.is_empty()
+    }
+
+    /// The earliest time any continuation is waiting for, if any. The continuation may have since
+    /// been woken up by something else.
+    pub fn next_deadline(&self) -> Option<SysTime> {
+        self.timers.peek().map(|Reverse((time, _, _))| *time)
+    }
+
+    /// Add a continuation that waits for `kind`.
+    pub fn enqueue(&mut self, kind: EventKind, cont: Continuation) {
+        let id = WaitId(self.next_id);
+        self.next_id += 1;
+
+        self.waiting.insert(id, (kind.clone(), cont));
+        self.register(id, &kind, &mut vec![]);
+    }
+
+    /// Record that the given continuation has finished with the given result, if anyone is going to
+    /// join it.
+    pub fn finished(&mut self, cont: ContId, value: Option<ContValue>) {
+        // Only waiters that are still waiting are indexed, so if there are none, nobody is going to
+        // join us.
+        let waiters = match self.joins.get(&cont) {
+            Some(waiters) => waiters.clone(),
+            None => return,
+        };
+
+        self.finished.insert(cont, value);
+
+        for (id, path) in waiters {
+            self.try_join(id, &path);
+        }
+    }
+
+    /// Record that the given user task has exited with the given exit code.
+    pub fn task_exited(&mut self, task: TaskHandle, code: isize) {
+        self.exited.insert(task, code);
+
+        for (id, path) in self.tasks.remove(&task).unwrap_or_default() {
+            self.fire(id, &path, Event::TaskExited { code });
+        }
+    }
+
+    /// Something was sent or received on `chan`, so continuations waiting on it may be able to
+    /// make progress.
+    pub fn channel_activity(&mut self, chan: ResourceHandle) {
+        // Keep going until nobody can make progress. A send may allow a receive and vice versa.
+        loop {
+            let waiters = match self.channels.remove(&chan) {
+                Some(waiters) => waiters,
+                None => return,
+            };
+
+            let mut progress = false;
+            let mut still_waiting = VecDeque::new();
+
+            for (id, path) in waiters {
+                let event = match self.kind_at(id, &path) {
+                    Some(EventKind::ChannelRecv(chan)) => {
+                        chan.with(|cap| cap_unwrap!(Channel(cap)).recv().map(Event::Message))
+                    }
+                    Some(EventKind::ChannelSend(chan, msg)) => chan.with(|cap| {
+                        cap_unwrap!(Channel(cap))
+                            .send(msg.clone())
+                            .ok()
+                            .map(|()| Event::MessageSent)
+                    }),
+
+                    // Stale: drop it.
+                    _ => continue,
+                };
+
+                match event {
+                    Ok(Some(event)) => {
+                        self.fire(id, &path, event);
+                        progress = true;
+                    }
+                    Ok(None) => still_waiting.push_back((id, path)),
+
+                    // The channel has been revoked, so this waiter can never make progress.
+                    Err(err) => self.fire(id, &path, Event::Error(err)),
+                }
+            }
+
+            if !still_waiting.is_empty() {
+                self.channels.insert(chan.clone(), still_waiting);
+            }
+
+            if !progress {
+                return;
+            }
+        }
+    }
+
+    /// Some capabilities have been revoked. Fail any continuations waiting on channels or IRQ lines
+    /// through them, since they can never make progress.
+    pub fn revoked(&mut self) {
+        let chans: Vec<ResourceHandle> = self.channels.keys().cloned().collect();
+        for chan in chans {
+            self.channel_activity(chan);
+        }
+
+        let lines: Vec<u8> = self.irqs.keys().copied().collect();
+        for line in lines {
+            let mut still_waiting = VecDeque::new();
+
+            for (id, path) in self.irqs.remove(&line).unwrap() {
+                let held = match self.kind_at(id, &path) {
+                    Some(EventKind::Irq(handle)) => handle.with(|_| ()),
+
+                    // Stale: drop it.
+                    _ => continue,
+                };
+
+                match held {
+                    Ok(()) => still_waiting.push_back((id, path)),
+                    Err(err) => self.fire(id, &path, Event::Error(err)),
+                }
+            }
+
+            if !still_waiting.is_empty() {
+                self.irqs.insert(line, still_waiting);
+            }
+        }
+    }
+
+    /// Iterate over all continuations that have yet to run, along with what they are waiting for
+    /// (`None` if they are ready).
+    pub fn iter(&self) -> impl Iterator<Item = (Option<&EventKind>, &Continuation)> {
+        self.ready
+            .iter()
+            .map(|(_, cont)| (None, cont))
+            .chain(self.waiting.values().map(|(kind, cont)| (Some(kind), cont)))
+    }
+
+    /// Index the waiting continuation `id` under `kind`, which is the branch of its event at
+    /// `path`. If the event has already happened, the continuation becomes ready right away.
+    fn register(&mut self, id: WaitId, kind: &EventKind, path: &mut Vec<usize>) {
+        match kind {
+            // Not waiting? Great!
+            EventKind::Now => self.fire(id, path, Event::Now),
+
+            // Timer events? Is the requested time here?
+            EventKind::Until(time) => {
+                if SysTime::now() >= *time && !replay::replaying() {
+                    self.fire(id, path, Event::Timer);
+                } else {
+                    self.timers.push(Reverse((*time, id, path.clone())));
+                }
+            }
+
+            // Waiting for kbd input? `next` will take care of it.
+            EventKind::Keyboard => self.keyboard.push_back((id, path.clone())),
+
+            // Waiting for an interrupt? `next` will take care of it too, unless the capability is
+            // not an IRQ line (e.g. it has been revoked).
+            EventKind::Irq(handle) => {
+                let line = handle.with(|cap| match cap {
+                    Capability::Irq(irq) => Ok(irq.number()),
+                    _ => Err(KernelError::WrongCapabilityType),
+                });
+
+                match line.and_then(|line| line) {
+                    Ok(line) => self
+                        .irqs
+                        .entry(line)
+                        .or_default()
+                        .push_back((id, path.clone())),
+                    Err(err) => self.fire(id, path, Event::Error(err)),
+                }
+            }
+
+            // Waiting for a user task to finish? It may have already finished.
+            EventKind::TaskExit(task) => match self.exited.get(task) {
+                Some(&code) => self.fire(id, path, Event::TaskExited { code }),
+                None => self
+                    .tasks
+                    .entry(*task)
+                    .or_default()
+                    .push((id, path.clone())),
+            },
+
+            // Waiting to send or receive a message? It may be possible already.
+            EventKind::ChannelRecv(chan) | EventKind::ChannelSend(chan, _) => {
+                self.channels
+                    .entry(chan.clone())
+                    .or_default()
+                    .push_back((id, path.clone()));
+                self.channel_activity(chan.clone());
+            }
+
+            // Waiting for a set of continuations to finish?
+            EventKind::Join(conts) => {
+                for cont in conts.iter() {
+                    self.joins
+                        .entry(*cont)
+                        .or_default()
+                        .push((id, path.clone()));
+                }
+
+                // In case there is nothing to wait for.
+                self.try_join(id, path);
+            }
+
+            // Waiting for any of a set of events? Register all of them, unless one has already
+            // happened.
+            EventKind::Any(kinds) => {
+                for (branch, kind) in kinds.iter().enumerate() {
+                    if !self.is_waiting(id) {
+                        break;
+                    }
+
+                    path.push(branch);
+                    self.register(id, kind, path);
+                    path.pop();
+                }
+            }
+        }
+    }
+
+    /// If all of the continuations that the `EventKind::Join` at `path` in waiter `id` waits for
+    /// have finished, hand out their results.
+    fn try_join(&mut self, id: WaitId, path: &[usize]) {
+        let conts = match self.kind_at(id, path) {
+            Some(EventKind::Join(conts)) => conts.clone(),
+            _ => return, // stale
+        };
+
+        if conts.iter().all(|cont| self.finished.contains_key(cont)) {
+            let results = conts
+                .iter()
+                .map(|cont| self.finished.remove(cont).flatten())
+                .collect();
+            self.fire(id, path, Event::Joined(results));
+        }
+    }
+
+    /// Is waiter `id` still waiting (i.e. none of its branches have fired)?
+    fn is_waiting(&self, id: WaitId) -> bool {
+        self.waiting.contains_key(&id)
+    }
+
+    /// The branch at `path` of the event waiter `id` waits for, if it is still waiting.
+    fn kind_at(&self, id: WaitId, path: &[usize]) -> Option<&EventKind> {
+        let mut kind = &self.waiting.get(&id)?.0;
+
+        for &branch in path {
+            kind = match kind {
+                EventKind::Any(kinds) => &kinds[branch],
+                _ => unreachable!(),
+            };
+        }
+
+        Some(kind)
+    }
+
+    /// The branch at `path` of waiter `id` has happened with the given `event`. Make the waiter
+    /// ready, cancelling its other branches. Does nothing if the waiter is no longer waiting.
+    fn fire(&mut self, id: WaitId, path: &[usize], event: Event) {
+        let (kind, mut cont) = match self.waiting.remove(&id) {
+            Some(waiting) => waiting,
+            None => return,
+        };
+
+        self.unregister(id, &kind);
+
+        // Keyboard input is latency-sensitive, so don't let it get stuck behind other work.
+        if let Event::Keyboard(_) = event {
+            cont = cont
+                .with_priority(policy::INTERACTIVE_PRIORITY)
+                .with_deadline(SysTime::now())
+                .with_tickets(policy::INTERACTIVE_TICKETS);
+        }
+
+        // Tell the continuation which branch of each `Any` fired.
+        let event = path.iter().rev().fold(event, |event, &branch| Event::Any {
+            branch,
+            event: Box::new(event),
+        });
+
+        self.ready.push_back((event, cont));
+    }
+
+    /// Remove waiter `id`, which waited for `kind`, from the task, channel, and join indices.
+    fn unregister(&mut self, id: WaitId, kind: &EventKind) {
+        match kind {
+            EventKind::TaskExit(task) => {
+                if let Some(waiters) = self.tasks.get_mut(task) {
+                    waiters.retain(|(other, _)| *other != id);
+                    if waiters.is_empty() {
+                        self.tasks.remove(task);
+                    }
+                }
+            }
+
+            EventKind::ChannelRecv(chan) | EventKind::ChannelSend(chan, _) => {
+                if let Some(waiters) = self.channels.get_mut(chan) {
+                    waiters.retain(|(other, _)| *other != id);
+                    if waiters.is_empty() {
+                        self.channels.remove(chan);
+                    }
+                }
+            }
+
+            // Once nobody is waiting for a continuation, its result is not needed anymore.
+            EventKind::Join(conts) => {
+                for cont in conts.iter() {
+                    if let Some(waiters) = self.joins.get_mut(cont) {
+                        waiters.retain(|(other, _)| *other != id);
+                        if waiters.is_empty() {
+                            self.joins.remove(cont);
+                            self.finished.remove(cont);
+                        }
+                    }
+                }
+            }
+
+            EventKind::Any(kinds) => {
+                for kind in kinds.iter() {
+                    self.unregister(id, kind);
+                }
+            }
+
+            // Timers and keyboard waiters are dropped lazily.
+            EventKind::Now | EventKind::Until(_) | EventKind::Keyboard => {}
+        }
+    }
+}
@@ -0,0 +1,140 @@
+//! A minimal driver for the local APIC, which we use to start the other cores and to send them
+//! inter-processor interrupts (IPIs).
+//!
+//! See Intel SDM Vol 3, ch 10 for the register layout.
+
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use x86_64::{
+    instructions::interrupts,
+    registers::model_specific::Msr,
+    structures::paging::{PageSize, PageTableFlags, Size4KiB},
+};
+
+use crate::{
+    cap::{self, KernelAuthority},
+    error::KernelError,
+    memory::{map_frames, PhysicalFrames, VirtualMemoryRegion},
+};
+
+/// The `IA32_APIC_BASE` MSR, which contains the physical address of the local APIC.
+const APIC_BASE: Msr = Msr::new(0x1B);
+
+/// Offset of the End-Of-Interrupt register.
+const EOI: usize = 0xB0;
+
+/// Offset of the Spurious Interrupt Vector register.
+const SVR: usize = 0xF0;
+
+/// Offsets of the low and high halves of the Interrupt Command Register.
+const ICR_LOW: usize = 0x300;
+const ICR_HIGH: usize = 0x310;
+
+/// Set in the SVR to software-enable the local APIC.
+const SVR_ENABLE: u32 = 1 << 8;
+
+// Bits of the ICR.
+
+/// Delivery mode: INIT.
+const ICR_INIT: u32 = 0b101 << 8;
+
+/// Delivery mode: Startup IPI.
+const ICR_STARTUP: u32 = 0b110 << 8;
+
+/// Set while the previous IPI is still being sent.
+const ICR_PENDING: u32 = 1 << 12;
+
+/// Level: assert.
+const ICR_ASSERT: u32 = 1 << 14;
+
+/// Destination shorthand: all cores except the sender.
+const ICR_ALL_BUT_SELF: u32 = 0b11 << 18;
+
+/// The virtual address where the local APIC registers are mapped, or 0 before `init`. Every core's
+/// local APIC is at the same address; each core only ever sees its own.
+static BASE: AtomicUsize = AtomicUsize::new(0);
+
+/// Map the local APIC registers. This is only called once, by the bootstrap core.
+pub fn init() -> Result<(), KernelError> {
+    let paddr = unsafe { APIC_BASE.read() } & !0xFFF;
+
+    // The registers stay mapped forever, so these capabilities are never destroyed. They belong to
+    // the kernel, and no continuation is running yet.
+    let kernel = unsafe { KernelAuthority::new() };
+    let vaddr = cap::as_kernel(&kernel, || {
+        let frames = PhysicalFrames::mmio(paddr, Size4KiB::SIZE)?.register();
+        let region = VirtualMemoryRegion::alloc(1)?.register();
+        map_frames(
+            &region,
+            &frames,
+            PageTableFlags::PRESENT
+                | PageTableFlags::WRITABLE
+                | PageTableFlags::NO_CACHE
+                | PageTableFlags::WRITE_THROUGH
+                | PageTableFlags::NO_EXECUTE,
+        )?;
+
+        region.with(|cap| cap_unwrap!(VirtualMemoryRegion(cap)).start())
+    })?;
+    BASE.store(vaddr as usize, Ordering::Release);
+
+    printk!("\tlocal APIC {:#x} mapped at {:p}\n", paddr, vaddr);
+
+    Ok(())
+}
+
+/// Has the local APIC been mapped yet?
+pub fn is_mapped() -> bool {
+    BASE.load(Ordering::Acquire) != 0
+}
+
+/// Software-enable the calling core's local APIC, with `spurious` as the spurious interrupt vector.
+pub fn enable(spurious: u8) {
+    unsafe {
+        write(SVR, SVR_ENABLE | spurious as u32);
+    }
+}
+
+/// Signal the end of an interrupt from the local APIC (e.g. an IPI).
+pub fn eoi() {
+    unsafe {
+        write(EOI, 0);
+    }
+}
+
+/// Send an INIT IPI to all other cores.
+pub fn send_init_all() {
+    send(ICR_ALL_BUT_SELF | ICR_ASSERT | ICR_INIT, 0);
+}
+
+/// Send a Startup IPI to all other cores, telling them to start executing in real mode at the
+/// beginning of the physical page `page`.
+pub fn send_startup_all(page: u8) {
+    send(ICR_ALL_BUT_SELF | ICR_ASSERT | ICR_STARTUP | page as u32, 0);
+}
+
+/// Send the interrupt `vector` to the core with the given local APIC id.
+pub fn send_ipi(apic_id: u8, vector: u8) {
+    send(ICR_ASSERT | vector as u32, apic_id);
+}
+
+/// Write the ICR and wait for the IPI to be sent.
+fn send(icr: u32, apic_id: u8) {
+    // An interrupt handler could send an IPI of its own between our two writes to the ICR.
+    interrupts::without_interrupts(|| unsafe {
+        write(ICR_HIGH, (apic_id as u32) << 24);
+        write(ICR_LOW, icr);
+
+        while read(ICR_LOW) & ICR_PENDING != 0 {}
+    });
+}
+
+/// Read the local APIC register at `offset`.
+unsafe fn read(offset: usize) -> u32 {
+    ((BASE.load(Ordering::Acquire) + offset) as *const u32).read_volatile()
+}
+
+/// Write the local APIC register at `offset`.
+unsafe fn write(offset: usize, val: u32) {
+    ((BASE.load(Ordering::Acquire) + offset) as *mut u32).write_volatile(val)
+}
@@ -0,0 +1,395 @@
+//! Symmetric multiprocessing: starting the other cores and keeping per-core state.
+//!
+//! The bootstrap processor (BSP) starts the application processors (APs) by broadcasting INIT and
+//! Startup IPIs (Intel SDM Vol 3, ch 8.4). Each AP runs the code in `trampoline` to get to long
+//! mode, sets up its own GDT, TSS, and interrupt stacks, and then waits for the scheduler to be
+//! created before running continuations on its own scheduler stacks.
+//!
+//! Some notes:
+//! - We don't parse the ACPI tables, so we don't know how many cores there are in advance. Instead,
+//!   we start all of them and count how many check in.
+//! - We use the local APIC id as the index of a core. This works as long as ids are small and
+//!   dense, as they are under QEMU (e.g. `-smp 4`). Cores with ids of `MAX_CPUS` or more are left
+//!   parked.
+//! - `cpu` is called on every `PerCpu` access, so it has to be fast. Each core stores its index in
+//!   `IA32_TSC_AUX`, which `rdtscp` reads without trapping. `cpuid` traps to the hypervisor under
+//!   virtualization, so we only use it on processors without `rdtscp` (e.g. QEMU's default model).
+//! - Only the BSP gets interrupts from the PIC. The other cores only get IPIs, so an idle core has
+//!   to be woken up explicitly with `wake_idle`.
+//! - When a mapping is removed or restricted, the other cores are told to flush their whole TLBs
+//!   with an IPI (`tlb_shootdown`). They do so asynchronously, so memory that was mapped can only
+//!   be reused once `TlbShootdown::done` says that every core has flushed.
+
+use alloc::{boxed::Box, vec::Vec};
+
+use core::{
+    arch::x86_64::{__cpuid, __rdtscp},
+    sync::atomic::{spin_loop_hint, AtomicBool, AtomicU64, AtomicUsize, Ordering},
+};
+
+use bootloader::{bootinfo::MemoryRegionType, BootInfo};
+
+use spin::{Mutex, Once};
+
+use x86_64::{
+    registers::model_specific::Msr,
+    structures::{
+        idt::{InterruptDescriptorTable, InterruptStackFrame},
+        paging::PageTableFlags,
+    },
+};
+
+use crate::{interrupts::IRQ_IST_FRAME_INDEX, sched};
+
+mod lapic;
+mod trampoline;
+
+/// The maximum number of cores we support. The bitmaps of cores (e.g. `IDLE`) are `u64`s, so this
+/// can be at most 64.
+pub const MAX_CPUS: usize = 16;
+
+/// The size of the stack each AP uses until it switches to its scheduler stacks.
+const AP_BOOT_STACK_SIZE: usize = 8 << 10;
+
+/// How long to wait for the APs to check in after starting them (ms).
+const AP_STARTUP_TIMEOUT: usize = 100;
+
+/// The IPI vector used to wake up idle cores.
+const WAKEUP_VECTOR: u8 = 0x40;
+
+/// The IPI vector used to preempt user tasks at the end of a time slice.
+const PREEMPT_VECTOR: u8 = 0x41;
+
+/// The IPI vector used to make other cores flush their TLBs.
+const TLB_SHOOTDOWN_VECTOR: u8 = 0x42;
+
+/// The local APIC's spurious interrupt vector.
+const SPURIOUS_VECTOR: u8 = 0xFF;
+
+/// Returned by `rdtscp` along with the time stamp. Each core keeps its index here.
+const TSC_AUX: Msr = Msr::new(0xC000_0103);
+
+/// Set once the BSP knows that the processor has `rdtscp`, so `cpu` can read `TSC_AUX`.
+static HAS_RDTSCP: AtomicBool = AtomicBool::new(false);
+
+/// The number of cores that are up and running.
+static ONLINE: AtomicUsize = AtomicUsize::new(1);
+
+/// Bitmap of cores that are idle, by core index.
+static IDLE: AtomicU64 = AtomicU64::new(0);
+
+/// Bitmap of cores that are running user tasks, by core index.
+static USER: AtomicU64 = AtomicU64::new(0);
+
+/// Bitmap of cores that flush their TLBs when asked to by `tlb_shootdown`, by core index.
+static SHOOTDOWN: AtomicU64 = AtomicU64::new(0);
+
+/// The number of TLB shootdowns each core has handled.
+static TLB_FLUSHES: PerCpu<u64> = PerCpu::new();
+
+/// A boot stack for an AP.
+#[repr(C, align(16))]
+struct BootStack([u8; AP_BOOT_STACK_SIZE]);
+
+/// A TLB shootdown that the other cores may not have handled yet, returned by `tlb_shootdown`.
+pub struct TlbShootdown {
+    /// The cores that were asked to flush, along with how many shootdowns each had handled.
+    flushes: Vec<(usize, u64)>,
+}
+
+/// A value with a separate copy for each core.
+///
+/// Each copy has its own lock, so cores don't contend with each other for their own copies.
+pub struct PerCpu<T> {
+    vals: Once<Vec<Mutex<T>>>,
+}
+
+impl<T> PerCpu<T> {
+    pub const fn new() -> Self {
+        PerCpu { vals: Once::new() }
+    }
+}
+
+impl<T: Default> PerCpu<T> {
+    /// The calling core's copy.
+    pub fn get(&self) -> &Mutex<T> {
+        &self.all()[cpu()]
+    }
+
+    /// All copies, indexed by core.
+    pub fn all(&self) -> &[Mutex<T>] {
+        self.vals
+            .call_once(|| (0..MAX_CPUS).map(|_| Mutex::new(T::default())).collect())
+    }
+}
+
+/// The index of the calling core.
+pub fn cpu() -> usize {
+    if HAS_RDTSCP.load(Ordering::Relaxed) {
+        let mut aux = 0;
+        unsafe {
+            __rdtscp(&mut aux);
+        }
+        aux as usize
+    } else {
+        apic_id()
+    }
+}
+
+/// The initial local APIC id of the calling core, which is its index.
+fn apic_id() -> usize {
+    // The initial local APIC id is in bits 31:24 of %ebx.
+    (unsafe { __cpuid(1) }.ebx >> 24) as usize
+}
+
+/// Store the calling core's index in `TSC_AUX`, if the processor has `rdtscp`. The BSP calls this
+/// first thing during boot, and each AP calls it before doing anything else, since `cpu` reads
+/// `TSC_AUX` as soon as the BSP has found `rdtscp`.
+pub fn init_cpu_id() {
+    // APs with bigger ids are parked by the trampoline, but the BSP's id could be anything.
+    let id = apic_id();
+    assert!(id < MAX_CPUS, "local APIC id {} is too big", id);
+
+    // RDTSCP is bit 27 of %edx.
+    if unsafe { __cpuid(0x8000_0001) }.edx & (1 << 27) == 0 {
+        return;
+    }
+
+    unsafe {
+        TSC_AUX.write(id as u64);
+    }
+    HAS_RDTSCP.store(true, Ordering::Release);
+}
+
+/// The number of cores that are up and running.
+pub fn count() -> usize {
+    ONLINE.load(Ordering::Acquire)
+}
+
+/// Start the other cores. This is called by the BSP after interrupts and the scheduler's syscall
+/// handling have been initialized, but before the scheduler is created.
+///
+/// If anything goes wrong, we just keep running on the BSP alone.
+pub fn init(boot_info: &'static BootInfo) {
+    if let Err(err) = lapic::init() {
+        printk!("\tunable to map local APIC: {:?}\n", err);
+        return;
+    }
+    lapic::enable(SPURIOUS_VECTOR);
+    SHOOTDOWN.fetch_or(1 << cpu(), Ordering::AcqRel);
+
+    // Scheduling decisions can only be recorded and replayed on one core (see `sched::replay`).
+    if cfg!(any(feature = "sched-record", feature = "sched-replay")) {
+        printk!("\tnot starting APs while recording or replaying\n");
+        return;
+    }
+
+    // The trampoline needs a free page below 1MiB.
+    let page = match trampoline_page(boot_info) {
+        Some(page) => page,
+        None => {
+            printk!("\tno free memory for the AP trampoline\n");
+            return;
+        }
+    };
+    if let Err(err) =
+        crate::memory::identity_map(page, PageTableFlags::PRESENT | PageTableFlags::WRITABLE)
+    {
+        printk!("\tunable to map the AP trampoline: {:?}\n", err);
+        return;
+    }
+
+    // The boot stacks are only used briefly, but we never free them.
+    let stacks: Vec<_> = (0..MAX_CPUS)
+        .map(|_| BootStack([0; AP_BOOT_STACK_SIZE]))
+        .collect();
+    let stacks = Box::leak(stacks.into_boxed_slice()).as_ptr() as u64;
+
+    unsafe {
+        trampoline::install(page, stacks, AP_BOOT_STACK_SIZE, MAX_CPUS, ap_main);
+    }
+
+    printk!("\tstarting APs with trampoline @ {:#x}\n", page);
+
+    // INIT-SIPI-SIPI
+    lapic::send_init_all();
+    crate::time::spin_ms(10);
+    for _ in 0..2 {
+        lapic::send_startup_all((page >> 12) as u8);
+        crate::time::spin_ms(1);
+    }
+
+    crate::time::spin_ms(AP_STARTUP_TIMEOUT);
+
+    printk!("\t{} cores online\n", count());
+}
+
+/// Returns the address of a free physical page below 1MiB (but not page 0), if there is one.
+fn trampoline_page(boot_info: &'static BootInfo) -> Option<u64> {
+    boot_info
+        .memory_map
+        .iter()
+        .filter(|region| match region.region_type {
+            MemoryRegionType::Usable => true,
+            _ => false,
+        })
+        .map(|region| {
+            (
+                region.range.start_frame_number.max(1),
+                region.range.end_frame_number,
+            )
+        })
+        .find(|&(start, end)| start < end && start < 0x100)
+        .map(|(start, _)| start << 12)
+}
+
+/// The entry point of the APs, called by the trampoline on the AP's boot stack.
+extern "C" fn ap_main() -> ! {
+    init_cpu_id();
+    crate::interrupts::init_cpu();
+    lapic::enable(SPURIOUS_VECTOR);
+    sched::user::init();
+
+    ONLINE.fetch_add(1, Ordering::AcqRel);
+    printk!("\tcore {} online\n", cpu());
+
+    // Wait for the BSP to create the scheduler.
+    while !sched::is_ready() {
+        spin_loop_hint();
+    }
+
+    sched::init_cpu();
+
+    // Mappings may have been removed since we started, and nobody told us.
+    SHOOTDOWN.fetch_or(1 << cpu(), Ordering::AcqRel);
+    x86_64::instructions::tlb::flush_all();
+
+    // We only get IPIs here, so it's safe to enable interrupts right away.
+    x86_64::instructions::interrupts::enable();
+
+    sched::start()
+}
+
+/// Mark the calling core as idle or not. Idle cores are woken up by `wake_idle`.
+pub fn set_idle(idle: bool) {
+    let bit = 1 << cpu();
+    if idle {
+        IDLE.fetch_or(bit, Ordering::AcqRel);
+    } else {
+        IDLE.fetch_and(!bit, Ordering::AcqRel);
+    }
+}
+
+/// Wake up all idle cores other than the calling one, e.g. because there is work for them.
+pub fn wake_idle() {
+    if !lapic::is_mapped() {
+        return;
+    }
+
+    let idle = IDLE.load(Ordering::Acquire) & !(1 << cpu());
+    for cpu in (0..MAX_CPUS).filter(|cpu| idle & (1 << cpu) != 0) {
+        lapic::send_ipi(cpu as u8, WAKEUP_VECTOR);
+    }
+}
+
+/// Mark the calling core as running (or no longer running) a user task.
+pub fn set_user(user: bool) {
+    let bit = 1 << cpu();
+    if user {
+        USER.fetch_or(bit, Ordering::AcqRel);
+    } else {
+        USER.fetch_and(!bit, Ordering::AcqRel);
+    }
+}
+
+/// Preempt the user tasks running on the cores other than the calling one whose time slice is
+/// over, according to `over`.
+pub fn preempt_user(over: impl Fn(usize) -> bool) {
+    if !lapic::is_mapped() {
+        return;
+    }
+
+    let user = USER.load(Ordering::Acquire) & !(1 << cpu());
+    for cpu in (0..MAX_CPUS).filter(|&cpu| user & (1 << cpu) != 0 && over(cpu)) {
+        lapic::send_ipi(cpu as u8, PREEMPT_VECTOR);
+    }
+}
+
+/// Tell all cores other than the calling one to flush their TLBs, e.g. because mappings have been
+/// removed. This doesn't wait for them to do so; use `TlbShootdown::done` to find out when they
+/// have.
+pub fn tlb_shootdown() -> TlbShootdown {
+    let cores = SHOOTDOWN.load(Ordering::Acquire) & !(1 << cpu());
+    let flushes: Vec<_> = (0..MAX_CPUS)
+        .filter(|cpu| cores & (1 << cpu) != 0)
+        .map(|cpu| (cpu, *TLB_FLUSHES.all()[cpu].lock()))
+        .collect();
+
+    for &(cpu, _) in flushes.iter() {
+        lapic::send_ipi(cpu as u8, TLB_SHOOTDOWN_VECTOR);
+    }
+
+    TlbShootdown { flushes }
+}
+
+impl TlbShootdown {
+    /// Has every core flushed its TLB since the shootdown?
+    pub fn done(&self) -> bool {
+        // The calling core may be one of the ones that were asked to flush, and its handler takes
+        // the same lock.
+        x86_64::instructions::interrupts::without_interrupts(|| {
+            self.flushes
+                .iter()
+                .all(|&(cpu, n)| *TLB_FLUSHES.all()[cpu].lock() > n)
+        })
+    }
+}
+
+/// Acknowledge an IPI that is handled outside of this module.
+pub fn ack_ipi() {
+    lapic::eoi();
+}
+
+/// Enable interrupts and halt until the next one. Enabling interrupts only takes effect after the
+/// next instruction, so an interrupt that is already pending still wakes us up.
+pub fn halt() {
+    unsafe {
+        asm!("sti; hlt" :::: "volatile");
+    }
+}
+
+/// Initialize the IDT entries for IPIs.
+pub unsafe fn init_ipis(idt: &mut InterruptDescriptorTable) {
+    idt[WAKEUP_VECTOR as usize]
+        .set_handler_fn(handle_wakeup)
+        .set_stack_index(IRQ_IST_FRAME_INDEX);
+    idt[PREEMPT_VECTOR as usize]
+        .set_handler_fn(crate::sched::user::preempt::ipi_handler())
+        .set_stack_index(IRQ_IST_FRAME_INDEX);
+    idt[TLB_SHOOTDOWN_VECTOR as usize]
+        .set_handler_fn(handle_tlb_shootdown)
+        .set_stack_index(IRQ_IST_FRAME_INDEX);
+    idt[SPURIOUS_VECTOR as usize]
+        .set_handler_fn(handle_spurious)
+        .set_stack_index(IRQ_IST_FRAME_INDEX);
+}
+
+/// Handle a wakeup IPI. Waking up is all we needed to do, so just acknowledge it.
+extern "x86-interrupt" fn handle_wakeup(_: &mut InterruptStackFrame) {
+    lapic::eoi();
+}
+
+/// Handle a TLB shootdown IPI. The count only goes up once the TLB has been flushed, and its lock
+/// is held throughout, so anyone who saw the old count (see `tlb_shootdown`) knows that a flush
+/// that started after they asked for it has finished once the count goes up.
+extern "x86-interrupt" fn handle_tlb_shootdown(_: &mut InterruptStackFrame) {
+    let mut flushes = TLB_FLUSHES.get().lock();
+    x86_64::instructions::tlb::flush_all();
+    *flushes += 1;
+    drop(flushes); // unlock
+
+    lapic::eoi();
+}
+
+/// Handle a spurious interrupt from the local APIC. These must not be acknowledged.
+extern "x86-interrupt" fn handle_spurious(_: &mut InterruptStackFrame) {}
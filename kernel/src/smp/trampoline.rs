@@ -0,0 +1,176 @@
+//! The code that application processors (APs) run when they first start.
+//!
+//! An AP starts in 16-bit real mode at the beginning of the physical page named in the Startup IPI,
+//! so the trampoline has to be copied to a page below 1MiB first. From there, it jumps straight to
+//! long mode using the kernel's page tables, picks a boot stack by its local APIC id, and calls
+//! the entry point. The trampoline page must be identity mapped so that it can keep running after
+//! paging is turned on.
+//!
+//! The fields at the end of the trampoline are filled in by `install` before any AP is started.
+
+use x86_64::registers::control::Cr3;
+
+global_asm!(
+    "
+    .pushsection .text.ap_trampoline, \"ax\"
+    .global ap_trampoline_start
+    .global ap_trampoline_end
+    .global ap_gdt
+    .global ap_gdt_ptr
+    .global ap_long_mode
+    .global ap_long_ptr
+    .global ap_cr3
+    .global ap_stacks
+    .global ap_stack_size
+    .global ap_max_cpus
+    .global ap_entry
+
+    .balign 16
+    .code16
+ap_trampoline_start:
+    cli
+    cld
+
+    # The segments point at the trampoline page.
+    mov %cs, %ax
+    mov %ax, %ds
+    mov %ax, %es
+    mov %ax, %ss
+
+    # Load the temporary GDT.
+    lgdtl ap_gdt_ptr - ap_trampoline_start
+
+    # Enable PAE.
+    mov %cr4, %eax
+    or $0x20, %eax
+    mov %eax, %cr4
+
+    # Use the kernel's page tables.
+    mov ap_cr3 - ap_trampoline_start, %eax
+    mov %eax, %cr3
+
+    # Enable long mode and no-execute in IA32_EFER.
+    mov $0xC0000080, %ecx
+    rdmsr
+    or $0x900, %eax
+    wrmsr
+
+    # Enable protected mode, paging, and write protection at the same time, which activates long
+    # mode.
+    mov %cr0, %eax
+    or $0x80010001, %eax
+    mov %eax, %cr0
+
+    # Jump to the 64-bit code segment.
+    ljmpl *ap_long_ptr - ap_trampoline_start
+
+    .code64
+ap_long_mode:
+    mov $0x10, %ax
+    mov %ax, %ds
+    mov %ax, %es
+    mov %ax, %ss
+    mov %ax, %fs
+    mov %ax, %gs
+
+    # Pick a boot stack by local APIC id.
+    mov $1, %eax
+    cpuid
+    shr $24, %ebx
+    cmp ap_max_cpus(%rip), %rbx
+    jae 2f
+    inc %rbx
+    imul ap_stack_size(%rip), %rbx
+    mov ap_stacks(%rip), %rsp
+    add %rbx, %rsp
+
+    xor %rbp, %rbp
+    mov ap_entry(%rip), %rax
+    call *%rax
+
+    # Too many cores (or the entry point returned), so just park this one.
+2:
+    cli
+    hlt
+    jmp 2b
+
+    .balign 8
+ap_gdt:
+    .quad 0
+    .quad 0x00209A0000000000 # 64-bit code
+    .quad 0x0000920000000000 # data
+ap_gdt_ptr:
+    .word ap_gdt_ptr - ap_gdt - 1
+    .long 0 # linear address of ap_gdt
+ap_long_ptr:
+    .long 0 # linear address of ap_long_mode
+    .word 0x08
+
+    .balign 8
+ap_cr3:
+    .quad 0
+ap_stacks:
+    .quad 0
+ap_stack_size:
+    .quad 0
+ap_max_cpus:
+    .quad 0
+ap_entry:
+    .quad 0
+ap_trampoline_end:
+    .popsection
+    "
+);
+
+extern "C" {
+    static ap_trampoline_start: u8;
+    static ap_trampoline_end: u8;
+    static ap_gdt: u8;
+    static ap_gdt_ptr: u8;
+    static ap_long_mode: u8;
+    static ap_long_ptr: u8;
+    static ap_cr3: u8;
+    static ap_stacks: u8;
+    static ap_stack_size: u8;
+    static ap_max_cpus: u8;
+    static ap_entry: u8;
+}
+
+/// Copy the trampoline to the identity mapped physical page at `page_addr` and fill in its fields.
+/// Each AP gets a boot stack of `stack_size` bytes from the array at `stacks`, indexed by local APIC
+/// id, and then calls `entry`.
+pub unsafe fn install(
+    page_addr: u64,
+    stacks: u64,
+    stack_size: usize,
+    max_cpus: usize,
+    entry: extern "C" fn() -> !,
+) {
+    let start = &ap_trampoline_start as *const u8;
+    let len = &ap_trampoline_end as *const u8 as usize - start as usize;
+    assert!(len <= 4096, "AP trampoline doesn't fit in a page");
+
+    let page = page_addr as *mut u8;
+    core::ptr::copy_nonoverlapping(start, page, len);
+
+    // The address of `sym` in the copy.
+    let relocated = |sym: &u8| page_addr + (sym as *const u8 as u64 - start as u64);
+
+    // 32-bit fields: the trampoline is below 1MiB.
+    (relocated(&ap_gdt_ptr) as *mut u8)
+        .add(2)
+        .cast::<u32>()
+        .write_unaligned(relocated(&ap_gdt) as u32);
+    (relocated(&ap_long_ptr) as *mut u32).write_unaligned(relocated(&ap_long_mode) as u32);
+
+    // 64-bit fields. The page tables need to be in the first 4GiB because we load CR3 from 32-bit
+    // code.
+    let cr3 = Cr3::read().0.start_address().as_u64();
+    assert!(cr3 < 1 << 32, "page tables are above 4GiB");
+
+    (relocated(&ap_cr3) as *mut u64).write(cr3);
+    (relocated(&ap_stacks) as *mut u64).write(stacks);
+    (relocated(&ap_stack_size) as *mut u64).write(stack_size as u64);
+    (relocated(&ap_max_cpus) as *mut u64).write(max_cpus as u64);
+    (relocated(&ap_entry) as *mut u64).write(entry as u64);
+}
@@ -1,49 +1,145 @@
 //! A module for dealing with system time and the passage of time.
 
-use core::sync::atomic::{AtomicUsize, Ordering};
+use core::{
+    arch::x86_64::_rdtsc,
+    sync::atomic::{AtomicUsize, Ordering},
+};
 
-use crate::interrupts::PIT_HZ;
+use spin::Mutex;
 
-/// Counts interrupts. This can be used as a source of time.
-static TICKS: AtomicUsize = AtomicUsize::new(0);
+use x86_64::instructions::interrupts;
+
+use crate::interrupts::{arm_timer, calibrate_tsc, MAX_ONE_SHOT_US};
+
+/// The frequency of the TSC, which is our source of time. Set once by `init`.
+static TSC_HZ: AtomicUsize = AtomicUsize::new(0);
+
+/// When the timer interrupt is going to happen, if it is armed. Only locked with interrupts
+/// disabled, since the timer interrupt handler locks it too.
+static ARMED: Mutex<Option<SysTime>> = Mutex::new(None);
+
+/// How early the timer interrupt may happen compared to `ARMED`, in microseconds. The PIT never
+/// fires early, but the TSC is only calibrated against it to within a few microseconds.
+const EARLY_US: usize = 20;
 
 /// Opaquely represents a system time
-#[derive(Copy, Clone, Eq, Ord, PartialEq, PartialOrd)]
+///
+/// This is a TSC value. We assume that the TSC runs at a constant rate and is synchronized across
+/// cores, as it is on modern processors (and in QEMU).
+#[derive(Copy, Clone, Debug, Eq, Ord, PartialEq, PartialOrd)]
 pub struct SysTime(usize);
 
 impl SysTime {
-    /// Get the system time without synchronizing. This has better performance but potentially misses a
-    /// tick every once in a while.
+    /// Get the system time.
     pub fn now() -> Self {
-        // safe because we are only reading and we don't mind missing some synchronous op
-        let time = unsafe {
-            // we are guaranteed by the standard library that `AtomicUsize` has the same memory layout
-            // as `usize`.
-            *(&TICKS as *const AtomicUsize as *const usize)
-        };
+        SysTime(unsafe { _rdtsc() } as usize)
+    }
 
-        SysTime(time)
+    /// Get the time `secs` seconds after `self`.
+    pub fn after(self, secs: usize) -> Self {
+        SysTime(self.0 + secs * TSC_HZ.load(Ordering::Relaxed))
     }
 
-    /*
-    /// Get the system time atomically. This has worse performance but will get the most recent
-    /// timestamp.
-    pub fn now_atomic() -> Self {
-        SysTime(TICKS.load(Ordering::Relaxed))
+    /// Get the time `ms` milliseconds after `self`.
+    pub fn after_ms(self, ms: usize) -> Self {
+        SysTime(self.0 + ms * TSC_HZ.load(Ordering::Relaxed) / 1000)
     }
-    */
 
-    /// Get the time `secs` seconds after `self`.
-    pub fn after(self, secs: usize) -> Self {
-        SysTime(self.0 + secs * PIT_HZ)
+    /// Get the time `us` microseconds after `self`.
+    fn after_us(self, us: usize) -> Self {
+        SysTime(self.0 + us * TSC_HZ.load(Ordering::Relaxed) / 1_000_000)
+    }
+
+    /// The number of TSC cycles from `earlier` until `self`, or 0 if `earlier` is later.
+    pub fn cycles_since(self, earlier: SysTime) -> u64 {
+        self.0.saturating_sub(earlier.0) as u64
+    }
+
+    /// The number of microseconds from now until `self`, rounded up, or 0 if `self` has passed.
+    fn us_from_now(self) -> usize {
+        let per_us = (TSC_HZ.load(Ordering::Relaxed) as u64 / 1_000_000).max(1);
+        ((self.cycles_since(Self::now()) + per_us - 1) / per_us) as usize
+    }
+}
+
+/// Convert a number of TSC cycles to microseconds.
+pub fn cycles_to_us(cycles: u64) -> u64 {
+    cycles / (TSC_HZ.load(Ordering::Relaxed) as u64 / 1_000_000).max(1)
+}
+
+/// An atomic `Option<SysTime>`. This lets interrupt handlers read a time without taking a lock.
+pub struct AtomicSysTime(AtomicUsize);
+
+impl AtomicSysTime {
+    /// Used to represent `None`.
+    const NONE: usize = core::usize::MAX;
+
+    pub const fn new() -> Self {
+        AtomicSysTime(AtomicUsize::new(Self::NONE))
+    }
+
+    pub fn load(&self) -> Option<SysTime> {
+        match self.0.load(Ordering::Acquire) {
+            Self::NONE => None,
+            time => Some(SysTime(time)),
+        }
     }
+
+    pub fn store(&self, time: Option<SysTime>) {
+        self.0
+            .store(time.map_or(Self::NONE, |time| time.0), Ordering::Release);
+    }
+}
+
+/// Calibrate the TSC and stop the periodic timer interrupt. From now on, the timer only fires when
+/// someone arms it.
+pub fn init() {
+    let hz = calibrate_tsc();
+    TSC_HZ.store(hz, Ordering::Relaxed);
+
+    printk!("tsc calibrated - {} MHz\n", hz / 1_000_000);
+
+    // Switch the PIT to one-shot mode.
+    interrupts::without_interrupts(|| {
+        let mut armed = ARMED.lock();
+        arm_timer(MAX_ONE_SHOT_US);
+        *armed = Some(SysTime::now().after_us(MAX_ONE_SHOT_US));
+    });
 }
 
-/// Tick the clock atomically.
+/// Busy-wait for about `ms` milliseconds. This works even with interrupts disabled.
+pub fn spin_ms(ms: usize) {
+    let end = SysTime::now().after_ms(ms);
+    while SysTime::now() < end {}
+}
+
+/// Make sure that the timer interrupt happens no later than `at` (or as soon as possible, if `at`
+/// has passed). The timer can only be armed so far in advance, so it may happen earlier.
+pub fn arm(at: SysTime) {
+    interrupts::without_interrupts(|| {
+        let mut armed = ARMED.lock();
+
+        if armed.map_or(false, |armed| armed <= at) {
+            return;
+        }
+
+        // Both `us` and the PIT count are rounded up, so the interrupt doesn't happen before `at`
+        // (or before the recorded time, if `at` is too far away).
+        let us = at.us_from_now().min(MAX_ONE_SHOT_US);
+        arm_timer(us);
+        *armed = Some(SysTime::now().after_us(us).min(at));
+    });
+}
+
+/// The timer interrupt happened, so the timer is no longer armed (unless another core armed it
+/// again for later in the meantime).
 ///
 /// # NOTE
 ///
 /// This should only be called from the timer interrupt handler.
-pub fn tick() {
-    TICKS.fetch_add(1, Ordering::Relaxed);
+pub fn fired() {
+    let mut armed = ARMED.lock();
+    if armed.map_or(false, |armed| armed <= SysTime::now().after_us(EARLY_US)) {
+        *armed = None;
+    }
 }
@@ -0,0 +1,163 @@
+//! Capabilities: finding out which handles the task holds and what they are, handing out handles
+//! with fewer rights, and giving handles up.
+
+use core::ops::{BitAnd, BitOr};
+
+use super::{nr, syscall, syscall4, Error, ResourceHandle};
+
+/// The operations a handle allows on its resource. These must match the kernel's.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct Rights(u8);
+
+impl Rights {
+    /// Read from the resource (e.g. map a memory region, or receive on a channel).
+    pub const READ: Rights = Rights(1 << 0);
+
+    /// Write to the resource (e.g. map a memory region writable, or send on a channel).
+    pub const WRITE: Rights = Rights(1 << 1);
+
+    /// Execute the resource (e.g. map a memory region executable).
+    pub const EXECUTE: Rights = Rights(1 << 2);
+
+    /// Pass the handle on to someone else (e.g. in a message).
+    pub const GRANT: Rights = Rights(1 << 3);
+
+    /// Map the resource into the address space.
+    pub const MAP: Rights = Rights(1 << 4);
+
+    /// All of the above.
+    pub const ALL: Rights = Rights(0b1_1111);
+
+    /// Does `self` include all of the rights in `other`?
+    pub fn contains(self, other: Rights) -> bool {
+        self.0 & other.0 == other.0
+    }
+}
+
+impl BitOr for Rights {
+    type Output = Rights;
+
+    fn bitor(self, other: Rights) -> Rights {
+        Rights(self.0 | other.0)
+    }
+}
+
+impl BitAnd for Rights {
+    type Output = Rights;
+
+    fn bitand(self, other: Rights) -> Rights {
+        Rights(self.0 & other.0)
+    }
+}
+
+/// What a capability is on, with metadata that depends on its type. The metadata is a snapshot,
+/// so it may be out of date by the time you look at it (e.g. the number of queued messages).
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Info {
+    /// A group of capabilities with `members` members.
+    Group { members: u64 },
+
+    /// A region of memory of `len` bytes, starting at `start`.
+    Region { start: u64, len: u64 },
+
+    /// A channel that has `queued` messages in it and can buffer up to `capacity`.
+    Channel { queued: u64, capacity: u64 },
+
+    /// The `len` I/O ports starting at `first`. See `io`.
+    IoPorts { first: u16, len: u32 },
+
+    /// The IRQ line `line`. See `io`.
+    Irq { line: u8 },
+
+    /// `len` bytes of physical memory, the first frame of which is at physical address `paddr`.
+    /// See `io::frame_address`.
+    Frames { paddr: u64, len: u64 },
+
+    /// A type of capability we don't know about.
+    Unknown(u8),
+}
+
+/// The metadata of a capability, as seen through a handle.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct Metadata {
+    /// What the handle allows.
+    pub rights: Rights,
+
+    /// What the capability is on.
+    pub info: Info,
+}
+
+/// An iterator over the handles held by the calling task. See `handles`.
+pub struct Handles {
+    next: u64,
+}
+
+impl Iterator for Handles {
+    type Item = ResourceHandle;
+
+    fn next(&mut self) -> Option<ResourceHandle> {
+        let (status, handle) = unsafe { syscall(nr::CAP_LIST, self.next, 0, 0) };
+        Error::check(status).ok()?;
+
+        self.next += 1;
+        Some(ResourceHandle(handle))
+    }
+}
+
+/// Iterate over the handles held by the calling task, in order, leaving out any that have been
+/// revoked. Members of groups the task holds are not included. Gaining or giving up handles while
+/// iterating may cause handles to be skipped or repeated.
+pub fn handles() -> Handles {
+    Handles { next: 0 }
+}
+
+/// Get the metadata of the capability `handle` is on.
+pub fn inspect(handle: ResourceHandle) -> Result<Metadata, Error> {
+    let (status, ty_rights, a, b) = unsafe { syscall4(nr::CAP_INSPECT, handle.0, 0, 0) };
+    Error::check(status)?;
+
+    let info = match ty_rights as u8 {
+        0 => Info::Group { members: a },
+        1 => Info::Region { start: a, len: b },
+        2 => Info::Channel {
+            queued: a,
+            capacity: b,
+        },
+        3 => Info::IoPorts {
+            first: a as u16,
+            len: b as u32,
+        },
+        4 => Info::Irq { line: a as u8 },
+        5 => Info::Frames { paddr: a, len: b },
+        ty => Info::Unknown(ty),
+    };
+
+    Ok(Metadata {
+        rights: Rights((ty_rights >> 8) as u8),
+        info,
+    })
+}
+
+/// Get a new handle on the same resource as `handle` that allows only those of its rights that are
+/// in `rights`, e.g. to pass on to another task. Releasing `handle` does not affect the new handle.
+pub fn duplicate(handle: ResourceHandle, rights: Rights) -> Result<ResourceHandle, Error> {
+    let (status, handle) = unsafe { syscall(nr::CAP_DUPLICATE, handle.0, rights.0 as u64, 0) };
+
+    Error::check(status).map(|()| ResourceHandle(handle))
+}
+
+/// Give up `handle`. It can't be used after this. Once no handles on a resource are left, the
+/// kernel frees it.
+pub fn release(handle: ResourceHandle) -> Result<(), Error> {
+    let (status, _) = unsafe { syscall(nr::CAP_RELEASE, handle.0, 0, 0) };
+
+    Error::check(status)
+}
+
+/// Revoke `handle` and every handle derived from it, e.g. with `duplicate`. None of them can be
+/// used after this, but `handle` still has to be released.
+pub fn revoke(handle: ResourceHandle) -> Result<(), Error> {
+    let (status, _) = unsafe { syscall(nr::CAP_REVOKE, handle.0, 0, 0) };
+
+    Error::check(status)
+}
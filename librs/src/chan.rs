@@ -0,0 +1,21 @@
+//! Message channels. Channels are kernel resources, so you need a `ResourceHandle` to use one. The
+//! handle of a group with a channel among its members works too, in which case its first channel is
+//! used.
+
+use super::{nr, syscall, Error, ResourceHandle};
+
+/// Send `word` on the channel `chan`. Does not block; if the channel is full, `Error::Full` is
+/// returned.
+pub fn send(chan: ResourceHandle, word: u64) -> Result<(), Error> {
+    let (status, _) = unsafe { syscall(nr::CHAN_SEND, chan.0, word, 0) };
+
+    Error::check(status)
+}
+
+/// Receive a word from the channel `chan`. Does not block; if the channel is empty, `Error::Empty`
+/// is returned.
+pub fn try_recv(chan: ResourceHandle) -> Result<u64, Error> {
+    let (status, word) = unsafe { syscall(nr::CHAN_RECV, chan.0, 0, 0) };
+
+    Error::check(status).map(|()| word)
+}
@@ -0,0 +1,122 @@
+//! Device I/O, for drivers in user space: I/O ports and interrupts.
+//!
+//! A task may use the I/O ports it holds an I/O port capability on (with both read and write
+//! rights). Using any other port is a general protection fault. To handle interrupts, a task needs
+//! a capability on the IRQ line: `wait_irq` blocks until there is an interrupt, and `ack_irq`
+//! tells the kernel that the task is ready for the next one. Devices that do DMA need buffers in
+//! physical memory, which `alloc_frames` allocates and `map_frames` maps, and they need to be told
+//! where the buffers are, which `frame_address` finds out. `map_frames` also maps device memory
+//! (e.g. a PCI BAR) that the task was given, uncached.
+//!
+//! A driver is often given one group with everything it needs. The group's handle can be passed to
+//! any of these functions instead of the handle of the member they need.
+
+use super::{nr, syscall, syscall4, Error, ResourceHandle};
+
+/// Where the frames allocated by `alloc_frames` may be.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Placement {
+    /// Anywhere: the frames need not be contiguous.
+    Any,
+
+    /// In one physically contiguous run, starting at a multiple of `align` bytes (a power of two).
+    Contiguous { align: u64 },
+}
+
+/// How memory mapped with `map_frames` is cached. The values are the kernel's caching flags.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Caching {
+    /// Cached as usual, e.g. for DMA buffers of cache-coherent devices.
+    WriteBack = 0,
+
+    /// Writes go straight to memory, but reads may be cached.
+    WriteThrough = 2,
+
+    /// Not cached at all, e.g. for device registers.
+    Uncached = 3,
+}
+
+/// Read a byte from `port`.
+///
+/// # Safety
+///
+/// This talks to a device directly, so the caller is responsible for what the device does.
+pub unsafe fn inb(port: u16) -> u8 {
+    let value: u8;
+
+    llvm_asm!(
+        "inb %dx, %al"
+        : "={al}"(value)
+        : "{dx}"(port)
+        : /* no clobbers */
+        : "volatile"
+    );
+
+    value
+}
+
+/// Write the byte `value` to `port`.
+///
+/// # Safety
+///
+/// This talks to a device directly, so the caller is responsible for what the device does.
+pub unsafe fn outb(port: u16, value: u8) {
+    llvm_asm!(
+        "outb %al, %dx"
+        : /* no outputs */
+        : "{dx}"(port), "{al}"(value)
+        : /* no clobbers */
+        : "volatile"
+    );
+}
+
+/// Block until there is an interrupt on the IRQ line `irq`. No more interrupts are delivered on the
+/// line until `ack_irq` is called.
+pub fn wait_irq(irq: ResourceHandle) -> Result<(), Error> {
+    let (status, _) = unsafe { syscall(nr::IRQ_WAIT, irq.0, 0, 0) };
+
+    Error::check(status)
+}
+
+/// The last interrupt on the IRQ line `irq` has been dealt with, so the next one can be delivered.
+pub fn ack_irq(irq: ResourceHandle) -> Result<(), Error> {
+    let (status, _) = unsafe { syscall(nr::IRQ_ACK, irq.0, 0, 0) };
+
+    Error::check(status)
+}
+
+/// The physical address of the `n`th frame of the physical memory `frames`, e.g. for a device
+/// doing DMA. Returns `Error::OutOfRange` if there are not that many frames.
+pub fn frame_address(frames: ResourceHandle, n: usize) -> Result<u64, Error> {
+    let (status, paddr) = unsafe { syscall(nr::FRAME_ADDRESS, frames.0, n as u64, 0) };
+
+    Error::check(status).map(|()| paddr)
+}
+
+/// Allocate `n` zeroed frames of physical memory, placed as `placement` says, e.g. for a DMA
+/// buffer. The frames count against the task's quota of 1024 frames until they are released.
+/// Returns `Error::NoMemory` if there isn't enough (contiguous) memory or quota left, and
+/// `Error::Invalid` if `n` is 0 or more than the whole quota, or the alignment is not a power of
+/// two.
+pub fn alloc_frames(n: usize, placement: Placement) -> Result<ResourceHandle, Error> {
+    let align = match placement {
+        Placement::Any => 0,
+        Placement::Contiguous { align } => align,
+    };
+    let (status, frames) = unsafe { syscall(nr::FRAME_ALLOC, n as u64, align, 0) };
+
+    Error::check(status).map(|()| ResourceHandle(frames))
+}
+
+/// Map all of the physical memory `frames` into a new memory region, cached as `caching` says. The
+/// memory is writable if `frames` grants writing. Returns the region's handle and a pointer to the
+/// beginning of the memory. Releasing the region unmaps the memory, but it doesn't free it.
+pub fn map_frames(
+    frames: ResourceHandle,
+    caching: Caching,
+) -> Result<(ResourceHandle, *mut u8), Error> {
+    let (status, region, start, _) =
+        unsafe { syscall4(nr::FRAME_MAP, frames.0, caching as u64, 0) };
+
+    Error::check(status).map(|()| (ResourceHandle(region), start as *mut u8))
+}
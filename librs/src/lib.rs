@@ -7,6 +7,257 @@
 #![feature(llvm_asm, start)]
 
 pub mod bare_bones;
+pub mod cap;
+pub mod chan;
+pub mod io;
+
+/// System call numbers. These must match the kernel's.
+mod nr {
+    /// Terminate the calling task.
+    pub const EXIT: u64 = 0xDEADBEEF;
+
+    /// Send a word on a channel.
+    pub const CHAN_SEND: u64 = 1;
+
+    /// Receive a word from a channel.
+    pub const CHAN_RECV: u64 = 2;
+
+    /// Dump the kernel's continuation graph.
+    pub const DEBUG_DOT: u64 = 3;
+
+    /// Get the calling task's scheduler statistics.
+    pub const SCHED_STATS: u64 = 4;
+
+    /// Dump the kernel's scheduler statistics.
+    pub const DEBUG_STATS: u64 = 5;
+
+    /// Get one of the handles held by the calling task.
+    pub const CAP_LIST: u64 = 6;
+
+    /// Get the metadata of a capability.
+    pub const CAP_INSPECT: u64 = 7;
+
+    /// Derive a handle with fewer rights.
+    pub const CAP_DUPLICATE: u64 = 8;
+
+    /// Give up a handle.
+    pub const CAP_RELEASE: u64 = 9;
+
+    /// Revoke a handle and everything derived from it.
+    pub const CAP_REVOKE: u64 = 10;
+
+    /// Wait for an interrupt.
+    pub const IRQ_WAIT: u64 = 11;
+
+    /// Acknowledge an interrupt.
+    pub const IRQ_ACK: u64 = 12;
+
+    /// Get the physical address of a frame of physical memory.
+    pub const FRAME_ADDRESS: u64 = 13;
+
+    /// Allocate physical memory.
+    pub const FRAME_ALLOC: u64 = 14;
+
+    /// Map physical memory into a new memory region.
+    pub const FRAME_MAP: u64 = 15;
+}
+
+/// Errors returned by the kernel. These must match the kernel's error codes.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Error {
+    /// The handle does not name a resource.
+    BadHandle,
+
+    /// The resource or message is of the wrong type.
+    WrongType,
+
+    /// The channel is full.
+    Full,
+
+    /// The channel is empty.
+    Empty,
+
+    /// The address is not in memory the task may access.
+    BadPointer,
+
+    /// The handle does not grant the rights needed.
+    NoRights,
+
+    /// The index is past the end.
+    OutOfRange,
+
+    /// There is not enough memory left.
+    NoMemory,
+
+    /// An argument is invalid.
+    Invalid,
+
+    /// The kernel returned an error code we don't know about.
+    Unknown(u64),
+}
+
+impl Error {
+    /// Turn a status code returned by the kernel into a `Result`.
+    fn check(status: u64) -> Result<(), Error> {
+        match status {
+            0 => Ok(()),
+            s if s == !0 => Err(Error::BadHandle),
+            s if s == !1 => Err(Error::WrongType),
+            s if s == !2 => Err(Error::Full),
+            s if s == !3 => Err(Error::Empty),
+            s if s == !4 => Err(Error::BadPointer),
+            s if s == !5 => Err(Error::NoRights),
+            s if s == !6 => Err(Error::OutOfRange),
+            s if s == !7 => Err(Error::NoMemory),
+            s if s == !8 => Err(Error::Invalid),
+            s => Err(Error::Unknown(s)),
+        }
+    }
+}
+
+/// A handle to a kernel resource. Having the handle gives access to the resource. A handle is an
+/// index into the calling task's own table of handles, like a file descriptor, so it means nothing
+/// to any other task.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct ResourceHandle(u64);
+
+impl ResourceHandle {
+    /// Create a handle from the raw value given by the kernel.
+    pub fn from_raw(value: u64) -> Self {
+        ResourceHandle(value)
+    }
+
+    /// The raw value passed to the kernel.
+    pub fn raw(self) -> u64 {
+        self.0
+    }
+}
+
+/// Ask the kernel to dump its continuation graph over the serial port (in Graphviz DOT format).
+pub fn debug_dump_continuations() {
+    unsafe {
+        syscall(nr::DEBUG_DOT, 0, 0, 0);
+    }
+}
+
+/// The longest label or event name in a `SchedStats`. Longer names are cut short.
+pub const STATS_NAME_LEN: usize = 24;
+
+/// The number of continuation labels that fit in a `SchedStats`.
+pub const STATS_LABELS: usize = 16;
+
+/// The number of kinds of events that fit in a `LabelStats`.
+pub const STATS_WAITS: usize = 16;
+
+/// Scheduler statistics, as filled in by `sched_stats`. Times are in microseconds. This must match
+/// the kernel's `UserStats`.
+#[repr(C)]
+pub struct SchedStats {
+    /// The number of times any core went idle.
+    pub idle_loops: u64,
+
+    /// The number of times the calling task was scheduled, i.e. started or resumed after
+    /// preemption.
+    pub task_runs: u64,
+
+    /// The total time the calling task has run, including system calls.
+    pub task_runtime_us: u64,
+
+    /// The number of entries of `labels` that are filled in. Labels that don't fit are left out.
+    pub nlabels: u64,
+
+    /// Statistics for the kernel's continuations, by label.
+    pub labels: [LabelStats; STATS_LABELS],
+}
+
+/// The statistics for the continuations with some label in a `SchedStats`.
+#[repr(C)]
+pub struct LabelStats {
+    /// The label, padded with NULs (see `name`).
+    pub label: [u8; STATS_NAME_LEN],
+
+    /// The number of times a continuation with this label ran.
+    pub runs: u64,
+
+    /// The total time continuations with this label ran.
+    pub runtime_us: u64,
+
+    /// The number of entries of `waits` that are filled in.
+    pub nwaits: u64,
+
+    /// How often and how long continuations with this label waited, by kind of event.
+    pub waits: [WaitStats; STATS_WAITS],
+}
+
+/// The waits on some kind of event in a `LabelStats`.
+#[repr(C)]
+pub struct WaitStats {
+    /// The name of the kind of event, padded with NULs (see `name`).
+    pub kind: [u8; STATS_NAME_LEN],
+
+    /// The number of waits.
+    pub count: u64,
+
+    /// The total time waited.
+    pub time_us: u64,
+}
+
+/// The name in `padded`, up to the first NUL.
+pub fn name(padded: &[u8]) -> &str {
+    let len = padded.iter().position(|&c| c == 0).unwrap_or(padded.len());
+    core::str::from_utf8(&padded[..len]).unwrap_or("?")
+}
+
+/// Get the scheduler statistics: those of the calling task and of the kernel's continuations. The
+/// kernel writes them to `stats`, which is too big to keep on a small stack (e.g. use a `static`).
+pub fn sched_stats(stats: &mut SchedStats) -> Result<(), Error> {
+    let (status, _) = unsafe { syscall(nr::SCHED_STATS, stats as *mut SchedStats as u64, 0, 0) };
+
+    Error::check(status)
+}
+
+/// Ask the kernel to dump its scheduler statistics for all continuations and tasks over the serial
+/// port.
+pub fn debug_dump_sched_stats() {
+    unsafe {
+        syscall(nr::DEBUG_STATS, 0, 0, 0);
+    }
+}
+
+/// Make a system call with the given syscall number and arguments. Returns the values returned by
+/// the kernel in %rax and %rdi.
+unsafe fn syscall(nr: u64, a0: u64, a1: u64, a2: u64) -> (u64, u64) {
+    let ret0: u64;
+    let ret1: u64;
+
+    llvm_asm!(
+        "syscall"
+        : "={rax}"(ret0), "={rdi}"(ret1)
+        : "{rax}"(nr), "{rdi}"(a0), "{rsi}"(a1), "{r10}"(a2)
+        : "rcx", "r11", "rdx", "memory"
+        : "volatile"
+    );
+
+    (ret0, ret1)
+}
+
+/// Like `syscall`, but also returns the values returned by the kernel in %rsi and %rdx.
+unsafe fn syscall4(nr: u64, a0: u64, a1: u64, a2: u64) -> (u64, u64, u64, u64) {
+    let ret0: u64;
+    let ret1: u64;
+    let ret2: u64;
+    let ret3: u64;
+
+    llvm_asm!(
+        "syscall"
+        : "={rax}"(ret0), "={rdi}"(ret1), "={rsi}"(ret2), "={rdx}"(ret3)
+        : "{rax}"(nr), "{rdi}"(a0), "{rsi}"(a1), "{r10}"(a2)
+        : "rcx", "r11", "memory"
+        : "volatile"
+    );
+
+    (ret0, ret1, ret2, ret3)
+}
 
 /// Instructs the kernel to terminate the current task and free its resources. The exit `code` is
 /// passed to the kernel.
@@ -19,7 +270,7 @@ pub fn exit(code: isize) -> ! {
             jmp __librs_exit
             "
             : /* no outputs */
-            : "{rax}"(code)
+            : "{rax}"(nr::EXIT), "{rdi}"(code)
             : "stack", "memory"
             : "volatile"
         );
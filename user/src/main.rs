@@ -1,9 +1,118 @@
+//! A PS/2 keyboard driver in user space. The kernel gives us a group of capabilities on the
+//! keyboard's I/O ports and IRQ line, and on a channel to send what is typed on. We exit when enter
+//! is pressed.
+
 #![no_std]
 #![no_main]
 
+use rs::{
+    cap::{self, Info},
+    chan,
+    io::{self, ack_irq, wait_irq},
+};
+
 rs::panic_handler!();
 
+/// Keyboard status port
+const KBD_STATUS: u16 = 0x64;
+
+/// Keyboard data port
+const KBD_DATA: u16 = 0x60;
+
+/// The difference between a capital and lowercase
+const CAP: u8 = b'a' - b'A';
+
 #[no_mangle]
 pub unsafe extern "C" fn main() -> isize {
-    0xDEADBEEF
+    // Find the group the kernel gave us. It can be passed wherever the IRQ line or the channel is
+    // needed.
+    let driver = cap::handles().find(|&handle| match cap::inspect(handle) {
+        Ok(metadata) => matches!(metadata.info, Info::Group { .. }),
+        Err(_) => false,
+    });
+
+    let driver = match driver {
+        Some(driver) => driver,
+        None => return -1,
+    };
+
+    let mut shift = false;
+    loop {
+        if wait_irq(driver).is_err() {
+            return -2;
+        }
+
+        let key = read(&mut shift);
+
+        if ack_irq(driver).is_err() {
+            return -3;
+        }
+
+        if let Some(key) = key {
+            // If the kernel isn't keeping up, drop the key.
+            let _ = chan::send(driver, key as u64);
+
+            if key == b'\n' {
+                return 0;
+            }
+        }
+    }
+}
+
+/// Get a character from the keyboard after an interrupt, keeping track of whether shift is held.
+unsafe fn read(shift: &mut bool) -> Option<u8> {
+    while io::inb(KBD_STATUS) & 1 == 0 {}
+    let b = io::inb(KBD_DATA);
+
+    let caps = *shift;
+    let ul = |c: u8| if caps { c - CAP } else { c };
+
+    match b {
+        0x02..=0x0a => Some(b'0' + b - 1),
+        0x0b => Some(b'0'),
+
+        0x10 => Some(ul(b'q')),
+        0x11 => Some(ul(b'w')),
+        0x12 => Some(ul(b'e')),
+        0x13 => Some(ul(b'r')),
+        0x14 => Some(ul(b't')),
+        0x15 => Some(ul(b'y')),
+        0x16 => Some(ul(b'u')),
+        0x17 => Some(ul(b'i')),
+        0x18 => Some(ul(b'o')),
+        0x19 => Some(ul(b'p')),
+        0x1e => Some(ul(b'a')),
+        0x1f => Some(ul(b's')),
+        0x20 => Some(ul(b'd')),
+        0x21 => Some(ul(b'f')),
+        0x22 => Some(ul(b'g')),
+        0x23 => Some(ul(b'h')),
+        0x24 => Some(ul(b'j')),
+        0x25 => Some(ul(b'k')),
+        0x26 => Some(ul(b'l')),
+        0x2c => Some(ul(b'z')),
+        0x2d => Some(ul(b'x')),
+        0x2e => Some(ul(b'c')),
+        0x2f => Some(ul(b'v')),
+        0x30 => Some(ul(b'b')),
+        0x31 => Some(ul(b'n')),
+        0x32 => Some(ul(b'm')),
+
+        0x1c => Some(b'\n'),
+        0x39 => Some(b' '),
+
+        0x0e => Some(8),
+
+        // Handle shift
+        0x2a | 0x36 => {
+            *shift = true;
+            None
+        }
+        0xaa | 0xb6 => {
+            *shift = false;
+            None
+        }
+
+        _ => None,
+    }
 }